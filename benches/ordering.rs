@@ -0,0 +1,119 @@
+//! Compares the allocation throughput of `Limit` with each `CounterOrdering`, with many threads
+//! allocating at the same time. Run with `cargo bench --bench ordering`.
+//!
+//! The `vec churn` workload grows buffers using `realloc` the same way a `Vec` does, with a limit
+//! low enough that some allocations fail. After each run it checks that the limit was never
+//! exceeded and that all the memory was given back, so this also works as a stress test of the
+//! counters with weaker orderings.
+use limit_alloc::{CounterOrdering, Limit};
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::hint::black_box;
+use std::thread;
+use std::time::{Duration, Instant};
+
+const ITERATIONS: usize = 200_000;
+const CHURN_ITERATIONS: usize = 20_000;
+/// Size of the biggest buffer in the `vec churn` workload.
+const CHURN_MAX_SIZE: usize = 4096;
+
+/// Allocates and deallocates 64 bytes in a loop.
+fn alloc_dealloc(limit: &Limit<System>) {
+    let layout = Layout::from_size_align(64, 8).unwrap();
+    for _ in 0..ITERATIONS {
+        unsafe {
+            let ptr = limit.alloc(layout);
+            assert!(!ptr.is_null());
+            limit.dealloc(black_box(ptr), layout);
+        }
+    }
+}
+
+/// Grows a buffer from 16 bytes up to `CHURN_MAX_SIZE` bytes doubling its size, then deallocates
+/// it. Growing stops early if the limit is exhausted.
+fn vec_churn(limit: &Limit<System>) {
+    for _ in 0..CHURN_ITERATIONS {
+        unsafe {
+            let mut layout = Layout::from_size_align(16, 8).unwrap();
+            let mut ptr = limit.alloc(layout);
+            if ptr.is_null() {
+                continue;
+            }
+            while layout.size() < CHURN_MAX_SIZE {
+                let new = limit.realloc(ptr, layout, layout.size() * 2);
+                if new.is_null() {
+                    break;
+                }
+                ptr = new;
+                layout = Layout::from_size_align(layout.size() * 2, 8).unwrap();
+            }
+            limit.dealloc(black_box(ptr), layout);
+        }
+    }
+}
+
+struct Workload {
+    name: &'static str,
+    iterations: usize,
+    /// Limit of the allocator, depending on the number of threads.
+    limit: fn(usize) -> usize,
+    run: fn(&Limit<System>),
+}
+
+const WORKLOADS: [Workload; 2] = [
+    Workload {
+        name: "alloc",
+        iterations: ITERATIONS,
+        limit: |_threads| usize::MAX,
+        run: alloc_dealloc,
+    },
+    Workload {
+        name: "vec churn",
+        iterations: CHURN_ITERATIONS,
+        // Enough memory for about half of the threads to have a buffer of the maximum size
+        limit: |threads| (threads * CHURN_MAX_SIZE / 2).max(CHURN_MAX_SIZE),
+        run: vec_churn,
+    },
+];
+
+fn bench(ordering: CounterOrdering, threads: usize, workload: &Workload) -> Duration {
+    let max = (workload.limit)(threads);
+    let limit = Limit::with_ordering(max, System, ordering);
+    let start = Instant::now();
+    thread::scope(|s| {
+        for _ in 0..threads {
+            s.spawn(|| (workload.run)(&limit));
+        }
+    });
+    let elapsed = start.elapsed();
+    assert!(limit.peak() <= max, "limit exceeded: {:?}", limit);
+    assert_eq!(limit.allocated(), 0, "memory not given back: {:?}", limit);
+    elapsed
+}
+
+fn main() {
+    let max_threads = thread::available_parallelism().map_or(4, |n| n.get());
+    for workload in &WORKLOADS {
+        println!("{}", workload.name);
+        let mut threads = 1;
+        loop {
+            for ordering in [
+                CounterOrdering::SeqCst,
+                CounterOrdering::AcqRel,
+                CounterOrdering::Relaxed,
+            ] {
+                let elapsed = bench(ordering, threads, workload);
+                let ops = (threads * workload.iterations) as f64 / elapsed.as_secs_f64();
+                println!(
+                    "{:>3} threads {:>8}: {:>12.0} iterations/s",
+                    threads,
+                    format!("{:?}", ordering),
+                    ops
+                );
+            }
+            if threads >= max_threads {
+                break;
+            }
+            threads = (threads * 2).min(max_threads);
+        }
+    }
+}
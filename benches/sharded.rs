@@ -0,0 +1,73 @@
+//! Compares the allocation throughput of `System`, `Limit` and `ShardedLimit` with many threads
+//! allocating at the same time. Run with `cargo bench --bench sharded`.
+//!
+//! The `tight` workload uses a limit low enough that the shards need to be rebalanced often, and
+//! checks that the memory allocated at the same time never exceeds the limit and that all the
+//! memory is given back, so this also works as a stress test of `ShardedLimit`.
+use limit_alloc::{Limit, ShardedLimit};
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::hint::black_box;
+use std::sync::atomic::AtomicUsize;
+use std::sync::atomic::Ordering::SeqCst;
+use std::thread;
+use std::time::{Duration, Instant};
+
+const ITERATIONS: usize = 200_000;
+const SIZE: usize = 64;
+
+/// Allocates and deallocates `SIZE` bytes in a loop. `live` counts the memory that is allocated
+/// at the same time, and must never exceed `limit`.
+fn run<G: GlobalAlloc + Sync>(alloc: &G, threads: usize, limit: usize) -> Duration {
+    let layout = Layout::from_size_align(SIZE, 8).unwrap();
+    let live = AtomicUsize::new(0);
+    let start = Instant::now();
+    thread::scope(|s| {
+        for _ in 0..threads {
+            s.spawn(|| {
+                for _ in 0..ITERATIONS {
+                    unsafe {
+                        let ptr = alloc.alloc(layout);
+                        if ptr.is_null() {
+                            continue;
+                        }
+                        let old = live.fetch_add(SIZE, SeqCst);
+                        assert!(old + SIZE <= limit, "limit exceeded");
+                        live.fetch_sub(SIZE, SeqCst);
+                        alloc.dealloc(black_box(ptr), layout);
+                    }
+                }
+            });
+        }
+    });
+    start.elapsed()
+}
+
+fn report(name: &str, threads: usize, elapsed: Duration) {
+    let ops = (threads * ITERATIONS) as f64 / elapsed.as_secs_f64();
+    println!(
+        "{:>3} threads {:>14}: {:>12.0} alloc+dealloc/s",
+        threads, name, ops
+    );
+}
+
+fn main() {
+    let max_threads = thread::available_parallelism().map_or(4, |n| n.get());
+    let mut threads = 1;
+    loop {
+        report("System", threads, run(&System, threads, usize::MAX));
+        let limit = Limit::new(usize::MAX, System);
+        report("Limit", threads, run(&limit, threads, usize::MAX));
+        let sharded: ShardedLimit<System, 16> = ShardedLimit::new(usize::MAX, System);
+        report("ShardedLimit", threads, run(&sharded, threads, usize::MAX));
+        // Only enough memory for one allocation per thread, so most allocations need to take
+        // memory from other shards
+        let tight_limit = threads * SIZE;
+        let tight: ShardedLimit<System, 16> = ShardedLimit::new(tight_limit, System);
+        report("tight", threads, run(&tight, threads, tight_limit));
+        assert_eq!(tight.remaining(), tight_limit, "memory not given back");
+        if threads >= max_threads {
+            break;
+        }
+        threads = (threads * 2).min(max_threads);
+    }
+}
@@ -0,0 +1,50 @@
+//! Compares the allocation throughput of `System` with a `Limit` without a limit, created using
+//! `Limit::unlimited`, and with a limit that is never reached. Run with
+//! `cargo bench --bench unlimited`.
+use limit_alloc::Limit;
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::hint::black_box;
+use std::time::{Duration, Instant};
+
+const ITERATIONS: usize = 2_000_000;
+const RUNS: usize = 5;
+
+/// Allocates and deallocates 64 bytes in a loop, returns the fastest of `RUNS` runs.
+fn bench<G: GlobalAlloc>(alloc: &G) -> Duration {
+    let layout = Layout::from_size_align(64, 8).unwrap();
+    (0..RUNS)
+        .map(|_| {
+            let start = Instant::now();
+            for _ in 0..ITERATIONS {
+                unsafe {
+                    let ptr = alloc.alloc(layout);
+                    assert!(!ptr.is_null());
+                    alloc.dealloc(black_box(ptr), layout);
+                }
+            }
+            start.elapsed()
+        })
+        .min()
+        .unwrap()
+}
+
+fn main() {
+    let unlimited = Limit::unlimited(System);
+    let limited = Limit::new(usize::MAX / 2, System);
+    let results = [
+        ("System", bench(&System)),
+        ("Limit::unlimited", bench(&unlimited)),
+        ("Limit::new", bench(&limited)),
+    ];
+    assert_eq!(unlimited.allocated(), 0);
+    assert_eq!(limited.allocated(), 0);
+    let system = results[0].1.as_secs_f64();
+    for (name, elapsed) in results {
+        println!(
+            "{:>16}: {:>12.0} iterations/s, {:>5.1}% of System",
+            name,
+            ITERATIONS as f64 / elapsed.as_secs_f64(),
+            system / elapsed.as_secs_f64() * 100.0
+        );
+    }
+}
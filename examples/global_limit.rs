@@ -0,0 +1,15 @@
+// Limit available RAM to 4MB, and print the statistics of the global allocator
+limit_alloc::limit_global!(4 * 1024 * 1024);
+
+fn main() {
+    let mut v: Vec<u8> = Vec::new();
+    if v.try_reserve(8 * 1024 * 1024).is_err() {
+        println!("allocation of 8MB failed");
+    }
+    v.resize(1024 * 1024, 0);
+    let stats = global_limit().stats();
+    println!(
+        "allocated {} bytes, {} bytes remaining, {} allocations rejected",
+        stats.allocated, stats.remaining, stats.rejected
+    );
+}
@@ -22,6 +22,11 @@ enum AllocatorMethod {
         // Free the index^th allocation we've made.
         index: usize,
     },
+    Realloc {
+        // Resize the index^th allocation we've made.
+        index: usize,
+        new_size: usize,
+    },
 }
 
 struct FakeAlloc {
@@ -190,6 +195,17 @@ unsafe impl GlobalAlloc for FakeAlloc {
             .unwrap()
             .add_free_region(ptr as usize, size)
     }
+
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        // Same as the default implementation, but without copying the memory because it does not
+        // exist
+        let new_layout = Layout::from_size_align_unchecked(new_size, layout.align());
+        let new_ptr = self.alloc(new_layout);
+        if !new_ptr.is_null() {
+            self.dealloc(ptr, layout);
+        }
+        new_ptr
+    }
 }
 
 fuzz_target!(|methods: Vec<AllocatorMethod>| {
@@ -229,6 +245,23 @@ fuzz_target!(|methods: Vec<AllocatorMethod>| {
                 }
                 _ => {}
             },
+            AllocatorMethod::Realloc { index, new_size } => match allocs.get(index) {
+                Some((ptr, layout)) if !ptr.is_null() => {
+                    if new_size == 0 {
+                        continue;
+                    }
+                    let new_layout = Layout::from_size_align(new_size, layout.align());
+                    if new_layout.is_err() {
+                        continue;
+                    }
+                    let new_ptr = unsafe { a.realloc(*ptr, *layout, new_size) };
+                    // On failure the old allocation is still valid
+                    if !new_ptr.is_null() {
+                        allocs[index] = (new_ptr, new_layout.unwrap());
+                    }
+                }
+                _ => {}
+            },
         }
         //println!("{:?}", allocs);
     }
@@ -0,0 +1,86 @@
+//! Allocation that is deallocated when dropped, see `Limit::alloc_guard`.
+use crate::Limit;
+use core::alloc::{GlobalAlloc, Layout};
+use core::fmt;
+use core::mem;
+use core::ptr::{self, NonNull};
+
+/// Memory allocated using `Limit::alloc_guard`. It owns the allocation, and deallocates it
+/// through the `Limit` when dropped, so the memory is returned to the limit on every exit path,
+/// including when a panic unwinds. Use `leak` to keep the memory allocated.
+///
+/// The guard does not know what the memory contains, so it only gives access to a raw pointer.
+/// Allocations of size 0 do not call the inner allocator, the pointer is dangling but aligned.
+pub struct AllocGuard<'a, A: GlobalAlloc> {
+    limit: &'a Limit<A>,
+    ptr: NonNull<u8>,
+    layout: Layout,
+}
+
+// Safety: the guard owns the allocation, same as a `Box`, and the limit can be shared
+unsafe impl<A: GlobalAlloc> Send for AllocGuard<'_, A> where Limit<A>: Sync {}
+
+impl<'a, A: GlobalAlloc> AllocGuard<'a, A> {
+    pub(crate) fn new(limit: &'a Limit<A>, layout: Layout) -> Option<Self> {
+        let ptr = if layout.size() == 0 {
+            // Safety: the alignment is never 0
+            unsafe { NonNull::new_unchecked(ptr::without_provenance_mut(layout.align())) }
+        } else {
+            // Safety: the size is not 0
+            NonNull::new(unsafe { limit.try_alloc(layout) }?)?
+        };
+        Some(Self { limit, ptr, layout })
+    }
+
+    /// Returns a pointer to the start of the allocation.
+    pub fn as_ptr(&self) -> *mut u8 {
+        self.ptr.as_ptr()
+    }
+
+    /// Same as `as_ptr`.
+    pub fn as_non_null(&self) -> NonNull<u8> {
+        self.ptr
+    }
+
+    /// Returns the size of the allocation in bytes.
+    pub fn len(&self) -> usize {
+        self.layout.size()
+    }
+
+    /// Returns true if the size of the allocation is 0.
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Returns the layout that was used to allocate.
+    pub fn layout(&self) -> Layout {
+        self.layout
+    }
+
+    /// Returns the pointer without deallocating it, so the memory stays allocated and counted by
+    /// the limit. To free it, pass it to `GlobalAlloc::dealloc` of the same `Limit` with the same
+    /// `layout`, unless its size is 0.
+    pub fn leak(self) -> NonNull<u8> {
+        let ptr = self.ptr;
+        mem::forget(self);
+        ptr
+    }
+}
+
+impl<A: GlobalAlloc> Drop for AllocGuard<'_, A> {
+    fn drop(&mut self) {
+        if self.layout.size() != 0 {
+            // Safety: the pointer was allocated through this limit with this layout
+            unsafe { self.limit.dealloc(self.ptr.as_ptr(), self.layout) };
+        }
+    }
+}
+
+impl<A: GlobalAlloc> fmt::Debug for AllocGuard<'_, A> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("AllocGuard")
+            .field("ptr", &self.ptr)
+            .field("layout", &self.layout)
+            .finish()
+    }
+}
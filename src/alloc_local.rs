@@ -0,0 +1,18 @@
+//! Thread locals that can be used from inside the allocator.
+
+/// Same as `std::thread_local!`, but the initializer must be a `const` expression and the type
+/// must not need to be dropped.
+///
+/// A thread local with a lazy initializer or a destructor allocates or registers the destructor
+/// the first time it is used by each thread, and when that happens inside the global allocator
+/// it would call the allocator again. A const initialized thread local without a destructor only
+/// needs the thread local storage itself, so it can be used from `alloc` and `dealloc`. It may
+/// still be unavailable while the thread is exiting, so it must be accessed using `try_with`.
+macro_rules! alloc_local {
+    ($($(#[$attr:meta])* static $name:ident: $t:ty = $init:expr;)+) => {
+        std::thread_local! {
+            $($(#[$attr])* static $name: $t = const { $init };)+
+        }
+        $(const _: () = assert!(!core::mem::needs_drop::<$t>(), "alloc_local needs drop");)+
+    };
+}
@@ -0,0 +1,173 @@
+//! Implementation of the `Allocator` trait, this allows to limit the memory used by a single
+//! collection, for example using `Vec::new_in(&limit)`.
+//!
+//! The trait is implemented twice: the unstable one from the standard library (with the `nightly`
+//! feature) and the stable polyfill from the `allocator-api2` crate (with the `allocator-api2`
+//! feature). Both are implemented on top of `GlobalAlloc`, so the accounting is the same as when
+//! using the allocator as the global allocator.
+
+macro_rules! impl_allocator {
+    ($($path:ident)::+) => {
+        #[cfg(feature = "alloc")]
+        use crate::ArcLimit;
+        use crate::{ConstLimit, ConstLimitTag, Limit};
+        use core::alloc::{GlobalAlloc, Layout};
+        use core::ptr::{self, NonNull};
+        use $($path)::+::{AllocError, Allocator};
+
+        /// Pointer returned for zero-sized allocations, these must never reach the inner
+        /// allocator.
+        fn dangling(layout: Layout) -> NonNull<[u8]> {
+            // The alignment is never 0, so this pointer is never null
+            let ptr = ptr::without_provenance_mut(layout.align());
+            NonNull::slice_from_raw_parts(unsafe { NonNull::new_unchecked(ptr) }, 0)
+        }
+
+        fn to_slice(ptr: *mut u8, len: usize) -> Result<NonNull<[u8]>, AllocError> {
+            NonNull::new(ptr)
+                .map(|ptr| NonNull::slice_from_raw_parts(ptr, len))
+                .ok_or(AllocError)
+        }
+
+        fn allocate<G: GlobalAlloc>(
+            alloc: &G,
+            layout: Layout,
+        ) -> Result<NonNull<[u8]>, AllocError> {
+            if layout.size() == 0 {
+                return Ok(dangling(layout));
+            }
+            to_slice(unsafe { alloc.alloc(layout) }, layout.size())
+        }
+
+        fn allocate_zeroed<G: GlobalAlloc>(
+            alloc: &G,
+            layout: Layout,
+        ) -> Result<NonNull<[u8]>, AllocError> {
+            if layout.size() == 0 {
+                return Ok(dangling(layout));
+            }
+            to_slice(unsafe { alloc.alloc_zeroed(layout) }, layout.size())
+        }
+
+        unsafe fn deallocate<G: GlobalAlloc>(alloc: &G, ptr: NonNull<u8>, layout: Layout) {
+            if layout.size() != 0 {
+                alloc.dealloc(ptr.as_ptr(), layout);
+            }
+        }
+
+        /// Move the allocation to a new block, for the cases that `GlobalAlloc::realloc` cannot
+        /// handle. Both blocks are counted against the limit until the old one is deallocated.
+        unsafe fn reallocate<G: GlobalAlloc>(
+            alloc: &G,
+            ptr: NonNull<u8>,
+            old_layout: Layout,
+            new_layout: Layout,
+        ) -> Result<NonNull<[u8]>, AllocError> {
+            let new = allocate(alloc, new_layout)?;
+            let len = old_layout.size().min(new_layout.size());
+            ptr::copy_nonoverlapping(ptr.as_ptr(), new.cast::<u8>().as_ptr(), len);
+            deallocate(alloc, ptr, old_layout);
+            Ok(new)
+        }
+
+        unsafe fn grow<G: GlobalAlloc>(
+            alloc: &G,
+            ptr: NonNull<u8>,
+            old_layout: Layout,
+            new_layout: Layout,
+        ) -> Result<NonNull<[u8]>, AllocError> {
+            if old_layout.size() == 0 || old_layout.align() != new_layout.align() {
+                return reallocate(alloc, ptr, old_layout, new_layout);
+            }
+            // realloc only charges the difference between the old and the new size
+            let new = alloc.realloc(ptr.as_ptr(), old_layout, new_layout.size());
+            to_slice(new, new_layout.size())
+        }
+
+        unsafe fn grow_zeroed<G: GlobalAlloc>(
+            alloc: &G,
+            ptr: NonNull<u8>,
+            old_layout: Layout,
+            new_layout: Layout,
+        ) -> Result<NonNull<[u8]>, AllocError> {
+            let new = grow(alloc, ptr, old_layout, new_layout)?;
+            new.cast::<u8>()
+                .as_ptr()
+                .add(old_layout.size())
+                .write_bytes(0, new_layout.size() - old_layout.size());
+            Ok(new)
+        }
+
+        unsafe fn shrink<G: GlobalAlloc>(
+            alloc: &G,
+            ptr: NonNull<u8>,
+            old_layout: Layout,
+            new_layout: Layout,
+        ) -> Result<NonNull<[u8]>, AllocError> {
+            if new_layout.size() == 0 || old_layout.align() != new_layout.align() {
+                return reallocate(alloc, ptr, old_layout, new_layout);
+            }
+            let new = alloc.realloc(ptr.as_ptr(), old_layout, new_layout.size());
+            to_slice(new, new_layout.size())
+        }
+
+        impl_allocator!(@impl [A: GlobalAlloc] Limit<A>);
+        #[cfg(feature = "alloc")]
+        impl_allocator!(@impl [A: GlobalAlloc] ArcLimit<A>);
+        impl_allocator!(
+            @impl [A: GlobalAlloc, const L: usize, T: ConstLimitTag] ConstLimit<A, L, T>
+        );
+    };
+    (@impl [$($generics:tt)*] $ty:ty) => {
+        unsafe impl<$($generics)*> Allocator for $ty {
+            fn allocate(&self, layout: Layout) -> Result<NonNull<[u8]>, AllocError> {
+                allocate(self, layout)
+            }
+
+            fn allocate_zeroed(&self, layout: Layout) -> Result<NonNull<[u8]>, AllocError> {
+                allocate_zeroed(self, layout)
+            }
+
+            unsafe fn deallocate(&self, ptr: NonNull<u8>, layout: Layout) {
+                deallocate(self, ptr, layout)
+            }
+
+            unsafe fn grow(
+                &self,
+                ptr: NonNull<u8>,
+                old_layout: Layout,
+                new_layout: Layout,
+            ) -> Result<NonNull<[u8]>, AllocError> {
+                grow(self, ptr, old_layout, new_layout)
+            }
+
+            unsafe fn grow_zeroed(
+                &self,
+                ptr: NonNull<u8>,
+                old_layout: Layout,
+                new_layout: Layout,
+            ) -> Result<NonNull<[u8]>, AllocError> {
+                grow_zeroed(self, ptr, old_layout, new_layout)
+            }
+
+            unsafe fn shrink(
+                &self,
+                ptr: NonNull<u8>,
+                old_layout: Layout,
+                new_layout: Layout,
+            ) -> Result<NonNull<[u8]>, AllocError> {
+                shrink(self, ptr, old_layout, new_layout)
+            }
+        }
+    };
+}
+
+#[cfg(feature = "nightly")]
+mod nightly {
+    impl_allocator!(core::alloc);
+}
+
+#[cfg(feature = "allocator-api2")]
+mod api2 {
+    impl_allocator!(allocator_api2::alloc);
+}
@@ -0,0 +1,164 @@
+//! Reference counted `Limit`, requires the `alloc` feature.
+use crate::{Limit, Stats};
+use alloc::sync::{Arc, Weak};
+use core::alloc::{GlobalAlloc, Layout};
+use core::fmt;
+use core::ops::Deref;
+
+/// `Limit` that implements `Clone`, all the clones share the same counters. It derefs to `Limit`,
+/// so all the methods of `Limit` are available.
+///
+/// ```
+/// use limit_alloc::{ArcLimit, Limit};
+/// use std::alloc::System;
+///
+/// let limit = ArcLimit::new(Limit::new(1_000, System));
+/// let clone = limit.clone();
+/// {
+///     let _guard = clone.scoped_raise(500);
+///     assert_eq!(limit.limit(), 1_500);
+/// }
+/// assert_eq!(limit.limit(), 1_000);
+/// ```
+pub struct ArcLimit<A>(Arc<Limit<A>>);
+
+impl<A> Clone for ArcLimit<A> {
+    fn clone(&self) -> Self {
+        Self(Arc::clone(&self.0))
+    }
+}
+
+impl<A: GlobalAlloc> ArcLimit<A> {
+    pub fn new(l: Limit<A>) -> Self {
+        Self(Arc::new(l))
+    }
+
+    /// Same as `ArcLimit::new(Limit::new(limit, alloc))`.
+    ///
+    /// ```
+    /// use limit_alloc::ArcLimit;
+    /// use std::alloc::{GlobalAlloc, Layout, System};
+    ///
+    /// let limit = ArcLimit::with_limit(1_000, System);
+    /// let clone = limit.clone();
+    /// let layout = Layout::from_size_align(300, 1).unwrap();
+    /// unsafe {
+    ///     let ptr = clone.alloc(layout);
+    ///     // All the clones share the same counters
+    ///     assert_eq!(limit.remaining(), 700);
+    ///     clone.dealloc(ptr, layout);
+    /// }
+    /// ```
+    pub fn with_limit(limit: usize, alloc: A) -> Self {
+        Self::new(Limit::new(limit, alloc))
+    }
+
+    /// Returns the shared `Limit`. `ArcLimit` also derefs to it, so this is only needed when the
+    /// deref is not applied automatically, for example to pass it to a function that expects a
+    /// `&Limit<A>`.
+    pub fn limit_handle(&self) -> &Limit<A> {
+        &self.0
+    }
+
+    /// Returns the `Limit` if this is the last clone of this `ArcLimit`, otherwise returns `self`
+    /// back.
+    pub fn try_into_inner(self) -> Result<Limit<A>, ArcLimit<A>> {
+        Arc::try_unwrap(self.0).map_err(ArcLimit)
+    }
+
+    /// Same as `Limit::child`, but the child keeps a clone of this `ArcLimit` instead of a
+    /// reference, so it can be moved to other threads or stored without a lifetime.
+    pub fn child(&self, limit: usize) -> Limit<ArcLimit<A>> {
+        Limit::new(limit, self.clone())
+    }
+}
+
+impl<A> ArcLimit<A> {
+    /// Wraps an existing `Arc<Limit<A>>`, the new `ArcLimit` shares the counters with all the other
+    /// clones of `arc`.
+    ///
+    /// ```
+    /// use limit_alloc::{ArcLimit, Limit};
+    /// use std::alloc::System;
+    /// use std::sync::Arc;
+    ///
+    /// let arc = Arc::new(Limit::new(1_000, System));
+    /// let limit = ArcLimit::from_arc(Arc::clone(&arc));
+    /// assert!(Arc::ptr_eq(limit.as_arc(), &arc));
+    /// ```
+    pub fn from_arc(arc: Arc<Limit<A>>) -> Self {
+        Self(arc)
+    }
+
+    /// Returns the inner `Arc`, clone it to get an `Arc<Limit<A>>`.
+    pub fn as_arc(&self) -> &Arc<Limit<A>> {
+        &self.0
+    }
+
+    /// Returns a `WeakLimit` that can read the statistics of this limit without keeping it
+    /// alive.
+    pub fn downgrade(&self) -> WeakLimit<A> {
+        WeakLimit(Arc::downgrade(&self.0))
+    }
+}
+
+/// Prints the inner `Limit`, for example `ArcLimit(Limit { limit: 1000, .. })`.
+impl<A: GlobalAlloc> fmt::Debug for ArcLimit<A> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_tuple("ArcLimit").field(&*self.0).finish()
+    }
+}
+
+impl<A> Deref for ArcLimit<A> {
+    type Target = Limit<A>;
+
+    fn deref(&self) -> &Limit<A> {
+        &self.0
+    }
+}
+
+unsafe impl<A: GlobalAlloc> GlobalAlloc for ArcLimit<A> {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        Limit::alloc(&self.0, layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        Limit::dealloc(&self.0, ptr, layout)
+    }
+
+    unsafe fn alloc_zeroed(&self, layout: Layout) -> *mut u8 {
+        Limit::alloc_zeroed(&self.0, layout)
+    }
+
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        Limit::realloc(&self.0, ptr, layout, new_size)
+    }
+}
+
+/// Weak reference to an `ArcLimit`, created using `ArcLimit::downgrade`. Useful for observers,
+/// for example a metrics exporter, that should not keep the allocator alive. All the methods
+/// return None once all the `ArcLimit` have been dropped.
+pub struct WeakLimit<A>(Weak<Limit<A>>);
+
+impl<A> Clone for WeakLimit<A> {
+    fn clone(&self) -> Self {
+        Self(Weak::clone(&self.0))
+    }
+}
+
+impl<A: GlobalAlloc> WeakLimit<A> {
+    /// Returns the `ArcLimit`, or None if it was already dropped.
+    pub fn upgrade(&self) -> Option<ArcLimit<A>> {
+        self.0.upgrade().map(ArcLimit)
+    }
+
+    /// Same as `Limit::remaining`.
+    pub fn remaining(&self) -> Option<usize> {
+        self.0.upgrade().map(|l| l.remaining())
+    }
+
+    /// Same as `Limit::stats`.
+    pub fn stats(&self) -> Option<Stats> {
+        self.0.upgrade().map(|l| l.stats())
+    }
+}
@@ -0,0 +1,239 @@
+//! Allocator that waits for memory to be deallocated instead of failing.
+use crate::Limit;
+use core::alloc::{GlobalAlloc, Layout};
+use core::ops::Deref;
+use core::ptr;
+use core::sync::atomic::AtomicUsize;
+use core::sync::atomic::Ordering::SeqCst;
+use std::sync::{Condvar, Mutex, MutexGuard};
+use std::time::{Duration, Instant};
+
+/// `Limit` that blocks when an allocation would exceed the limit, until another thread
+/// deallocates enough memory or until the timeout expires. After the timeout the allocation
+/// fails and returns null, same as `Limit`. It derefs to the inner `Limit`, so the statistics are
+/// available.
+///
+/// This can deadlock if all the threads that could deallocate memory are waiting for an
+/// allocation, for example with a single thread. The timeout is the only way out of that, so it
+/// should not be too long. Each attempt that does not fit in the limit counts as a failed
+/// allocation and calls the OOM handler of the inner `Limit`.
+///
+/// ```
+/// use limit_alloc::{BlockingLimit, Limit};
+/// use std::alloc::System;
+/// use std::time::Duration;
+///
+/// #[global_allocator]
+/// static A: BlockingLimit<System> =
+///     BlockingLimit::new(Limit::new(1_000_000_000, System), Duration::from_millis(100));
+/// ```
+pub struct BlockingLimit<A> {
+    limit: Limit<A>,
+    timeout: Duration,
+    /// Number of threads waiting for memory, deallocations only notify if this is not 0.
+    waiters: AtomicUsize,
+    /// Incremented every time memory is given back, so a waiting thread can tell if memory was freed
+    /// between its last attempt and the moment it takes the lock.
+    generation: AtomicUsize,
+    lock: Mutex<()>,
+    freed: Condvar,
+}
+
+impl<A: GlobalAlloc> BlockingLimit<A> {
+    /// Allocations wait at most `timeout` for memory to be deallocated.
+    pub const fn new(limit: Limit<A>, timeout: Duration) -> Self {
+        Self {
+            limit,
+            timeout,
+            waiters: AtomicUsize::new(0),
+            generation: AtomicUsize::new(0),
+            lock: Mutex::new(()),
+            freed: Condvar::new(),
+        }
+    }
+
+    /// Returns the maximum time that an allocation waits for memory.
+    pub fn timeout(&self) -> Duration {
+        self.timeout
+    }
+
+    /// Returns None if the memory limit would still be exhausted after waiting for the timeout.
+    ///
+    /// # Safety
+    ///
+    /// The same restrictions as `GlobalAlloc::alloc`.
+    pub unsafe fn try_alloc(&self, layout: Layout) -> Option<*mut u8> {
+        self.wait_for(|| self.limit.try_alloc(layout))
+    }
+
+    /// Calls `f` until it returns Some, waiting for a deallocation between calls. Returns None
+    /// after the timeout.
+    fn wait_for(&self, mut f: impl FnMut() -> Option<*mut u8>) -> Option<*mut u8> {
+        if let Some(ret) = f() {
+            return Some(ret);
+        }
+        let deadline = Instant::now() + self.timeout;
+        self.waiters.fetch_add(1, SeqCst);
+        let ret = loop {
+            // `f` runs without the lock: a failed attempt calls the OOM handler and the report of
+            // the inner `Limit`, and memory they free goes through `notify`, which takes the lock
+            let generation = self.generation.load(SeqCst);
+            if let Some(ret) = f() {
+                break Some(ret);
+            }
+            let now = Instant::now();
+            if now >= deadline {
+                break None;
+            }
+            let guard = self.lock();
+            // Memory freed after `f` failed changed the generation, try again without waiting.
+            // Otherwise the next deallocation has to take the lock to notify, so it happens
+            // after wait_timeout releases it and the notification is not lost
+            if self.generation.load(SeqCst) != generation {
+                continue;
+            }
+            drop(
+                self.freed
+                    .wait_timeout(guard, deadline - now)
+                    .unwrap_or_else(|e| e.into_inner()),
+            );
+        };
+        self.waiters.fetch_sub(1, SeqCst);
+        ret
+    }
+
+    fn lock(&self) -> MutexGuard<'_, ()> {
+        // The lock does not protect any data, so a panic while holding it does not matter
+        self.lock.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    /// Called after some memory is given back to the limit, wakes up the waiting threads.
+    fn notify(&self) {
+        self.generation.fetch_add(1, SeqCst);
+        if self.waiters.load(SeqCst) != 0 {
+            let _guard = self.lock();
+            self.freed.notify_all();
+        }
+    }
+}
+
+impl<A> Deref for BlockingLimit<A> {
+    type Target = Limit<A>;
+
+    fn deref(&self) -> &Limit<A> {
+        &self.limit
+    }
+}
+
+unsafe impl<A: GlobalAlloc> GlobalAlloc for BlockingLimit<A> {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        self.try_alloc(layout).unwrap_or(ptr::null_mut())
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        self.limit.dealloc(ptr, layout);
+        self.notify();
+    }
+
+    unsafe fn alloc_zeroed(&self, layout: Layout) -> *mut u8 {
+        self.wait_for(|| self.limit.try_alloc_zeroed(layout))
+            .unwrap_or(ptr::null_mut())
+    }
+
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        let ret = self
+            .wait_for(|| self.limit.try_realloc(ptr, layout, new_size))
+            .unwrap_or(ptr::null_mut());
+        if !ret.is_null() && new_size < layout.size() {
+            self.notify();
+        }
+        ret
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::alloc::System;
+    use std::sync::atomic::AtomicPtr;
+    use std::thread;
+
+    const LAYOUT: Layout = unsafe { Layout::from_size_align_unchecked(800, 1) };
+
+    #[test]
+    fn waits_for_another_thread_to_deallocate() {
+        let limit = BlockingLimit::new(Limit::new(1_000, System), Duration::from_secs(10));
+        let first = unsafe { limit.alloc(LAYOUT) } as usize;
+        assert_ne!(first, 0);
+        let start = Instant::now();
+        let second = thread::scope(|s| {
+            s.spawn(|| {
+                thread::sleep(Duration::from_millis(50));
+                unsafe { limit.dealloc(first as *mut u8, LAYOUT) };
+            });
+            unsafe { limit.alloc(LAYOUT) }
+        });
+        assert!(!second.is_null());
+        let elapsed = start.elapsed();
+        assert!(elapsed >= Duration::from_millis(50), "{:?}", elapsed);
+        assert!(elapsed < Duration::from_secs(10), "{:?}", elapsed);
+        unsafe { limit.dealloc(second, LAYOUT) };
+        assert_eq!(limit.allocated(), 0);
+    }
+
+    #[test]
+    fn many_threads_share_the_memory() {
+        let limit = BlockingLimit::new(Limit::new(1_000, System), Duration::from_secs(10));
+        thread::scope(|s| {
+            for _ in 0..8 {
+                s.spawn(|| {
+                    for _ in 0..50 {
+                        let ptr = unsafe { limit.alloc(LAYOUT) };
+                        assert!(!ptr.is_null());
+                        unsafe { limit.dealloc(ptr, LAYOUT) };
+                    }
+                });
+            }
+        });
+        assert_eq!(limit.allocated(), 0);
+    }
+
+    #[test]
+    fn timeout_returns_null() {
+        let limit = BlockingLimit::new(Limit::new(1_000, System), Duration::from_millis(50));
+        let first = unsafe { limit.alloc(LAYOUT) };
+        let start = Instant::now();
+        assert!(unsafe { limit.alloc(LAYOUT) }.is_null());
+        assert!(start.elapsed() >= Duration::from_millis(50));
+        assert!(unsafe { limit.try_alloc(LAYOUT) }.is_none());
+        unsafe { limit.dealloc(first, LAYOUT) };
+        assert_eq!(limit.allocated(), 0);
+    }
+
+    static REENTRANT: BlockingLimit<System> =
+        BlockingLimit::new(Limit::new(1_000, System), Duration::from_secs(10));
+    static STASHED: AtomicPtr<u8> = AtomicPtr::new(ptr::null_mut());
+
+    /// Deallocates through the same `BlockingLimit` from inside the OOM handler, as the report
+    /// would when the `BlockingLimit` is the global allocator.
+    fn free_stashed(_layout: Layout, _remaining: usize) {
+        let ptr = STASHED.swap(ptr::null_mut(), SeqCst);
+        if !ptr.is_null() {
+            unsafe { REENTRANT.dealloc(ptr, LAYOUT) };
+        }
+    }
+
+    #[test]
+    fn oom_handler_can_deallocate() {
+        REENTRANT.set_oom_handler(free_stashed);
+        STASHED.store(unsafe { REENTRANT.alloc(LAYOUT) }, SeqCst);
+        // The first attempt fails and the handler frees the stashed memory, so the retry
+        // succeeds without waiting for the timeout
+        let start = Instant::now();
+        let ptr = unsafe { REENTRANT.alloc(LAYOUT) };
+        assert!(!ptr.is_null());
+        assert!(start.elapsed() < Duration::from_secs(10));
+        unsafe { REENTRANT.dealloc(ptr, LAYOUT) };
+        assert_eq!(REENTRANT.allocated(), 0);
+    }
+}
@@ -0,0 +1,523 @@
+//! Builder to configure the options of a `Limit`, see `Limit::builder`.
+use crate::watermark::{SoftLimit, Watermarks};
+#[cfg(feature = "alloc")]
+use crate::ArcLimit;
+use crate::{Accounting, CounterOrdering, Limit, Overcommit, WatermarkCallback, MAX_WATERMARKS};
+use core::alloc::{GlobalAlloc, Layout};
+use core::fmt;
+use core::mem::ManuallyDrop;
+use core::sync::atomic::{AtomicBool, AtomicPtr, AtomicUsize};
+
+/// Configures the options of a `Limit` before creating it. All the methods are `const`, so this
+/// can be used to create a global allocator. Options that are not set keep the same default as
+/// `Limit::new`, and the limit itself defaults to `usize::MAX`, see `Limit::unlimited`.
+///
+/// ```
+/// use limit_alloc::{Limit, LimitBuilder};
+/// use std::alloc::{GlobalAlloc, Layout, System};
+///
+/// #[global_allocator]
+/// static A: Limit<System> = LimitBuilder::new(System)
+///     .limit(1 << 30)
+///     .max_single_alloc(1 << 28)
+///     .count_padding(true)
+///     .build();
+///
+/// fn main() {
+///     assert_eq!(A.limit(), 1 << 30);
+///     assert_eq!(A.max_single_alloc(), 1 << 28);
+///
+///     let limit = Limit::builder(System).limit(1_000).count_padding(true).build();
+///     let layout = Layout::from_size_align(1, 64).unwrap();
+///     let ptr = unsafe { limit.alloc(layout) };
+///     assert_eq!(limit.allocated(), 64);
+///     unsafe { limit.dealloc(ptr, layout) };
+/// }
+/// ```
+pub struct LimitBuilder<A> {
+    /// `ManuallyDrop` so the builder has no destructor, otherwise `build` could not be `const`.
+    /// The allocator is only leaked if the builder is dropped without calling `build`.
+    alloc: ManuallyDrop<A>,
+    limit: usize,
+    max_single_alloc: usize,
+    max_live_allocations: usize,
+    accounting: Accounting,
+    ordering: CounterOrdering,
+    overcommit: Overcommit,
+    strict_accounting: bool,
+    oom_handler: Option<fn(Layout, usize)>,
+    soft_limit: usize,
+    soft_limit_hook: Option<WatermarkCallback>,
+    /// The first `watermarks_len` elements are the watermarks, all of them are `Some`.
+    watermarks: [(usize, Option<WatermarkCallback>); MAX_WATERMARKS],
+    watermarks_len: usize,
+    #[cfg(feature = "std")]
+    reserve: usize,
+}
+
+impl<A: GlobalAlloc> LimitBuilder<A> {
+    pub const fn new(alloc: A) -> Self {
+        Self {
+            alloc: ManuallyDrop::new(alloc),
+            limit: usize::MAX,
+            max_single_alloc: usize::MAX,
+            max_live_allocations: usize::MAX,
+            accounting: Accounting::Size,
+            ordering: CounterOrdering::SeqCst,
+            overcommit: Overcommit::Reject,
+            strict_accounting: false,
+            oom_handler: None,
+            soft_limit: usize::MAX,
+            soft_limit_hook: None,
+            watermarks: [(usize::MAX, None); MAX_WATERMARKS],
+            watermarks_len: 0,
+            #[cfg(feature = "std")]
+            reserve: 0,
+        }
+    }
+
+    /// Sets the memory limit in bytes.
+    pub const fn limit(mut self, bytes: usize) -> Self {
+        self.limit = bytes;
+        self
+    }
+
+    /// Sets the maximum size of a single allocation, see `Limit::set_max_single_alloc`.
+    pub const fn max_single_alloc(mut self, bytes: usize) -> Self {
+        self.max_single_alloc = bytes;
+        self
+    }
+
+    /// Sets the maximum number of live allocations, see `Limit::with_counts`.
+    pub const fn max_live_allocations(mut self, count: usize) -> Self {
+        self.max_live_allocations = count;
+        self
+    }
+
+    /// Counts the padding of each allocation if `padded` is true, same as `Accounting::Padded`.
+    pub const fn count_padding(mut self, padded: bool) -> Self {
+        self.accounting = if padded {
+            Accounting::Padded
+        } else {
+            Accounting::Size
+        };
+        self
+    }
+
+    /// Same as `count_padding`.
+    #[inline]
+    pub const fn count_padded(self, padded: bool) -> Self {
+        self.count_padding(padded)
+    }
+
+    /// Sets how many bytes are counted for each allocation, see `Limit::with_accounting`.
+    pub const fn accounting(mut self, accounting: Accounting) -> Self {
+        self.accounting = accounting;
+        self
+    }
+
+    /// Sets the memory ordering of the counter, see `Limit::with_ordering`.
+    pub const fn ordering(mut self, ordering: CounterOrdering) -> Self {
+        self.ordering = ordering;
+        self
+    }
+
+    /// Sets what happens to allocations that exceed the limit, see `Limit::set_overcommit`.
+    pub const fn overcommit(mut self, policy: Overcommit) -> Self {
+        self.overcommit = policy;
+        self
+    }
+
+    /// Records the deallocations of more memory than is allocated, see
+    /// `Limit::set_strict_accounting`.
+    pub const fn strict_accounting(mut self, strict: bool) -> Self {
+        self.strict_accounting = strict;
+        self
+    }
+
+    /// Sets the function called when an allocation fails, see `Limit::set_oom_handler`.
+    pub const fn oom_handler(mut self, f: fn(Layout, usize)) -> Self {
+        self.oom_handler = Some(f);
+        self
+    }
+
+    /// Same as `oom_handler`.
+    #[inline]
+    pub const fn on_exhausted(self, f: fn(Layout, usize)) -> Self {
+        self.oom_handler(f)
+    }
+
+    /// Sets the soft limit and the function called when it is crossed, see
+    /// `Limit::set_soft_limit`.
+    pub const fn soft_limit(mut self, bytes: usize, hook: Option<WatermarkCallback>) -> Self {
+        self.soft_limit = bytes;
+        self.soft_limit_hook = hook;
+        self
+    }
+
+    /// Sets the watermarks, see `Limit::set_watermarks`.
+    ///
+    /// # Panics
+    ///
+    /// If more than `MAX_WATERMARKS` watermarks are passed, or if a threshold is 0, see
+    /// `Limit::set_watermarks`. In a `const` context these are compile errors.
+    pub const fn watermarks(mut self, watermarks: &[(usize, WatermarkCallback)]) -> Self {
+        assert!(
+            watermarks.len() <= MAX_WATERMARKS,
+            "too many watermarks, see MAX_WATERMARKS"
+        );
+        let mut i = 0;
+        while i < watermarks.len() {
+            assert!(watermarks[i].0 != 0, "a watermark threshold cannot be 0");
+            self.watermarks[i] = (watermarks[i].0, Some(watermarks[i].1));
+            i += 1;
+        }
+        self.watermarks_len = watermarks.len();
+        self
+    }
+
+    /// Sets the emergency reserve, on top of the limit, see `Limit::with_reserve`. `try_build`
+    /// returns an error if the reserve is bigger than the limit.
+    #[cfg(feature = "std")]
+    pub const fn reserve(mut self, bytes: usize) -> Self {
+        self.reserve = bytes;
+        self
+    }
+
+    /// Returns an error if the options do not make sense together, see `BuildError`.
+    pub const fn validate(&self) -> Result<(), BuildError> {
+        #[cfg(feature = "std")]
+        if self.reserve > self.limit {
+            return Err(BuildError::ReserveAboveLimit {
+                reserve: self.reserve,
+                limit: self.limit,
+            });
+        }
+        if self.soft_limit != usize::MAX && self.soft_limit > self.limit {
+            return Err(BuildError::SoftLimitAboveLimit {
+                soft_limit: self.soft_limit,
+                limit: self.limit,
+            });
+        }
+        Ok(())
+    }
+
+    /// Same as `build`, but returns an error instead of creating the `Limit` if `validate` fails.
+    ///
+    /// ```
+    /// use limit_alloc::{BuildError, Limit};
+    /// use std::alloc::System;
+    ///
+    /// let err = Limit::builder(System)
+    ///     .limit(1_000)
+    ///     .soft_limit(2_000, None)
+    ///     .try_build()
+    ///     .unwrap_err();
+    /// assert_eq!(
+    ///     err,
+    ///     BuildError::SoftLimitAboveLimit {
+    ///         soft_limit: 2_000,
+    ///         limit: 1_000
+    ///     }
+    /// );
+    /// assert_eq!(err.to_string(), "soft limit of 2000 bytes is above the limit of 1000 bytes");
+    ///
+    /// # #[cfg(feature = "std")]
+    /// # {
+    /// let err = Limit::builder(System).limit(1_000).reserve(5_000).try_build().unwrap_err();
+    /// assert_eq!(err.to_string(), "reserve of 5000 bytes is bigger than the limit of 1000 bytes");
+    /// # }
+    ///
+    /// let limit = Limit::builder(System).limit(1_000).soft_limit(800, None).try_build().unwrap();
+    /// assert_eq!(limit.soft_limit(), 800);
+    /// ```
+    pub fn try_build(self) -> Result<Limit<A>, BuildError> {
+        match self.validate() {
+            Ok(()) => Ok(self.build()),
+            Err(e) => {
+                drop(ManuallyDrop::into_inner(self.alloc));
+                Err(e)
+            }
+        }
+    }
+
+    /// Same as `build`, but returns an `ArcLimit`.
+    #[cfg(feature = "alloc")]
+    pub fn build_arc(self) -> ArcLimit<A> {
+        ArcLimit::new(self.build())
+    }
+
+    /// Creates the `Limit`. The options are not validated, use `try_build` for that.
+    pub const fn build(self) -> Limit<A> {
+        let mut l = Limit::with_accounting(
+            self.limit,
+            ManuallyDrop::into_inner(self.alloc),
+            self.accounting,
+        );
+        l.max_single_alloc = AtomicUsize::new(self.max_single_alloc);
+        l.max_live_allocations = self.max_live_allocations;
+        l.ordering = self.ordering;
+        l.overcommit = AtomicBool::new(matches!(self.overcommit, Overcommit::Allow));
+        l.strict_accounting = AtomicBool::new(self.strict_accounting);
+        if let Some(f) = self.oom_handler {
+            l.oom_handler = AtomicPtr::new(f as *mut ());
+        }
+        l.soft_limit = SoftLimit::with_threshold(self.soft_limit, self.soft_limit_hook);
+        l.watermarks = Watermarks::with_thresholds(self.watermarks, self.watermarks_len);
+        #[cfg(feature = "std")]
+        {
+            l.reserve = self.reserve;
+        }
+        l
+    }
+}
+
+/// Reason why `LimitBuilder::try_build` failed.
+#[non_exhaustive]
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum BuildError {
+    /// The emergency reserve is bigger than the limit.
+    ReserveAboveLimit { reserve: usize, limit: usize },
+    /// The soft limit is above the limit, so it would never be crossed.
+    SoftLimitAboveLimit { soft_limit: usize, limit: usize },
+}
+
+impl fmt::Display for BuildError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            BuildError::ReserveAboveLimit { reserve, limit } => write!(
+                f,
+                "reserve of {} bytes is bigger than the limit of {} bytes",
+                reserve, limit
+            ),
+            BuildError::SoftLimitAboveLimit { soft_limit, limit } => write!(
+                f,
+                "soft limit of {} bytes is above the limit of {} bytes",
+                soft_limit, limit
+            ),
+        }
+    }
+}
+
+#[cfg(feature = "std")]
+impl std::error::Error for BuildError {}
+
+#[cfg(all(test, feature = "std"))]
+mod tests {
+    use super::*;
+    use core::sync::atomic::Ordering::SeqCst;
+    use std::alloc::System;
+
+    const LAYOUT: Layout = unsafe { Layout::from_size_align_unchecked(100, 1) };
+
+    #[test]
+    fn defaults_are_the_same_as_unlimited() {
+        let built = LimitBuilder::new(System).build();
+        let unlimited = Limit::unlimited(System);
+        assert_eq!(built.limit(), unlimited.limit());
+        assert_eq!(built.max_single_alloc(), usize::MAX);
+        assert_eq!(built.max_live_allocations(), usize::MAX);
+        assert!(matches!(built.accounting, Accounting::Size));
+        assert_eq!(built.ordering, CounterOrdering::SeqCst);
+        assert_eq!(built.overcommit(), Overcommit::Reject);
+        assert_eq!(built.soft_limit(), usize::MAX);
+        assert_eq!(built.stats(), unlimited.stats());
+    }
+
+    #[test]
+    fn limit() {
+        let limit = Limit::builder(System).limit(150).build();
+        let ptr = unsafe { limit.alloc(LAYOUT) };
+        assert!(!ptr.is_null());
+        assert!(unsafe { limit.alloc(LAYOUT) }.is_null());
+        unsafe { limit.dealloc(ptr, LAYOUT) };
+    }
+
+    #[test]
+    fn max_single_alloc() {
+        let limit = Limit::builder(System).max_single_alloc(99).build();
+        assert_eq!(limit.max_single_alloc(), 99);
+        assert!(unsafe { limit.alloc(LAYOUT) }.is_null());
+        assert_eq!(limit.rejected_allocations(), 1);
+    }
+
+    #[test]
+    fn max_live_allocations() {
+        let limit = Limit::builder(System).max_live_allocations(1).build();
+        let ptr = unsafe { limit.alloc(LAYOUT) };
+        assert!(unsafe { limit.alloc(LAYOUT) }.is_null());
+        assert_eq!(limit.live_allocations(), 1);
+        unsafe { limit.dealloc(ptr, LAYOUT) };
+        assert_eq!(limit.live_allocations(), 0);
+    }
+
+    #[test]
+    fn count_padding_and_accounting() {
+        let layout = Layout::from_size_align(1, 64).unwrap();
+        for builder in [
+            Limit::builder(System).count_padding(true),
+            Limit::builder(System).count_padded(true),
+            Limit::builder(System).accounting(Accounting::Padded),
+        ] {
+            let limit = builder.build();
+            let ptr = unsafe { limit.alloc(layout) };
+            assert_eq!(limit.allocated(), 64);
+            unsafe { limit.dealloc(ptr, layout) };
+        }
+        let limit = Limit::builder(System)
+            .count_padding(true)
+            .count_padded(false)
+            .build();
+        assert!(matches!(limit.accounting, Accounting::Size));
+    }
+
+    #[test]
+    fn ordering() {
+        let limit = Limit::builder(System)
+            .ordering(CounterOrdering::Relaxed)
+            .build();
+        assert_eq!(limit.ordering, CounterOrdering::Relaxed);
+    }
+
+    #[test]
+    fn overcommit() {
+        let limit = Limit::builder(System)
+            .limit(150)
+            .overcommit(Overcommit::Allow)
+            .build();
+        let a = unsafe { limit.alloc(LAYOUT) };
+        let b = unsafe { limit.alloc(LAYOUT) };
+        assert!(!b.is_null());
+        assert_eq!(limit.overcommitted_bytes(), 50);
+        unsafe {
+            limit.dealloc(a, LAYOUT);
+            limit.dealloc(b, LAYOUT);
+        }
+    }
+
+    #[test]
+    fn strict_accounting() {
+        let limit = Limit::builder(System).strict_accounting(true).build();
+        assert!(limit.strict_accounting.load(SeqCst));
+    }
+
+    static EXHAUSTED: AtomicUsize = AtomicUsize::new(0);
+
+    fn count_exhausted(_layout: Layout, _remaining: usize) {
+        EXHAUSTED.fetch_add(1, SeqCst);
+    }
+
+    #[test]
+    fn oom_handler_and_on_exhausted() {
+        for builder in [
+            Limit::builder(System).limit(0).oom_handler(count_exhausted),
+            Limit::builder(System)
+                .limit(0)
+                .on_exhausted(count_exhausted),
+        ] {
+            let limit = builder.build();
+            let before = EXHAUSTED.load(SeqCst);
+            assert!(unsafe { limit.alloc(LAYOUT) }.is_null());
+            assert_eq!(EXHAUSTED.load(SeqCst), before + 1);
+        }
+    }
+
+    static SOFT: AtomicUsize = AtomicUsize::new(0);
+
+    #[test]
+    fn soft_limit() {
+        let limit = Limit::builder(System)
+            .soft_limit(50, Some(|allocated, _| SOFT.store(allocated, SeqCst)))
+            .build();
+        assert_eq!(limit.soft_limit(), 50);
+        let ptr = unsafe { limit.alloc(LAYOUT) };
+        assert!(limit.is_over_soft_limit());
+        assert_eq!(SOFT.load(SeqCst), 100);
+        unsafe { limit.dealloc(ptr, LAYOUT) };
+    }
+
+    static LOW: AtomicUsize = AtomicUsize::new(0);
+    static HIGH: AtomicUsize = AtomicUsize::new(0);
+
+    #[test]
+    fn watermarks() {
+        static LIMIT: Limit<System> = LimitBuilder::new(System)
+            .limit(1_000)
+            .watermarks(&[
+                (100, |allocated, _| LOW.store(allocated, SeqCst)),
+                (500, |allocated, _| HIGH.store(allocated, SeqCst)),
+            ])
+            .build();
+        let ptr = unsafe { LIMIT.alloc(LAYOUT) };
+        assert_eq!(LOW.load(SeqCst), 100);
+        assert_eq!(HIGH.load(SeqCst), 0);
+        unsafe { LIMIT.dealloc(ptr, LAYOUT) };
+    }
+
+    #[test]
+    #[should_panic(expected = "too many watermarks")]
+    fn too_many_watermarks() {
+        let w: (usize, WatermarkCallback) = (1, |_, _| {});
+        let _ = Limit::builder(System).watermarks(&[w; MAX_WATERMARKS + 1]);
+    }
+
+    #[test]
+    fn reserve() {
+        let limit = Limit::builder(System).limit(50).reserve(100).build();
+        assert!(unsafe { limit.alloc(LAYOUT) }.is_null());
+        let ptr = limit.use_reserve(|| unsafe { limit.alloc(LAYOUT) });
+        assert!(!ptr.is_null());
+        // Only the bytes above the limit come from the reserve
+        assert_eq!(limit.reserve_used(), 50);
+        unsafe { limit.dealloc(ptr, LAYOUT) };
+    }
+
+    #[test]
+    fn try_build_validates() {
+        let err = Limit::builder(System)
+            .limit(10)
+            .soft_limit(20, None)
+            .try_build()
+            .unwrap_err();
+        assert_eq!(
+            err,
+            BuildError::SoftLimitAboveLimit {
+                soft_limit: 20,
+                limit: 10
+            }
+        );
+        // The soft limit is not set by default, so it is never above the limit
+        assert_eq!(Limit::builder(System).limit(10).validate(), Ok(()));
+    }
+
+    #[test]
+    fn try_build_rejects_a_reserve_above_the_limit() {
+        let err = Limit::builder(System)
+            .limit(10)
+            .reserve(20)
+            .try_build()
+            .unwrap_err();
+        assert_eq!(
+            err,
+            BuildError::ReserveAboveLimit {
+                reserve: 20,
+                limit: 10
+            }
+        );
+        assert!(Limit::builder(System)
+            .limit(10)
+            .reserve(10)
+            .try_build()
+            .is_ok());
+    }
+
+    #[test]
+    fn build_arc() {
+        let limit = Limit::builder(System).limit(150).build_arc();
+        let clone = limit.clone();
+        let ptr = unsafe { limit.alloc(LAYOUT) };
+        assert_eq!(clone.allocated(), 100);
+        unsafe { clone.dealloc(ptr, LAYOUT) };
+        assert_eq!(limit.allocated(), 0);
+    }
+}
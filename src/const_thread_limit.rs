@@ -0,0 +1,213 @@
+//! Allocator with a memory limit known at compile time for each thread.
+use std::alloc::{GlobalAlloc, Layout};
+use std::cell::Cell;
+use std::ptr;
+
+struct ThreadCounter {
+    /// Memory allocated by this thread, in bytes.
+    allocated: Cell<usize>,
+    /// Highest value of `allocated`.
+    peak: Cell<usize>,
+}
+
+alloc_local! {
+    static COUNTER: ThreadCounter = ThreadCounter {
+        allocated: Cell::new(0),
+        peak: Cell::new(0),
+    };
+}
+
+/// Same as `ConstLimit`, but each thread has its own counter, so each thread can allocate up to
+/// `L` bytes. This is useful in tests, which run in parallel in different threads: with a
+/// `ConstLimit` they would all share the same static counter. The counter is stored in a thread
+/// local, so this type is zero-sized if the inner allocator is zero-sized. All the
+/// `ConstThreadLimit` share the same counters, whatever their limit.
+///
+/// Memory is always credited to the thread that deallocates it, because a thread cannot modify
+/// the counter of another thread, same as `ThreadLimit`. So if a thread allocates memory and
+/// another thread deallocates it, the memory is still counted by the first thread, and the counter
+/// of the second thread saturates at 0. All the methods, such as `remaining` and `allocated`,
+/// return the value for the current thread.
+///
+/// ```
+/// use limit_alloc::ConstThreadLimit;
+/// use std::alloc::{GlobalAlloc, Layout, System};
+///
+/// static LIMIT: ConstThreadLimit<System, 1_000> = ConstThreadLimit::new(System);
+///
+/// let layout = Layout::from_size_align(800, 1).unwrap();
+/// std::thread::scope(|s| {
+///     for _ in 0..4 {
+///         s.spawn(|| unsafe {
+///             // Each thread can allocate 1_000 bytes
+///             let ptr = LIMIT.try_alloc(layout).unwrap();
+///             assert_eq!(LIMIT.remaining(), 200);
+///             assert!(LIMIT.try_alloc(layout).is_none());
+///             LIMIT.dealloc(ptr, layout);
+///         });
+///     }
+/// });
+/// assert_eq!(LIMIT.allocated(), 0);
+/// assert_eq!(std::mem::size_of_val(&LIMIT), 0);
+/// ```
+#[derive(Clone)]
+pub struct ConstThreadLimit<A, const L: usize> {
+    alloc: A,
+}
+
+impl<A: GlobalAlloc, const L: usize> ConstThreadLimit<A, L> {
+    pub const fn new(alloc: A) -> Self {
+        Self { alloc }
+    }
+
+    /// Returns a reference to the inner allocator. Memory allocated using the inner allocator
+    /// directly is not counted, so it must also be deallocated using the inner allocator.
+    pub fn inner(&self) -> &A {
+        &self.alloc
+    }
+
+    /// Returns the memory limit of each thread in bytes, this is always `L`.
+    pub fn limit(&self) -> usize {
+        L
+    }
+
+    /// Returns the memory allocated by the current thread, in bytes.
+    pub fn allocated(&self) -> usize {
+        COUNTER.try_with(|c| c.allocated.get()).unwrap_or(0)
+    }
+
+    /// Returns the remaining memory of the current thread in bytes. Memory allocated by this
+    /// thread and deallocated by another thread is still counted.
+    pub fn remaining(&self) -> usize {
+        // Saturate because ConstThreadLimit with a bigger limit may have allocated more memory
+        // than L
+        L.saturating_sub(self.allocated())
+    }
+
+    /// Returns true if `remaining() >= bytes`, see `Limit::has_headroom`.
+    pub fn has_headroom(&self, bytes: usize) -> bool {
+        self.remaining() >= bytes
+    }
+
+    /// Returns the highest number of bytes that were allocated at the same time by the current
+    /// thread, since the thread started or since the last call to `reset_peak`.
+    pub fn peak(&self) -> usize {
+        COUNTER.try_with(|c| c.peak.get()).unwrap_or(0)
+    }
+
+    /// Sets the peak of the current thread back to the memory that it has allocated right now.
+    pub fn reset_peak(&self) {
+        let _ = COUNTER.try_with(|c| c.peak.set(c.allocated.get()));
+    }
+
+    /// Returns None if the memory limit of the current thread would be exhausted after
+    /// allocating.
+    ///
+    /// # Safety
+    ///
+    /// The same restrictions as `GlobalAlloc::alloc`.
+    pub unsafe fn try_alloc(&self, layout: Layout) -> Option<*mut u8> {
+        self.alloc_with(layout, |alloc, layout| alloc.alloc(layout))
+    }
+
+    /// Same as `try_alloc`, but the memory is zeroed.
+    ///
+    /// # Safety
+    ///
+    /// The same restrictions as `GlobalAlloc::alloc_zeroed`.
+    pub unsafe fn try_alloc_zeroed(&self, layout: Layout) -> Option<*mut u8> {
+        self.alloc_with(layout, |alloc, layout| alloc.alloc_zeroed(layout))
+    }
+
+    /// Returns None if the memory limit of the current thread would be exhausted after growing
+    /// the allocation, in that case the old allocation is still valid. Otherwise returns the
+    /// result of the inner allocator, which is null if it failed.
+    ///
+    /// # Safety
+    ///
+    /// The same restrictions as `GlobalAlloc::realloc`.
+    pub unsafe fn try_realloc(
+        &self,
+        ptr: *mut u8,
+        layout: Layout,
+        new_size: usize,
+    ) -> Option<*mut u8> {
+        let old_size = layout.size();
+        if new_size > old_size {
+            let delta = new_size - old_size;
+            if !Self::charge(delta) {
+                return None;
+            }
+            let ret = self.alloc.realloc(ptr, layout, new_size);
+            if ret.is_null() {
+                // The old allocation is still valid, so only subtract the difference
+                Self::credit(delta);
+            }
+            Some(ret)
+        } else {
+            let ret = self.alloc.realloc(ptr, layout, new_size);
+            if !ret.is_null() {
+                Self::credit(old_size - new_size);
+            }
+            Some(ret)
+        }
+    }
+
+    unsafe fn alloc_with(
+        &self,
+        layout: Layout,
+        f: impl FnOnce(&A, Layout) -> *mut u8,
+    ) -> Option<*mut u8> {
+        if !Self::charge(layout.size()) {
+            return None;
+        }
+        let ret = f(&self.alloc, layout);
+        if ret.is_null() {
+            // Nothing was actually allocated, so subtract the size
+            Self::credit(layout.size());
+        }
+
+        Some(ret)
+    }
+
+    /// Add `size` to the memory allocated by the current thread. Returns false if that would
+    /// exceed the limit, in that case the counter is not modified.
+    fn charge(size: usize) -> bool {
+        COUNTER
+            .try_with(|c| {
+                let Some(new) = crate::add_within_limit(c.allocated.get(), size, L) else {
+                    return false;
+                };
+                c.allocated.set(new);
+                c.peak.set(c.peak.get().max(new));
+                true
+            })
+            // The thread local is not available, allow the allocation without counting it
+            .unwrap_or(true)
+    }
+
+    /// Subtract `size` from the memory allocated by the current thread.
+    fn credit(size: usize) {
+        let _ = COUNTER.try_with(|c| c.allocated.set(c.allocated.get().saturating_sub(size)));
+    }
+}
+
+unsafe impl<A: GlobalAlloc, const L: usize> GlobalAlloc for ConstThreadLimit<A, L> {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        self.try_alloc(layout).unwrap_or(ptr::null_mut())
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        self.alloc.dealloc(ptr, layout);
+        Self::credit(layout.size());
+    }
+
+    unsafe fn alloc_zeroed(&self, layout: Layout) -> *mut u8 {
+        self.try_alloc_zeroed(layout).unwrap_or(ptr::null_mut())
+    }
+
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        self.try_realloc(ptr, layout, new_size)
+            .unwrap_or(ptr::null_mut())
+    }
+}
@@ -0,0 +1,128 @@
+//! Allocators that limit the number of allocations instead of, or as well as, the bytes.
+use crate::Limit;
+use core::alloc::{GlobalAlloc, Layout};
+use core::ops::Deref;
+
+/// Allocator that limits the number of allocations that are live at the same time, independently
+/// of their size. Each allocation counts as 1 and each deallocation gives it back, and an
+/// allocation fails when there are already `max_allocations` live allocations. Useful in fuzz
+/// targets to catch programs that create millions of tiny allocations.
+///
+/// This is a `Limit` created using `Limit::with_counts` with no limit in bytes, and it derefs to
+/// it, so all the statistics are available. Use `Limit::with_counts` to limit both.
+///
+/// ```
+/// use limit_alloc::CountLimit;
+/// use std::alloc::System;
+///
+/// #[global_allocator]
+/// static A: CountLimit<System> = CountLimit::new(1_000_000, System);
+/// ```
+pub struct CountLimit<A>(Limit<A>);
+
+impl<A: GlobalAlloc> CountLimit<A> {
+    pub const fn new(max_allocations: usize, alloc: A) -> Self {
+        Self(Limit::with_counts(usize::MAX, max_allocations, alloc))
+    }
+
+    /// Returns the number of allocations that can still be made before reaching the limit.
+    pub fn remaining_allocations(&self) -> usize {
+        self.0
+            .max_live_allocations()
+            .saturating_sub(self.0.live_allocations())
+    }
+}
+
+impl<A> Deref for CountLimit<A> {
+    type Target = Limit<A>;
+
+    fn deref(&self) -> &Limit<A> {
+        &self.0
+    }
+}
+
+unsafe impl<A: GlobalAlloc> GlobalAlloc for CountLimit<A> {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        self.0.alloc(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        self.0.dealloc(ptr, layout)
+    }
+
+    unsafe fn alloc_zeroed(&self, layout: Layout) -> *mut u8 {
+        self.0.alloc_zeroed(layout)
+    }
+
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        self.0.realloc(ptr, layout, new_size)
+    }
+}
+
+/// Allocator that limits both the memory and the number of allocations that are live at the same
+/// time. An allocation fails if it would exceed either of them.
+///
+/// The bytes are reserved first and then the allocation count, and if the count is exhausted the
+/// bytes are given back before returning null, so a failed allocation never leaves anything
+/// counted. This is a `Limit` created using `Limit::with_counts`, and it derefs to it.
+///
+/// ```
+/// use limit_alloc::DualLimit;
+/// use std::alloc::{GlobalAlloc, Layout, System};
+///
+/// let limit = DualLimit::new(1_000, 2, System);
+/// let small = Layout::from_size_align(10, 1).unwrap();
+/// let big = Layout::from_size_align(900, 1).unwrap();
+/// unsafe {
+///     // The byte limit is exhausted, but not the count limit
+///     let a = limit.alloc(big);
+///     assert!(limit.alloc(big).is_null());
+///     // The count limit is exhausted, but there are still bytes left
+///     let b = limit.alloc(small);
+///     assert!(limit.alloc(small).is_null());
+///     assert_eq!(limit.allocated(), 910);
+///     limit.dealloc(a, big);
+///     limit.dealloc(b, small);
+/// }
+/// assert_eq!(limit.remaining_allocations(), 2);
+/// ```
+pub struct DualLimit<A>(Limit<A>);
+
+impl<A: GlobalAlloc> DualLimit<A> {
+    pub const fn new(max_bytes: usize, max_allocations: usize, alloc: A) -> Self {
+        Self(Limit::with_counts(max_bytes, max_allocations, alloc))
+    }
+
+    /// Returns the number of allocations that can still be made before reaching the limit.
+    pub fn remaining_allocations(&self) -> usize {
+        self.0
+            .max_live_allocations()
+            .saturating_sub(self.0.live_allocations())
+    }
+}
+
+impl<A> Deref for DualLimit<A> {
+    type Target = Limit<A>;
+
+    fn deref(&self) -> &Limit<A> {
+        &self.0
+    }
+}
+
+unsafe impl<A: GlobalAlloc> GlobalAlloc for DualLimit<A> {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        self.0.alloc(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        self.0.dealloc(ptr, layout)
+    }
+
+    unsafe fn alloc_zeroed(&self, layout: Layout) -> *mut u8 {
+        self.0.alloc_zeroed(layout)
+    }
+
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        self.0.realloc(ptr, layout, new_size)
+    }
+}
@@ -0,0 +1,84 @@
+//! Rules to count the bytes of an allocation, see `Limit::with_policy`.
+use core::alloc::Layout;
+
+/// Number of bytes counted for an allocation. Use it with `Limit::with_policy` or
+/// `Accounting::policy`.
+///
+/// The same number is counted when the allocation is made, deallocated and reallocated, so the
+/// counter always goes back to 0, as long as `charge` always returns the same value for the same
+/// layout. `charge` runs inside the allocator, so it must not allocate.
+///
+/// ```
+/// use limit_alloc::{CountPolicy, ExactSize, Limit, PaddedSize, Quantized, WithOverhead};
+/// use std::alloc::{GlobalAlloc, Layout, System};
+///
+/// fn charged<P: CountPolicy>() -> (usize, usize) {
+///     let limit = Limit::with_policy::<P>(10_000, System);
+///     let small = Layout::from_size_align(1, 8).unwrap();
+///     let big = Layout::from_size_align(100, 4).unwrap();
+///     unsafe {
+///         let a = limit.alloc(small);
+///         let b = limit.alloc(big);
+///         let b = limit.realloc(b, big, 150);
+///         let allocated = limit.allocated();
+///         limit.dealloc(a, small);
+///         limit.dealloc(b, Layout::from_size_align(150, 4).unwrap());
+///         (allocated, limit.allocated())
+///     }
+/// }
+///
+/// assert_eq!(charged::<ExactSize>(), (151, 0));
+/// assert_eq!(charged::<PaddedSize>(), (160, 0));
+/// assert_eq!(charged::<Quantized<16>>(), (176, 0));
+/// assert_eq!(charged::<WithOverhead<16>>(), (183, 0));
+/// ```
+pub trait CountPolicy {
+    /// Returns the number of bytes counted for an allocation with `layout`.
+    fn charge(layout: Layout) -> usize;
+}
+
+/// Count `layout.size()`, same as `Accounting::Size`.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct ExactSize;
+
+impl CountPolicy for ExactSize {
+    fn charge(layout: Layout) -> usize {
+        layout.size()
+    }
+}
+
+/// Count the size rounded up to a multiple of the alignment, same as `Accounting::Padded`.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct PaddedSize;
+
+impl CountPolicy for PaddedSize {
+    fn charge(layout: Layout) -> usize {
+        layout.pad_to_align().size()
+    }
+}
+
+/// Count the size rounded up to a multiple of `Q` bytes, for example the size classes of the
+/// inner allocator. `Quantized<0>` is the same as `ExactSize`.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Quantized<const Q: usize>;
+
+impl<const Q: usize> CountPolicy for Quantized<Q> {
+    fn charge(layout: Layout) -> usize {
+        let size = layout.size();
+        match size % Q.max(1) {
+            0 => size,
+            rest => size.saturating_add(Q - rest),
+        }
+    }
+}
+
+/// Count the size plus `N` bytes, for example the header that the inner allocator stores before
+/// each allocation.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct WithOverhead<const N: usize>;
+
+impl<const N: usize> CountPolicy for WithOverhead<N> {
+    fn charge(layout: Layout) -> usize {
+        layout.size().saturating_add(N)
+    }
+}
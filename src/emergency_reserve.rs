@@ -0,0 +1,36 @@
+//! Thread local state of `Limit::use_reserve`.
+use core::ptr;
+use std::cell::Cell;
+
+alloc_local! {
+    /// Address of the `Limit` whose reserve can be used by the current thread, or null.
+    static RESERVE: Cell<*const ()> = Cell::new(ptr::null());
+}
+
+/// Returns true if the current thread can use the reserve of the limit at `limit`.
+pub(crate) fn in_use(limit: *const ()) -> bool {
+    RESERVE
+        .try_with(|reserve| reserve.get() == limit)
+        .unwrap_or(false)
+}
+
+/// Allows the current thread to use the reserve of a limit until it is dropped, and then restores
+/// the previous limit, so `use_reserve` can be nested.
+pub(crate) struct ReserveGuard {
+    previous: *const (),
+}
+
+impl ReserveGuard {
+    pub(crate) fn new(limit: *const ()) -> Self {
+        let previous = RESERVE
+            .try_with(|reserve| reserve.replace(limit))
+            .unwrap_or(ptr::null());
+        Self { previous }
+    }
+}
+
+impl Drop for ReserveGuard {
+    fn drop(&mut self) {
+        let _ = RESERVE.try_with(|reserve| reserve.set(self.previous));
+    }
+}
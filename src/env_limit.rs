@@ -0,0 +1,172 @@
+//! Allocator that reads its limit from an environment variable on the first allocation.
+use crate::{parse_size, Limit};
+use core::alloc::{GlobalAlloc, Layout};
+use core::ops::Deref;
+use core::sync::atomic::AtomicU8;
+use core::sync::atomic::Ordering::{Acquire, Release};
+
+/// Values of `EnvLimit::state`.
+const UNINIT: u8 = 0;
+const INITIALIZING: u8 = 1;
+const READY: u8 = 2;
+
+/// `Limit` that reads its limit from an environment variable, for example to set the limit of a
+/// test run using `LIMIT_ALLOC_BYTES=256M cargo test`. The value is parsed using `parse_size`.
+///
+/// A global allocator must be created in a `const` context, where the environment is not
+/// available, so the variable is read on the first allocation, or the first call to `remaining`
+/// or `limit`. Only one thread reads it, and allocations made by other threads while it is being
+/// read, or by the reading thread itself, use the default limit. The default is also used if the
+/// variable is not set or cannot be parsed. On Unix the variable is read using `getenv`, which
+/// does not allocate, on other platforms using `std::env::var_os`. Changing the variable after
+/// the first allocation has no effect.
+///
+/// On Unix `getenv` does not take the lock that `std::env::set_var` and `std::env::remove_var`
+/// use, so the environment must not be modified by another thread while the variable is read,
+/// which is during the first allocation. Modify the environment before starting any threads, as
+/// required by `set_var` anyway on most platforms, or call `limit` at the start of `main` to read
+/// the variable early.
+///
+/// It derefs to the inner `Limit`, so the statistics are available. Note that the methods of the
+/// `Limit` do not read the variable, only the methods of `EnvLimit` and the `GlobalAlloc`
+/// implementation do.
+///
+/// ```
+/// use limit_alloc::EnvLimit;
+/// use std::alloc::{Layout, System};
+///
+/// #[global_allocator]
+/// static A: EnvLimit<System> = EnvLimit::new("LIMIT_ALLOC_BYTES", usize::MAX, System);
+///
+/// fn main() {
+///     std::env::set_var("MY_TEST_LIMIT", "1K");
+///     let limit = EnvLimit::new("MY_TEST_LIMIT", 100, System);
+///     assert_eq!(limit.remaining(), 1024);
+///     let layout = Layout::from_size_align(2_000, 1).unwrap();
+///     assert!(unsafe { limit.try_alloc(layout) }.is_none());
+/// }
+/// ```
+pub struct EnvLimit<A> {
+    var: &'static str,
+    /// Whether the variable was read, one of `UNINIT`, `INITIALIZING` or `READY`.
+    state: AtomicU8,
+    limit: Limit<A>,
+}
+
+impl<A: GlobalAlloc> EnvLimit<A> {
+    /// Reads the limit from the environment variable `var`, or uses `default` if it is not set.
+    pub const fn new(var: &'static str, default: usize, alloc: A) -> Self {
+        Self {
+            var,
+            state: AtomicU8::new(UNINIT),
+            limit: Limit::new(default, alloc),
+        }
+    }
+
+    /// Returns the name of the environment variable.
+    pub fn var(&self) -> &'static str {
+        self.var
+    }
+
+    /// Reads the variable if this was not done yet.
+    fn init(&self) {
+        if self.state.load(Acquire) == READY {
+            return;
+        }
+        // Only the thread that moves the state out of UNINIT reads the variable, the others keep
+        // using the default limit instead of waiting, because the reading thread may allocate
+        if self
+            .state
+            .compare_exchange(UNINIT, INITIALIZING, Acquire, Acquire)
+            .is_ok()
+        {
+            if let Some(limit) = read_var(self.var) {
+                self.limit.set_limit(limit);
+            }
+            self.state.store(READY, Release);
+        }
+    }
+
+    /// Returns the remaining memory in bytes, see `Limit::remaining`.
+    pub fn remaining(&self) -> usize {
+        self.init();
+        self.limit.remaining()
+    }
+
+    /// Returns the memory limit in bytes.
+    pub fn limit(&self) -> usize {
+        self.init();
+        self.limit.limit()
+    }
+
+    /// Returns None if the memory limit would be exhausted after allocating, see
+    /// `Limit::try_alloc`.
+    ///
+    /// # Safety
+    ///
+    /// The same restrictions as `GlobalAlloc::alloc`.
+    pub unsafe fn try_alloc(&self, layout: Layout) -> Option<*mut u8> {
+        self.init();
+        self.limit.try_alloc(layout)
+    }
+}
+
+/// Reads and parses the variable `var`, returns None if it is not set or cannot be parsed.
+#[cfg(unix)]
+fn read_var(var: &str) -> Option<usize> {
+    use core::ffi::{c_char, CStr};
+
+    extern "C" {
+        fn getenv(name: *const c_char) -> *const c_char;
+    }
+
+    // getenv needs a nul terminated string, and a CString would allocate
+    let mut name = [0u8; 256];
+    if var.len() >= name.len() || var.contains('\0') {
+        return None;
+    }
+    name[..var.len()].copy_from_slice(var.as_bytes());
+    // Safety: name is nul terminated, and the result is only used before returning. The
+    // environment is not modified concurrently, see the docs of `EnvLimit`
+    let value = unsafe { getenv(name.as_ptr().cast()) };
+    if value.is_null() {
+        return None;
+    }
+    let value = unsafe { CStr::from_ptr(value) };
+    parse_size(value.to_str().ok()?).ok()
+}
+
+/// Reads and parses the variable `var`, returns None if it is not set or cannot be parsed.
+#[cfg(not(unix))]
+fn read_var(var: &str) -> Option<usize> {
+    parse_size(std::env::var_os(var)?.to_str()?).ok()
+}
+
+impl<A> Deref for EnvLimit<A> {
+    type Target = Limit<A>;
+
+    fn deref(&self) -> &Limit<A> {
+        &self.limit
+    }
+}
+
+unsafe impl<A: GlobalAlloc> GlobalAlloc for EnvLimit<A> {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        self.init();
+        self.limit.alloc(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        self.limit.dealloc(ptr, layout)
+    }
+
+    unsafe fn alloc_zeroed(&self, layout: Layout) -> *mut u8 {
+        self.init();
+        self.limit.alloc_zeroed(layout)
+    }
+
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        self.init();
+        self.limit.realloc(ptr, layout, new_size)
+    }
+}
@@ -0,0 +1,49 @@
+//! Reason why an allocation failed, see `Limit::try_alloc2`.
+use core::fmt;
+
+/// Reason why an allocation through a `Limit` failed.
+///
+/// New variants may be added when the limit gains new ways to reject an allocation, so matches
+/// on this type need a wildcard arm.
+#[non_exhaustive]
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum AllocError {
+    /// The allocation does not fit in the remaining memory of the limit. `requested` is the
+    /// number of bytes counted for the allocation, which may include padding depending on the
+    /// `Accounting`.
+    LimitExceeded { requested: usize, remaining: usize },
+    /// The allocation is bigger than the maximum size of a single allocation, see
+    /// `Limit::set_max_single_alloc`.
+    TooLarge { requested: usize, max: usize },
+    /// The maximum number of live allocations was reached, see `Limit::with_counts`.
+    TooManyAllocations { max: usize },
+    /// The limit allowed the allocation but the inner allocator returned null.
+    InnerFailed,
+}
+
+impl fmt::Display for AllocError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            AllocError::LimitExceeded {
+                requested,
+                remaining,
+            } => write!(
+                f,
+                "memory limit exceeded: requested {} bytes but only {} bytes remaining",
+                requested, remaining
+            ),
+            AllocError::TooLarge { requested, max } => write!(
+                f,
+                "allocation of {} bytes is bigger than the maximum of {} bytes",
+                requested, max
+            ),
+            AllocError::TooManyAllocations { max } => {
+                write!(f, "too many live allocations, the maximum is {}", max)
+            }
+            AllocError::InnerFailed => f.write_str("the inner allocator failed"),
+        }
+    }
+}
+
+#[cfg(feature = "std")]
+impl std::error::Error for AllocError {}
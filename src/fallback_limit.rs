@@ -0,0 +1,218 @@
+//! Allocator that uses a second allocator when the limit is exhausted.
+use crate::Limit;
+use core::alloc::{GlobalAlloc, Layout};
+use core::ops::Deref;
+use core::ptr;
+use core::sync::atomic::AtomicUsize;
+use core::sync::atomic::Ordering::Relaxed;
+
+/// Values of the header byte, which stores the allocator that owns each block.
+const PRIMARY: u8 = 0;
+const FALLBACK: u8 = 1;
+
+/// Allocator that allocates from `A` while the limit allows it, and from the fallback allocator
+/// `B` when an allocation is rejected by the limit, so the program keeps running instead of
+/// failing. Memory allocated using the fallback is not counted by the limit. For example `B` can
+/// be a pre-allocated emergency heap, or a slower allocator.
+///
+/// To know which allocator owns a pointer when it is deallocated, every block starts with a header
+/// that stores the owner in the byte before the returned pointer. The header takes
+/// `layout.align()` bytes to keep the alignment of the allocation, so each allocation uses that
+/// many extra bytes, and these are also counted by the limit. Allocations rejected by the limit
+/// still count as rejected in the statistics of the `Limit`, even though they succeed. An
+/// allocation that moves to the fallback when it grows stays there, even after memory is freed.
+///
+/// ```
+/// use limit_alloc::FallbackLimit;
+/// use std::alloc::{GlobalAlloc, Layout, System};
+///
+/// let limit = FallbackLimit::new(1_000, System, System);
+/// let layout = Layout::from_size_align(800, 1).unwrap();
+/// unsafe {
+///     let a = limit.alloc(layout);
+///     // 801 bytes are counted because of the header
+///     assert_eq!(limit.allocated(), 801);
+///     // The limit is exhausted, so this uses the fallback
+///     let b = limit.try_alloc(layout).unwrap();
+///     assert_eq!(limit.allocated(), 801);
+///     assert_eq!(limit.fallback_allocations(), 1);
+///     limit.dealloc(b, layout);
+///     limit.dealloc(a, layout);
+/// }
+/// assert_eq!(limit.allocated(), 0);
+/// ```
+pub struct FallbackLimit<A, B> {
+    limit: Limit<A>,
+    fallback: B,
+    /// Number of allocations made using the fallback allocator.
+    fallback_allocations: AtomicUsize,
+}
+
+impl<A: GlobalAlloc, B: GlobalAlloc> FallbackLimit<A, B> {
+    pub const fn new(limit: usize, primary: A, fallback: B) -> Self {
+        Self {
+            limit: Limit::new(limit, primary),
+            fallback,
+            fallback_allocations: AtomicUsize::new(0),
+        }
+    }
+
+    /// Returns the fallback allocator.
+    pub fn fallback(&self) -> &B {
+        &self.fallback
+    }
+
+    /// Returns the number of allocations that were made using the fallback allocator, including
+    /// allocations that were moved there by `realloc`.
+    pub fn fallback_allocations(&self) -> usize {
+        self.fallback_allocations.load(Relaxed)
+    }
+
+    /// Allocates from the primary allocator if the limit allows it, otherwise from the fallback
+    /// allocator. Returns None if the allocation failed in both, or if the primary allocator
+    /// failed for another reason than the limit.
+    ///
+    /// Use this instead of `Limit::try_alloc` through `Deref`: that one does not write the
+    /// header, so the pointer could not be passed to `dealloc`.
+    ///
+    /// # Safety
+    ///
+    /// The same restrictions as `GlobalAlloc::alloc`.
+    pub unsafe fn try_alloc(&self, layout: Layout) -> Option<*mut u8> {
+        non_null(self.alloc_with(layout, false))
+    }
+
+    /// Same as `try_alloc`, but the memory is zeroed.
+    ///
+    /// # Safety
+    ///
+    /// The same restrictions as `GlobalAlloc::alloc_zeroed`.
+    pub unsafe fn try_alloc_zeroed(&self, layout: Layout) -> Option<*mut u8> {
+        non_null(self.alloc_with(layout, true))
+    }
+
+    /// Same as `GlobalAlloc::realloc`, but returns None instead of a null pointer. If the limit
+    /// does not allow the allocation to grow, it is moved to the fallback allocator.
+    ///
+    /// # Safety
+    ///
+    /// The same restrictions as `GlobalAlloc::realloc`.
+    pub unsafe fn try_realloc(
+        &self,
+        ptr: *mut u8,
+        layout: Layout,
+        new_size: usize,
+    ) -> Option<*mut u8> {
+        non_null(self.realloc(ptr, layout, new_size))
+    }
+
+    unsafe fn alloc_with(&self, layout: Layout, zeroed: bool) -> *mut u8 {
+        let Some((block, offset)) = with_header(layout) else {
+            return ptr::null_mut();
+        };
+        let primary = if zeroed {
+            self.limit.try_alloc_zeroed(block)
+        } else {
+            self.limit.try_alloc(block)
+        };
+        let (base, owner) = match primary {
+            Some(base) => (base, PRIMARY),
+            None => {
+                let base = if zeroed {
+                    self.fallback.alloc_zeroed(block)
+                } else {
+                    self.fallback.alloc(block)
+                };
+                (base, FALLBACK)
+            }
+        };
+        if base.is_null() {
+            return base;
+        }
+        if owner == FALLBACK {
+            self.fallback_allocations.fetch_add(1, Relaxed);
+        }
+        let ptr = base.add(offset);
+        ptr.sub(1).write(owner);
+        ptr
+    }
+}
+
+fn non_null(ptr: *mut u8) -> Option<*mut u8> {
+    if ptr.is_null() {
+        None
+    } else {
+        Some(ptr)
+    }
+}
+
+/// Returns the layout of the block that holds an allocation of `layout` and its header, and the
+/// offset of the allocation inside the block.
+fn with_header(layout: Layout) -> Option<(Layout, usize)> {
+    // The header needs at least 1 byte, and the alignment is never 0
+    let offset = layout.align();
+    let size = layout.size().checked_add(offset)?;
+    let block = Layout::from_size_align(size, layout.align()).ok()?;
+    Some((block, offset))
+}
+
+impl<A, B> Deref for FallbackLimit<A, B> {
+    type Target = Limit<A>;
+
+    fn deref(&self) -> &Limit<A> {
+        &self.limit
+    }
+}
+
+unsafe impl<A: GlobalAlloc, B: GlobalAlloc> GlobalAlloc for FallbackLimit<A, B> {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        self.alloc_with(layout, false)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        // Safety: the same layout was used to allocate, so it has a valid header layout
+        let (block, offset) = with_header(layout).unwrap_unchecked();
+        let base = ptr.sub(offset);
+        if ptr.sub(1).read() == FALLBACK {
+            self.fallback.dealloc(base, block);
+        } else {
+            self.limit.dealloc(base, block);
+        }
+    }
+
+    unsafe fn alloc_zeroed(&self, layout: Layout) -> *mut u8 {
+        self.alloc_with(layout, true)
+    }
+
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        let (block, offset) = with_header(layout).unwrap_unchecked();
+        let Some((new_block, _)) =
+            with_header(Layout::from_size_align_unchecked(new_size, layout.align()))
+        else {
+            return ptr::null_mut();
+        };
+        let base = ptr.sub(offset);
+        if ptr.sub(1).read() == FALLBACK {
+            let new = self.fallback.realloc(base, block, new_block.size());
+            // realloc keeps the header
+            return if new.is_null() { new } else { new.add(offset) };
+        }
+        match self.limit.try_realloc(base, block, new_block.size()) {
+            Some(new) if new.is_null() => new,
+            Some(new) => new.add(offset),
+            None => {
+                // The limit is exhausted, move the allocation to the fallback
+                let new = self.fallback.alloc(new_block);
+                if new.is_null() {
+                    return new;
+                }
+                self.fallback_allocations.fetch_add(1, Relaxed);
+                let new_ptr = new.add(offset);
+                new_ptr.sub(1).write(FALLBACK);
+                ptr::copy_nonoverlapping(ptr, new_ptr, layout.size().min(new_size));
+                self.limit.dealloc(base, block);
+                new_ptr
+            }
+        }
+    }
+}
@@ -0,0 +1,31 @@
+//! Thread local state of `Limit::set_free_notify`. The callback may deallocate, and when the
+//! `Limit` is the global allocator that would call it again, so a thread local flag is set while
+//! it runs and deallocations made by the callback do not notify.
+use std::cell::Cell;
+
+alloc_local! {
+    static NOTIFYING: Cell<bool> = Cell::new(false);
+}
+
+/// Clears the flag when dropped, also if the callback panics.
+struct NotifyingGuard;
+
+impl Drop for NotifyingGuard {
+    fn drop(&mut self) {
+        let _ = NOTIFYING.try_with(|notifying| notifying.set(false));
+    }
+}
+
+/// Calls `f` with the flag set, unless the current thread is already running a callback.
+pub(crate) fn notify(f: impl FnOnce()) {
+    // Also skip it if the thread local is not available, because then recursion cannot be
+    // detected
+    if !matches!(
+        NOTIFYING.try_with(|notifying| notifying.replace(true)),
+        Ok(false)
+    ) {
+        return;
+    }
+    let _guard = NotifyingGuard;
+    f();
+}
@@ -0,0 +1,409 @@
+//! Several allocators that share one memory limit, requires the `alloc` feature.
+use alloc::sync::Arc;
+use core::alloc::{GlobalAlloc, Layout};
+use core::fmt;
+use core::ptr;
+use core::sync::atomic::AtomicUsize;
+use core::sync::atomic::Ordering::SeqCst;
+
+struct Budget {
+    limit: AtomicUsize,
+    /// Memory allocated by all the `GroupLimit` that use this budget, in bytes.
+    allocated: AtomicUsize,
+    /// Highest value of `allocated`.
+    peak: AtomicUsize,
+}
+
+/// Memory limit shared by a group of `GroupLimit`. Clones of a `SharedBudget` refer to the same
+/// counter, so it can be kept to read the remaining memory of the whole group.
+#[derive(Clone)]
+pub struct SharedBudget(Arc<Budget>);
+
+impl SharedBudget {
+    pub fn new(limit: usize) -> Self {
+        Self(Arc::new(Budget {
+            limit: AtomicUsize::new(limit),
+            allocated: AtomicUsize::new(0),
+            peak: AtomicUsize::new(0),
+        }))
+    }
+
+    /// Returns the memory limit of the group in bytes.
+    pub fn limit(&self) -> usize {
+        self.0.limit.load(SeqCst)
+    }
+
+    /// Changes the memory limit of the group, see `Limit::set_limit`.
+    pub fn set_limit(&self, new_limit: usize) {
+        self.0.limit.store(new_limit, SeqCst);
+    }
+
+    /// Returns the memory allocated by all the allocators of the group, in bytes.
+    pub fn allocated(&self) -> usize {
+        self.0.allocated.load(SeqCst)
+    }
+
+    /// Returns the remaining memory of the group in bytes.
+    pub fn remaining(&self) -> usize {
+        // Saturate in case the limit was set below the allocated memory
+        self.limit().saturating_sub(self.allocated())
+    }
+
+    /// Returns true if `remaining() >= bytes`, see `Limit::has_headroom`.
+    pub fn has_headroom(&self, bytes: usize) -> bool {
+        self.remaining() >= bytes
+    }
+
+    /// Returns the highest number of bytes that were allocated at the same time by the group.
+    pub fn peak(&self) -> usize {
+        self.0.peak.load(SeqCst)
+    }
+
+    /// Add `size` to the allocated memory. Returns false if that would exceed the limit, in that
+    /// case the counter is not modified.
+    fn charge(&self, size: usize) -> bool {
+        let limit = self.limit();
+        match self.0.allocated.fetch_update(SeqCst, SeqCst, |old| {
+            crate::add_within_limit(old, size, limit)
+        }) {
+            Ok(old) => {
+                self.0.peak.fetch_max(old + size, SeqCst);
+                true
+            }
+            Err(_e) => false,
+        }
+    }
+
+    /// Subtract `size` from the allocated memory.
+    fn credit(&self, size: usize) {
+        // Saturate in case a dealloc adds back more bytes than were allocated
+        let _ = self
+            .0
+            .allocated
+            .fetch_update(SeqCst, SeqCst, |old| Some(old.saturating_sub(size)));
+    }
+}
+
+impl fmt::Debug for SharedBudget {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("SharedBudget")
+            .field("limit", &self.limit())
+            .field("allocated", &self.allocated())
+            .finish()
+    }
+}
+
+/// Allocator that charges a `SharedBudget`, so several allocators of different types can share
+/// one memory limit. Unlike `ArcLimit`, each `GroupLimit` has its own inner allocator, and
+/// unlike `Limit::child`, none of them owns the limit. Each `GroupLimit` also counts its own
+/// allocated memory, which is included in the budget.
+///
+/// The budget is reference counted, so a `GroupLimit` cannot be created in a `const` context and
+/// cannot be the global allocator, use `Limit::child` for that.
+///
+/// ```
+/// use limit_alloc::{GroupLimit, SharedBudget};
+/// use std::alloc::{GlobalAlloc, Layout, System};
+///
+/// /// Second allocator type, for example an arena.
+/// struct Arena;
+///
+/// unsafe impl GlobalAlloc for Arena {
+///     unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+///         System.alloc(layout)
+///     }
+///
+///     unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+///         System.dealloc(ptr, layout)
+///     }
+/// }
+///
+/// let budget = SharedBudget::new(1_000);
+/// let parser = GroupLimit::new(budget.clone(), System);
+/// let cache = GroupLimit::new(budget.clone(), Arena);
+/// let layout = Layout::from_size_align(800, 1).unwrap();
+/// unsafe {
+///     let ptr = parser.alloc(layout);
+///     assert_eq!(budget.remaining(), 200);
+///     // The parser used most of the budget, so the cache cannot allocate
+///     assert!(cache.try_alloc(layout).is_none());
+///     assert_eq!(cache.allocated(), 0);
+///     parser.dealloc(ptr, layout);
+///     let ptr = cache.try_alloc(layout).unwrap();
+///     assert_eq!(cache.allocated(), 800);
+///     cache.dealloc(ptr, layout);
+/// }
+/// assert_eq!(budget.remaining(), 1_000);
+/// ```
+pub struct GroupLimit<A> {
+    budget: SharedBudget,
+    alloc: A,
+    /// Memory allocated by this allocator, in bytes.
+    allocated: AtomicUsize,
+}
+
+impl<A: GlobalAlloc> GroupLimit<A> {
+    pub fn new(budget: SharedBudget, alloc: A) -> Self {
+        Self {
+            budget,
+            alloc,
+            allocated: AtomicUsize::new(0),
+        }
+    }
+
+    /// Returns the budget shared by the group.
+    pub fn budget(&self) -> &SharedBudget {
+        &self.budget
+    }
+
+    /// Returns a reference to the inner allocator. Memory allocated using the inner allocator
+    /// directly is not counted, so it must also be deallocated using the inner allocator.
+    pub fn inner(&self) -> &A {
+        &self.alloc
+    }
+
+    /// Returns the memory allocated by this allocator, in bytes. Use `budget().allocated()` for
+    /// the memory of the whole group.
+    pub fn allocated(&self) -> usize {
+        self.allocated.load(SeqCst)
+    }
+
+    /// Returns the remaining memory of the group in bytes, same as `budget().remaining()`.
+    pub fn remaining(&self) -> usize {
+        self.budget.remaining()
+    }
+
+    /// Returns None if the memory limit of the group would be exhausted after allocating.
+    /// Otherwise returns the result of the inner allocator, which is null if it failed.
+    ///
+    /// # Safety
+    ///
+    /// The same restrictions as `GlobalAlloc::alloc`.
+    pub unsafe fn try_alloc(&self, layout: Layout) -> Option<*mut u8> {
+        self.alloc_with(layout, |alloc, layout| alloc.alloc(layout))
+    }
+
+    /// Same as `try_alloc`, but the memory is zeroed.
+    ///
+    /// # Safety
+    ///
+    /// The same restrictions as `GlobalAlloc::alloc_zeroed`.
+    pub unsafe fn try_alloc_zeroed(&self, layout: Layout) -> Option<*mut u8> {
+        self.alloc_with(layout, |alloc, layout| alloc.alloc_zeroed(layout))
+    }
+
+    /// Returns None if the memory limit of the group would be exhausted after growing the
+    /// allocation, in that case the old allocation is still valid. Otherwise returns the result
+    /// of the inner allocator, which is null if it failed.
+    ///
+    /// # Safety
+    ///
+    /// The same restrictions as `GlobalAlloc::realloc`.
+    pub unsafe fn try_realloc(
+        &self,
+        ptr: *mut u8,
+        layout: Layout,
+        new_size: usize,
+    ) -> Option<*mut u8> {
+        let old_size = layout.size();
+        if new_size > old_size {
+            let delta = new_size - old_size;
+            if !self.charge(delta) {
+                return None;
+            }
+            let ret = self.alloc.realloc(ptr, layout, new_size);
+            if ret.is_null() {
+                // The old allocation is still valid, so only subtract the difference
+                self.credit(delta);
+            }
+            Some(ret)
+        } else {
+            let ret = self.alloc.realloc(ptr, layout, new_size);
+            if !ret.is_null() {
+                self.credit(old_size - new_size);
+            }
+            Some(ret)
+        }
+    }
+
+    unsafe fn alloc_with(
+        &self,
+        layout: Layout,
+        f: impl FnOnce(&A, Layout) -> *mut u8,
+    ) -> Option<*mut u8> {
+        if !self.charge(layout.size()) {
+            return None;
+        }
+        let ret = f(&self.alloc, layout);
+        if ret.is_null() {
+            // Nothing was actually allocated, so subtract the size
+            self.credit(layout.size());
+        }
+
+        Some(ret)
+    }
+
+    fn charge(&self, size: usize) -> bool {
+        if !self.budget.charge(size) {
+            return false;
+        }
+        self.allocated.fetch_add(size, SeqCst);
+        true
+    }
+
+    fn credit(&self, size: usize) {
+        self.budget.credit(size);
+        let _ = self
+            .allocated
+            .fetch_update(SeqCst, SeqCst, |old| Some(old.saturating_sub(size)));
+    }
+}
+
+impl<A> fmt::Debug for GroupLimit<A> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("GroupLimit")
+            .field("budget", &self.budget)
+            .field("allocated", &self.allocated.load(SeqCst))
+            .finish()
+    }
+}
+
+unsafe impl<A: GlobalAlloc> GlobalAlloc for GroupLimit<A> {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        self.try_alloc(layout).unwrap_or(ptr::null_mut())
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        self.alloc.dealloc(ptr, layout);
+        self.credit(layout.size());
+    }
+
+    unsafe fn alloc_zeroed(&self, layout: Layout) -> *mut u8 {
+        self.try_alloc_zeroed(layout).unwrap_or(ptr::null_mut())
+    }
+
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        self.try_realloc(ptr, layout, new_size)
+            .unwrap_or(ptr::null_mut())
+    }
+}
+
+#[cfg(all(test, feature = "std"))]
+mod tests {
+    use super::*;
+    use crate::test_util::MockAlloc;
+    use std::alloc::System;
+    use std::thread;
+
+    /// Second inner allocator of a different type, counts the bytes it allocates.
+    struct Arena {
+        used: AtomicUsize,
+    }
+
+    unsafe impl GlobalAlloc for Arena {
+        unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+            self.used.fetch_add(layout.size(), SeqCst);
+            System.alloc(layout)
+        }
+
+        unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+            self.used.fetch_sub(layout.size(), SeqCst);
+            System.dealloc(ptr, layout)
+        }
+    }
+
+    const LAYOUT: Layout = unsafe { Layout::from_size_align_unchecked(400, 8) };
+
+    #[test]
+    fn exhausting_the_budget_through_one_blocks_the_other() {
+        let budget = SharedBudget::new(1_000);
+        let mock = GroupLimit::new(budget.clone(), MockAlloc::new());
+        let arena = GroupLimit::new(
+            budget.clone(),
+            Arena {
+                used: AtomicUsize::new(0),
+            },
+        );
+        let a = unsafe { mock.alloc(LAYOUT) };
+        let b = unsafe { mock.alloc(LAYOUT) };
+        assert_eq!(budget.remaining(), 200);
+        assert_eq!(mock.allocated(), 800);
+        // The arena is not called at all
+        assert!(unsafe { arena.try_alloc(LAYOUT) }.is_none());
+        assert!(unsafe { arena.alloc_zeroed(LAYOUT) }.is_null());
+        assert_eq!(arena.inner().used.load(SeqCst), 0);
+        assert_eq!(arena.allocated(), 0);
+        // And the other way around
+        unsafe { mock.dealloc(a, LAYOUT) };
+        let c = unsafe { arena.alloc(LAYOUT) };
+        assert!(!c.is_null());
+        assert_eq!(arena.inner().used.load(SeqCst), 400);
+        let allocs = mock.inner().allocs.load(SeqCst);
+        assert!(unsafe { mock.try_alloc(LAYOUT) }.is_none());
+        assert!(unsafe { mock.try_realloc(b, LAYOUT, 800) }.is_none());
+        assert_eq!(mock.inner().allocs.load(SeqCst), allocs);
+        assert_eq!(mock.inner().reallocs.load(SeqCst), 0);
+        assert_eq!(budget.allocated(), 800);
+        assert_eq!(budget.peak(), 800);
+        unsafe { mock.dealloc(b, LAYOUT) };
+        unsafe { arena.dealloc(c, LAYOUT) };
+        assert_eq!(budget.remaining(), 1_000);
+        assert_eq!((mock.allocated(), arena.allocated()), (0, 0));
+    }
+
+    #[test]
+    fn failed_inner_allocations_are_not_charged() {
+        let budget = SharedBudget::new(1_000);
+        let failing = GroupLimit::new(budget.clone(), MockAlloc::failing());
+        assert!(unsafe { failing.try_alloc(LAYOUT) }.unwrap().is_null());
+        assert!(unsafe { failing.try_alloc_zeroed(LAYOUT) }
+            .unwrap()
+            .is_null());
+        assert_eq!((budget.allocated(), failing.allocated()), (0, 0));
+        let mock = GroupLimit::new(budget.clone(), MockAlloc::new());
+        let ptr = unsafe { mock.alloc(LAYOUT) };
+        mock.inner().set_fail(true);
+        // The old allocation is still valid and still charged
+        assert!(unsafe { mock.try_realloc(ptr, LAYOUT, 600) }
+            .unwrap()
+            .is_null());
+        assert_eq!((budget.allocated(), mock.allocated()), (400, 400));
+        mock.inner().set_fail(false);
+        let ptr = unsafe { mock.realloc(ptr, LAYOUT, 600) };
+        assert_eq!((budget.allocated(), mock.allocated()), (600, 600));
+        let layout = Layout::from_size_align(600, 8).unwrap();
+        let ptr = unsafe { mock.realloc(ptr, layout, 100) };
+        assert_eq!((budget.allocated(), mock.allocated()), (100, 100));
+        unsafe { mock.dealloc(ptr, Layout::from_size_align(100, 8).unwrap()) };
+        assert_eq!(budget.allocated(), 0);
+    }
+
+    #[test]
+    fn concurrent_allocations_never_exceed_the_budget() {
+        let budget = SharedBudget::new(2_000);
+        let mock = GroupLimit::new(budget.clone(), MockAlloc::new());
+        let arena = GroupLimit::new(
+            budget.clone(),
+            Arena {
+                used: AtomicUsize::new(0),
+            },
+        );
+        fn churn(alloc: &(dyn GlobalAlloc + Sync)) {
+            for _ in 0..1_000 {
+                let ptr = unsafe { alloc.alloc(LAYOUT) };
+                if !ptr.is_null() {
+                    unsafe { alloc.dealloc(ptr, LAYOUT) };
+                }
+            }
+        }
+        thread::scope(|s| {
+            for _ in 0..4 {
+                s.spawn(|| churn(&mock));
+                s.spawn(|| churn(&arena));
+            }
+        });
+        assert!(budget.peak() <= 2_000, "{}", budget.peak());
+        assert_eq!(budget.allocated(), 0);
+        assert_eq!((mock.allocated(), arena.allocated()), (0, 0));
+    }
+}
@@ -0,0 +1,183 @@
+//! Number of allocations in each size class, enabled with the `histogram` feature.
+use core::fmt;
+use core::sync::atomic::AtomicUsize;
+use core::sync::atomic::Ordering::Relaxed;
+
+/// Number of size classes: `<= 16`, `<= 32`, ..., `<= 4 GiB` and bigger.
+pub const SIZE_CLASSES: usize = 30;
+
+/// log2 of the upper bound of the first size class.
+const FIRST_CLASS_BITS: u32 = 4;
+
+/// Returns the index of the size class of an allocation of `size` bytes.
+fn size_class(size: usize) -> usize {
+    let bits = size
+        .checked_next_power_of_two()
+        .map_or(usize::BITS, |p| p.trailing_zeros());
+    (bits.saturating_sub(FIRST_CLASS_BITS) as usize).min(SIZE_CLASSES - 1)
+}
+
+/// Returns the biggest size in the size class `class`, or `usize::MAX` for the last one.
+fn class_max_size(class: usize) -> usize {
+    if class == SIZE_CLASSES - 1 {
+        return usize::MAX;
+    }
+    1usize
+        .checked_shl(class as u32 + FIRST_CLASS_BITS)
+        .unwrap_or(usize::MAX)
+}
+
+pub(crate) struct Histogram {
+    total: [AtomicUsize; SIZE_CLASSES],
+    live: [AtomicUsize; SIZE_CLASSES],
+}
+
+impl Histogram {
+    pub(crate) const fn new() -> Self {
+        Self {
+            total: [const { AtomicUsize::new(0) }; SIZE_CLASSES],
+            live: [const { AtomicUsize::new(0) }; SIZE_CLASSES],
+        }
+    }
+
+    /// Called after a block of `size` bytes is allocated.
+    pub(crate) fn allocated(&self, size: usize) {
+        let class = size_class(size);
+        self.total[class].fetch_add(1, Relaxed);
+        self.live[class].fetch_add(1, Relaxed);
+    }
+
+    /// Called after a block of `size` bytes is deallocated.
+    pub(crate) fn deallocated(&self, size: usize) {
+        // Saturate in case the block was not allocated by this allocator
+        let _ =
+            self.live[size_class(size)].fetch_update(Relaxed, Relaxed, |old| old.checked_sub(1));
+    }
+
+    /// Called after a block is resized from `old_size` to `new_size` bytes.
+    pub(crate) fn reallocated(&self, old_size: usize, new_size: usize) {
+        if size_class(old_size) != size_class(new_size) {
+            self.deallocated(old_size);
+            self.allocated(new_size);
+        }
+    }
+
+    pub(crate) fn snapshot(&self) -> SizeHistogram {
+        let mut histogram = SizeHistogram {
+            total: [0; SIZE_CLASSES],
+            live: [0; SIZE_CLASSES],
+        };
+        for class in 0..SIZE_CLASSES {
+            histogram.total[class] = self.total[class].load(Relaxed);
+            histogram.live[class] = self.live[class].load(Relaxed);
+        }
+        histogram
+    }
+}
+
+/// Snapshot of the number of allocations in each size class, returned by `Limit::size_histogram`.
+/// The size classes are powers of two: `<= 16` bytes, `<= 32` bytes, and so on up to `<= 4 GiB`,
+/// and a last class for bigger allocations.
+///
+/// Iterating returns one `SizeClass` for each class, including the empty ones. The `Display`
+/// implementation prints one line for each class that is not empty.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct SizeHistogram {
+    total: [usize; SIZE_CLASSES],
+    live: [usize; SIZE_CLASSES],
+}
+
+/// One size class of a `SizeHistogram`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct SizeClass {
+    /// Biggest allocation size in this class, in bytes. This is `usize::MAX` for the last class.
+    pub max_size: usize,
+    /// Number of allocations in this class since the allocator was created. Reallocations that
+    /// move a block to a different class are counted in the new class.
+    pub total: usize,
+    /// Number of allocations in this class that are not deallocated yet.
+    pub live: usize,
+}
+
+impl SizeHistogram {
+    /// Returns the size class of an allocation of `size` bytes.
+    pub fn class_of(&self, size: usize) -> SizeClass {
+        self.class(size_class(size))
+    }
+
+    fn class(&self, class: usize) -> SizeClass {
+        SizeClass {
+            max_size: class_max_size(class),
+            total: self.total[class],
+            live: self.live[class],
+        }
+    }
+
+    /// Returns an iterator over all the size classes, from the smallest to the biggest.
+    pub fn iter(&self) -> SizeClasses<'_> {
+        SizeClasses {
+            histogram: self,
+            next: 0,
+        }
+    }
+}
+
+impl<'a> IntoIterator for &'a SizeHistogram {
+    type Item = SizeClass;
+    type IntoIter = SizeClasses<'a>;
+
+    fn into_iter(self) -> SizeClasses<'a> {
+        self.iter()
+    }
+}
+
+/// Prints one line for each size class that is not empty, for example `<= 64 B: 3 total, 1
+/// live`.
+impl fmt::Display for SizeHistogram {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for class in self
+            .iter()
+            .filter(|class| class.total != 0 || class.live != 0)
+        {
+            if class.max_size == usize::MAX {
+                f.write_str("> 4 GiB")?;
+            } else {
+                write!(f, "<= {}", Bytes(class.max_size))?;
+            }
+            writeln!(f, ": {} total, {} live", class.total, class.live)?;
+        }
+        Ok(())
+    }
+}
+
+/// Prints a power of two number of bytes using the biggest unit that fits.
+struct Bytes(usize);
+
+impl fmt::Display for Bytes {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (shift, unit) in [(30, "GiB"), (20, "MiB"), (10, "KiB")] {
+            if self.0 >> shift != 0 {
+                return write!(f, "{} {}", self.0 >> shift, unit);
+            }
+        }
+        write!(f, "{} B", self.0)
+    }
+}
+
+/// Iterator over the size classes of a `SizeHistogram`, returned by `SizeHistogram::iter`.
+pub struct SizeClasses<'a> {
+    histogram: &'a SizeHistogram,
+    next: usize,
+}
+
+impl Iterator for SizeClasses<'_> {
+    type Item = SizeClass;
+
+    fn next(&mut self) -> Option<SizeClass> {
+        if self.next == SIZE_CLASSES {
+            return None;
+        }
+        self.next += 1;
+        Some(self.histogram.class(self.next - 1))
+    }
+}
@@ -0,0 +1,263 @@
+//! Allocator that is created on first use, see `Limit::new_lazy`.
+use crate::Limit;
+use core::alloc::{GlobalAlloc, Layout};
+use core::cell::UnsafeCell;
+use core::fmt;
+use core::hint;
+use core::mem::MaybeUninit;
+use core::sync::atomic::AtomicU8;
+use core::sync::atomic::Ordering::{Acquire, Release};
+
+/// Values of `LazyAlloc::state`.
+const UNINIT: u8 = 0;
+const INITIALIZING: u8 = 1;
+const READY: u8 = 2;
+
+/// Allocator that is created by calling a function on first use, for allocators that cannot be
+/// created in a `const` context, see `Limit::new_lazy`.
+///
+/// The allocator is stored inline, so this does not allocate. If several threads use it at the
+/// same time before it is created, one of them calls the function and the others spin until it
+/// returns, so the function is called exactly once. The function must not allocate using this
+/// allocator, that would deadlock. If it panics, the next use calls it again.
+pub struct LazyAlloc<A> {
+    init: fn() -> A,
+    /// Whether `alloc` is initialized, one of `UNINIT`, `INITIALIZING` or `READY`.
+    state: AtomicU8,
+    alloc: UnsafeCell<MaybeUninit<A>>,
+}
+
+// Safety: `alloc` is only written once, by the thread that moves the state to INITIALIZING, and
+// only read after the state is READY
+unsafe impl<A: Send + Sync> Sync for LazyAlloc<A> {}
+
+impl<A> LazyAlloc<A> {
+    pub const fn new(init: fn() -> A) -> Self {
+        Self {
+            init,
+            state: AtomicU8::new(UNINIT),
+            alloc: UnsafeCell::new(MaybeUninit::uninit()),
+        }
+    }
+
+    /// Returns the allocator, creating it if this is the first use.
+    pub fn get(&self) -> &A {
+        loop {
+            match self
+                .state
+                .compare_exchange_weak(UNINIT, INITIALIZING, Acquire, Acquire)
+            {
+                Ok(_) => {
+                    // If init panics, let the next use try again instead of spinning forever
+                    let guard = ResetOnUnwind(&self.state);
+                    let alloc = (self.init)();
+                    // Safety: only this thread can write, and nobody reads before READY
+                    unsafe { (*self.alloc.get()).write(alloc) };
+                    core::mem::forget(guard);
+                    self.state.store(READY, Release);
+                }
+                Err(READY) => {
+                    // Safety: the state is only READY after alloc is written
+                    return unsafe { (*self.alloc.get()).assume_init_ref() };
+                }
+                Err(_) => hint::spin_loop(),
+            }
+        }
+    }
+
+    /// Returns the allocator if it was already created.
+    pub fn get_if_created(&self) -> Option<&A> {
+        if self.state.load(Acquire) == READY {
+            // Safety: the state is only READY after alloc is written
+            Some(unsafe { (*self.alloc.get()).assume_init_ref() })
+        } else {
+            None
+        }
+    }
+}
+
+/// Moves the state back to `UNINIT` when dropped.
+struct ResetOnUnwind<'a>(&'a AtomicU8);
+
+impl Drop for ResetOnUnwind<'_> {
+    fn drop(&mut self) {
+        self.0.store(UNINIT, Release);
+    }
+}
+
+impl<A> Drop for LazyAlloc<A> {
+    fn drop(&mut self) {
+        if *self.state.get_mut() == READY {
+            // Safety: the state is only READY after alloc is written
+            unsafe { self.alloc.get_mut().assume_init_drop() };
+        }
+    }
+}
+
+impl<A: fmt::Debug> fmt::Debug for LazyAlloc<A> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_tuple("LazyAlloc")
+            .field(&self.get_if_created())
+            .finish()
+    }
+}
+
+unsafe impl<A: GlobalAlloc> GlobalAlloc for LazyAlloc<A> {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        self.get().alloc(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        self.get().dealloc(ptr, layout)
+    }
+
+    unsafe fn alloc_zeroed(&self, layout: Layout) -> *mut u8 {
+        self.get().alloc_zeroed(layout)
+    }
+
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        self.get().realloc(ptr, layout, new_size)
+    }
+}
+
+impl<A: GlobalAlloc> Limit<LazyAlloc<A>> {
+    /// Same as `new`, but the inner allocator is created by calling `init` on the first
+    /// allocation, for allocators that cannot be created in a `const` context. See `LazyAlloc`,
+    /// `init` must not allocate using this `Limit`.
+    ///
+    /// ```
+    /// use limit_alloc::{LazyAlloc, Limit};
+    /// use std::alloc::{GlobalAlloc, Layout, System};
+    /// use std::sync::atomic::{AtomicUsize, Ordering};
+    ///
+    /// static CREATED: AtomicUsize = AtomicUsize::new(0);
+    ///
+    /// fn create() -> System {
+    ///     CREATED.fetch_add(1, Ordering::SeqCst);
+    ///     System
+    /// }
+    ///
+    /// static LIMIT: Limit<LazyAlloc<System>> = Limit::new_lazy(1_000_000, create);
+    ///
+    /// assert_eq!(CREATED.load(Ordering::SeqCst), 0);
+    /// let layout = Layout::from_size_align(100, 8).unwrap();
+    /// std::thread::scope(|s| {
+    ///     for _ in 0..8 {
+    ///         s.spawn(|| unsafe { LIMIT.dealloc(LIMIT.alloc(layout), layout) });
+    ///     }
+    /// });
+    /// assert_eq!(CREATED.load(Ordering::SeqCst), 1);
+    /// assert!(LIMIT.inner().get_if_created().is_some());
+    /// ```
+    pub const fn new_lazy(limit: usize, init: fn() -> A) -> Self {
+        Limit::new(limit, LazyAlloc::new(init))
+    }
+}
+
+#[cfg(all(test, feature = "std"))]
+mod tests {
+    use super::*;
+    use crate::test_util::MockAlloc;
+    use core::sync::atomic::AtomicUsize;
+    use core::sync::atomic::Ordering::SeqCst;
+    use std::panic::AssertUnwindSafe;
+    use std::sync::Barrier;
+    use std::thread;
+    use std::time::Duration;
+
+    const LAYOUT: Layout = unsafe { Layout::from_size_align_unchecked(100, 8) };
+
+    #[test]
+    fn init_runs_once_under_concurrent_first_use() {
+        static CREATED: AtomicUsize = AtomicUsize::new(0);
+
+        fn create() -> MockAlloc {
+            CREATED.fetch_add(1, SeqCst);
+            // Give the other threads time to see INITIALIZING
+            thread::sleep(Duration::from_millis(50));
+            MockAlloc::new()
+        }
+
+        let limit = Limit::new_lazy(1_000_000, create);
+        assert!(limit.inner().get_if_created().is_none());
+        let barrier = Barrier::new(8);
+        thread::scope(|s| {
+            for _ in 0..8 {
+                s.spawn(|| {
+                    barrier.wait();
+                    unsafe { limit.dealloc(limit.alloc(LAYOUT), LAYOUT) };
+                });
+            }
+        });
+        assert_eq!(CREATED.load(SeqCst), 1);
+        // All the threads used the same instance
+        let mock = limit.inner().get_if_created().unwrap();
+        assert_eq!(mock.allocs.load(SeqCst), 8);
+        assert_eq!(mock.deallocs.load(SeqCst), 8);
+        assert_eq!(limit.allocated(), 0);
+    }
+
+    #[test]
+    fn every_method_uses_the_created_allocator() {
+        static CREATED: AtomicUsize = AtomicUsize::new(0);
+
+        fn create() -> MockAlloc {
+            CREATED.fetch_add(1, SeqCst);
+            MockAlloc::new()
+        }
+
+        let lazy = LazyAlloc::new(create);
+        unsafe {
+            let ptr = lazy.alloc_zeroed(LAYOUT);
+            let ptr = lazy.realloc(ptr, LAYOUT, 200);
+            lazy.dealloc(ptr, Layout::from_size_align(200, 8).unwrap());
+            lazy.dealloc(lazy.alloc(LAYOUT), LAYOUT);
+        }
+        let mock = lazy.get_if_created().unwrap();
+        assert_eq!(mock.zeroed.load(SeqCst), 1);
+        assert_eq!(mock.reallocs.load(SeqCst), 1);
+        assert_eq!(mock.allocs.load(SeqCst), 1);
+        assert_eq!(mock.deallocs.load(SeqCst), 2);
+        assert_eq!(CREATED.load(SeqCst), 1);
+    }
+
+    #[test]
+    fn panic_in_init_is_retried() {
+        static CALLS: AtomicUsize = AtomicUsize::new(0);
+
+        fn create() -> MockAlloc {
+            if CALLS.fetch_add(1, SeqCst) == 0 {
+                panic!("first init fails");
+            }
+            MockAlloc::new()
+        }
+
+        let lazy = LazyAlloc::new(create);
+        assert!(
+            std::panic::catch_unwind(AssertUnwindSafe(|| lazy.get().allocs.load(SeqCst))).is_err()
+        );
+        assert!(lazy.get_if_created().is_none());
+        assert_eq!(lazy.get().allocs.load(SeqCst), 0);
+        assert_eq!(CALLS.load(SeqCst), 2);
+    }
+
+    #[test]
+    fn drop_only_drops_a_created_allocator() {
+        static DROPPED: AtomicUsize = AtomicUsize::new(0);
+
+        struct DropCounter;
+
+        impl Drop for DropCounter {
+            fn drop(&mut self) {
+                DROPPED.fetch_add(1, SeqCst);
+            }
+        }
+
+        drop(LazyAlloc::new(|| DropCounter));
+        assert_eq!(DROPPED.load(SeqCst), 0);
+        let lazy = LazyAlloc::new(|| DropCounter);
+        lazy.get();
+        drop(lazy);
+        assert_eq!(DROPPED.load(SeqCst), 1);
+    }
+}
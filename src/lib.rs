@@ -3,62 +3,2447 @@
 //! This crate implements a few similar types, you can choose the best depending on your use case:
 //!
 //! * Use `ConstLimit` if you know the limit at compile time, because that makes the allocator
-//! zero-sized (as long as the inner allocator is also zero-sized).
+//!   zero-sized (as long as the inner allocator is also zero-sized).
 //! * Use `Limit` if you are not sure, or if you need more than one limit in the same application.
-//! This is needed because `ConstLimit` uses a static counter to store the allocated memory, so it
-//! is impossible to track the memory allocated by different instances of the allocator, we can
-//! only track the total allocated memory. The size of `Limit` is `1 * usize`.
+//!   This is needed because `ConstLimit` uses a static counter to store the allocated memory, so
+//!   all the instances with the same tag share the same counter. Different tags can be declared
+//!   using `const_limit_tag!`, but only at compile time. `Limit` stores a few `usize` counters
+//!   next to the inner allocator.
 //! * Use `ArcLimit` if you need a `Limit` that implements `Clone`. Ideally you would have been
-//! able to use `Arc<Limit<A>>` instead, but `Arc<T>` cannot implement `GlobalAlloc`.
+//!   able to use `Arc<Limit<A>>` instead, but `Arc<T>` cannot implement `GlobalAlloc`.
+//! * Use `ThreadLimit` if you want each thread to have its own limit, or `ConstThreadLimit` if
+//!   that limit is known at compile time, for example to give each test its own budget.
+//! * Use `BlockingLimit` if allocations should wait for other threads to deallocate memory
+//!   instead of failing.
+//! * Use `CountLimit` to limit the number of allocations instead of the bytes, or `DualLimit` to
+//!   limit both.
+//! * Use `EnvLimit` to read the limit of a global allocator from an environment variable.
+//! * Use `FallbackLimit` if allocations that exceed the limit should use a second allocator
+//!   instead of failing.
+//! * Use `Limit::new_lazy` if the inner allocator cannot be created in a `const` context.
+//! * Use `GroupLimit` if several allocators of different types should share one limit, a
+//!   `SharedBudget`.
+//! * Use `RateLimit` to limit how many bytes can be allocated per second.
+//! * Use `ShardedLimit` if many threads allocate at the same time and the counter of `Limit` is a
+//!   bottleneck.
+//!
+//! Limits can be nested using `Limit::child`, for example a global limit for the whole program and
+//! a smaller limit for each subsystem.
 //!
 //! Note on alignment: an allocation of 1 byte with alignment greater than 1, for example 2 bytes,
-//! will allocate 2 bytes because of padding. But this crate only counts 1 byte. So the limit may
-//! not be completely accurate.
-use std::alloc::{GlobalAlloc, Layout};
-use std::ptr;
-use std::sync::atomic::AtomicUsize;
-use std::sync::atomic::Ordering::SeqCst;
-use std::sync::Arc;
+//! will allocate 2 bytes because of padding. But by default this crate only counts 1 byte. So the
+//! limit may not be completely accurate. Use `Limit::with_accounting` with `Accounting::Padded`
+//! to count the padding as well, or `Limit::with_policy` with a `CountPolicy` for other rules,
+//! such as rounding to the size classes of the inner allocator.
+//!
+//! With the `nightly` feature enabled, `Limit`, `ArcLimit` and `ConstLimit` also implement the
+//! unstable `Allocator` trait, so they can be used to limit the memory of a single collection, for
+//! example `Vec::new_in(&limit)`. The `allocator-api2` feature does the same for the stable
+//! `Allocator` trait from the `allocator-api2` crate.
+//!
+//! With the `tracing` feature enabled, every allocation rejected by a `Limit` emits a
+//! `tracing::warn!` event with the requested size and alignment, the remaining memory and the
+//! limit, and crossing a watermark set using `Limit::set_watermarks` emits a `tracing::info!`
+//! event with the allocated memory, the threshold and the limit. With the `log` feature a
+//! rejected allocation calls `log::error!` with the same information. The subscriber or logger
+//! that handles the message may allocate, and that allocation may go through the same
+//! `Limit` which has no memory left. To avoid failing or recursing forever, a thread local flag is
+//! set while the message is emitted: allocations made by the current thread during that time
+//! ignore the byte limit (they are still counted, so `allocated` may briefly be above the limit),
+//! and if one of them is rejected anyway, for example because of `set_max_single_alloc`, no nested
+//! message is emitted.
+//!
+//! The `debug-tracking` feature is a testing aid to find accounting bugs: in debug builds, every
+//! `Limit` records the layout of each live allocation in a map protected by a `Mutex`, and
+//! records an error when a pointer is deallocated with a different layout than it was allocated
+//! with, or when it was not allocated by that `Limit`. `Limit::assert_accounting` panics with that
+//! error. The deallocation itself cannot panic, because a global allocator must not unwind. This
+//! is slow, every allocation and deallocation takes a global lock and updates a `BTreeMap`. The
+//! map allocates using the global allocator, so when the `Limit` is the global allocator the map
+//! is counted by the limit, but its own allocations are not tracked. `Limit::tracked_allocations`
+//! and `Limit::dump_live` list the live allocations, for example to find leaks. Release builds do
+//! not track anything.
+//!
+//! The `verify-accounting` feature enables `debug-tracking` and adds `Limit::verify`, which checks
+//! that the allocated memory counted by the `Limit` is the sum of the recorded allocations, to
+//! find the cause of a counter that drifts.
+//!
+//! This crate is `no_std` when the default `std` feature is disabled, so `Limit` and `ConstLimit`
+//! can wrap a custom heap allocator in an embedded target. The `alloc` feature enables `ArcLimit`,
+//! and `std` enables `ThreadLimit` and the features that need the operating system.
+#![no_std]
+#![cfg_attr(feature = "nightly", feature(allocator_api))]
+#[cfg(feature = "alloc")]
+extern crate alloc;
+#[cfg(feature = "std")]
+extern crate std;
+
+use core::alloc::{GlobalAlloc, Layout};
+use core::fmt;
+use core::marker::PhantomData;
+use core::mem::{self, ManuallyDrop};
+use core::ptr;
+use core::sync::atomic::Ordering::{self, Acquire, Relaxed, Release, SeqCst};
+use core::sync::atomic::{AtomicBool, AtomicPtr, AtomicUsize};
+
+mod alloc_guard;
+#[cfg(feature = "std")]
+#[macro_use]
+mod alloc_local;
+#[cfg(any(feature = "nightly", feature = "allocator-api2"))]
+mod allocator_api;
+#[cfg(feature = "alloc")]
+mod arc_limit;
+#[cfg(feature = "std")]
+mod blocking_limit;
+mod builder;
+#[cfg(feature = "std")]
+mod const_thread_limit;
+mod count_limit;
+mod count_policy;
+#[cfg(feature = "std")]
+mod emergency_reserve;
+#[cfg(feature = "std")]
+mod env_limit;
+mod error;
+mod fallback_limit;
+#[cfg(feature = "std")]
+mod free_notify;
+#[cfg(feature = "alloc")]
+mod group_limit;
+#[cfg(feature = "histogram")]
+mod histogram;
+mod lazy;
+#[cfg(feature = "metrics")]
+mod limit_metrics;
+#[cfg(feature = "std")]
+mod measure;
+#[cfg(feature = "std")]
+mod rate_limit;
+#[cfg(any(feature = "tracing", feature = "log"))]
+mod report;
+mod reservation;
+#[cfg(feature = "std")]
+mod sharded_limit;
+mod size;
+#[cfg(feature = "system-memory")]
+mod system_memory;
+#[cfg(feature = "std")]
+mod tags;
+// Some helpers are only used with some features
+#[cfg(all(test, feature = "std"))]
+#[allow(dead_code)]
+mod test_util;
+#[cfg(feature = "std")]
+mod thread_limit;
+#[cfg(feature = "debug-tracking")]
+mod tracking;
+#[cfg(feature = "usable-size")]
+mod usable_size;
+mod watermark;
+
+pub use alloc_guard::AllocGuard;
+#[cfg(feature = "alloc")]
+pub use arc_limit::{ArcLimit, WeakLimit};
+#[cfg(feature = "std")]
+pub use blocking_limit::BlockingLimit;
+pub use builder::{BuildError, LimitBuilder};
+#[cfg(feature = "std")]
+pub use const_thread_limit::ConstThreadLimit;
+pub use count_limit::{CountLimit, DualLimit};
+pub use count_policy::{CountPolicy, ExactSize, PaddedSize, Quantized, WithOverhead};
+#[cfg(feature = "std")]
+pub use env_limit::EnvLimit;
+pub use error::AllocError;
+pub use fallback_limit::FallbackLimit;
+#[cfg(feature = "alloc")]
+pub use group_limit::{GroupLimit, SharedBudget};
+#[cfg(feature = "histogram")]
+use histogram::Histogram;
+#[cfg(feature = "histogram")]
+pub use histogram::{SizeClass, SizeClasses, SizeHistogram, SIZE_CLASSES};
+pub use lazy::LazyAlloc;
+#[cfg(feature = "metrics")]
+pub use limit_metrics::LimitMetrics;
+#[cfg(feature = "std")]
+pub use measure::{measure_const, MemoryDelta};
+#[cfg(feature = "std")]
+pub use rate_limit::RateLimit;
+pub use reservation::Reservation;
+#[cfg(feature = "std")]
+pub use sharded_limit::ShardedLimit;
+pub use size::{parse_size, ParseSizeError};
+#[cfg(feature = "system-memory")]
+pub use system_memory::{cgroup_memory_max, total_system_memory};
+#[cfg(feature = "std")]
+use tags::Tags;
+#[cfg(feature = "std")]
+pub use tags::MAX_TAGS;
+#[cfg(feature = "std")]
+pub use thread_limit::ThreadLimit;
+#[cfg(feature = "verify-accounting")]
+pub use tracking::AccountingError;
+#[cfg(feature = "debug-tracking")]
+pub use tracking::LiveAlloc;
+#[cfg(feature = "debug-tracking")]
+use tracking::Tracker;
+use watermark::{SoftLimit, Watermarks};
+pub use watermark::{WatermarkCallback, MAX_WATERMARKS};
+
+pub struct Limit<A> {
+    /// Memory currently allocated, in bytes.
+    allocated: AtomicUsize,
+    /// Highest number of bytes allocated at the same time.
+    peak: AtomicUsize,
+    limit: AtomicUsize,
+    /// Maximum size of a single allocation, in bytes.
+    max_single_alloc: AtomicUsize,
+    /// Number of allocations that are not deallocated yet, only counted if
+    /// `max_live_allocations` is not `usize::MAX`.
+    live_allocations: AtomicUsize,
+    max_live_allocations: usize,
+    /// Bytes above the limit that can only be used inside `use_reserve`.
+    #[cfg(feature = "std")]
+    reserve: usize,
+    /// Bytes allocated above the limit inside `use_reserve`, deallocations give them back first.
+    #[cfg(feature = "std")]
+    reserve_used: AtomicUsize,
+    /// Statistics, these are only informational so they use relaxed ordering.
+    allocations: AtomicUsize,
+    deallocations: AtomicUsize,
+    failed_allocations: AtomicUsize,
+    rejected_allocations: AtomicUsize,
+    /// True if an allocation was rejected since the allocator was created or since the last call
+    /// to `clear_exhausted`.
+    exhausted: AtomicBool,
+    bytes_allocated_total: AtomicUsize,
+    largest_request: AtomicUsize,
+    largest_live: AtomicUsize,
+    #[cfg(feature = "histogram")]
+    histogram: Histogram,
+    /// Function called when an allocation fails because of the limit, stored as a
+    /// `fn(Layout, usize)`.
+    oom_handler: AtomicPtr<()>,
+    /// Function called after every deallocation, stored as a `fn(Layout)`.
+    dealloc_hook: AtomicPtr<()>,
+    /// Function called when memory is returned to the limit, stored as a `fn(usize)`.
+    #[cfg(feature = "std")]
+    free_notify: AtomicPtr<()>,
+    /// True if the allocated memory should be checked when the `Limit` is dropped.
+    leak_check: AtomicBool,
+    /// Function called when a leak is found, stored as a `fn(usize)`. If null, a leak panics.
+    leak_report: AtomicPtr<()>,
+    /// True if deallocating more bytes than are allocated should be reported by
+    /// `assert_accounting`, instead of only saturating.
+    strict_accounting: AtomicBool,
+    /// Bytes deallocated above the allocated memory while `strict_accounting` was set.
+    over_credited: AtomicUsize,
+    /// True if allocations that exceed the limit succeed anyway, see `Overcommit::Allow`.
+    overcommit: AtomicBool,
+    /// Bytes above the limit allowed by `Overcommit::Allow`, in total.
+    overcommitted_bytes: AtomicUsize,
+    watermarks: Watermarks,
+    soft_limit: SoftLimit,
+    #[cfg(feature = "std")]
+    tags: Tags,
+    #[cfg(feature = "debug-tracking")]
+    tracker: Tracker,
+    /// Reserved memory that is not used by any allocation, see `verify`.
+    #[cfg(feature = "verify-accounting")]
+    reserved: AtomicUsize,
+    accounting: Accounting,
+    ordering: CounterOrdering,
+    /// Count the real size of the blocks returned by the system allocator, only possible when `A`
+    /// is `System`.
+    #[cfg(feature = "usable-size")]
+    usable_size: bool,
+    alloc: A,
+}
+
+/// How many bytes are counted for each allocation. This does not implement `PartialEq`, because
+/// function pointers cannot be compared reliably.
+#[derive(Clone, Copy, Debug, Default)]
+pub enum Accounting {
+    /// Count `layout.size()`. This is the default.
+    #[default]
+    Size,
+    /// Count the size rounded up to a multiple of the alignment, `layout.pad_to_align().size()`.
+    /// For example an allocation of 1 byte with alignment 64 counts as 64 bytes.
+    Padded,
+    /// Count the result of a function, usually `CountPolicy::charge`, see `Accounting::policy`.
+    Custom(fn(Layout) -> usize),
+}
+
+impl Accounting {
+    /// Returns the accounting that counts the bytes using the policy `P`.
+    pub const fn policy<P: CountPolicy>() -> Self {
+        Accounting::Custom(P::charge)
+    }
+
+    /// Returns the number of bytes counted for an allocation with this layout.
+    fn size(self, layout: Layout) -> usize {
+        match self {
+            Accounting::Size => layout.size(),
+            Accounting::Padded => layout.pad_to_align().size(),
+            Accounting::Custom(f) => f(layout),
+        }
+    }
+}
+
+/// What happens to an allocation that would exceed the limit, see `Limit::set_overcommit`.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Overcommit {
+    /// The allocation fails. This is the default.
+    #[default]
+    Reject,
+    /// The allocation succeeds and the allocated memory goes above the limit. The maximum size of
+    /// a single allocation and the maximum number of live allocations are still enforced.
+    Allow,
+}
+
+/// Memory ordering used to update the allocated memory, see `Limit::with_ordering`.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum CounterOrdering {
+    /// `SeqCst` for all the operations. This is the default.
+    #[default]
+    SeqCst,
+    /// `AcqRel` for the updates, `Acquire` for the loads.
+    AcqRel,
+    /// `Relaxed` for all the operations.
+    Relaxed,
+}
+
+impl CounterOrdering {
+    /// Ordering of read-modify-write operations.
+    fn rmw(self) -> Ordering {
+        match self {
+            CounterOrdering::SeqCst => SeqCst,
+            CounterOrdering::AcqRel => Ordering::AcqRel,
+            CounterOrdering::Relaxed => Relaxed,
+        }
+    }
+
+    /// Ordering of loads, also used as the failure ordering of `fetch_update`.
+    fn load(self) -> Ordering {
+        match self {
+            CounterOrdering::SeqCst => SeqCst,
+            CounterOrdering::AcqRel => Acquire,
+            CounterOrdering::Relaxed => Relaxed,
+        }
+    }
+
+    /// Ordering of stores.
+    fn store(self) -> Ordering {
+        match self {
+            CounterOrdering::SeqCst => SeqCst,
+            CounterOrdering::AcqRel => Release,
+            CounterOrdering::Relaxed => Relaxed,
+        }
+    }
+}
+
+/// Snapshot of the statistics of a `Limit`, returned by `Limit::stats`.
+///
+/// With the `serde` feature enabled this implements `Serialize` and `Deserialize`, using the
+/// field names as keys:
+///
+/// ```
+/// # #[cfg(feature = "serde")]
+/// # {
+/// use limit_alloc::{Limit, Stats};
+/// use std::alloc::System;
+///
+/// let limit = Limit::new(1_000, System);
+/// let json = serde_json::to_value(limit.stats()).unwrap();
+/// assert_eq!(json["remaining"], 1_000);
+/// assert_eq!(json["allocated"], 0);
+/// assert_eq!(json["peak"], 0);
+/// let stats: Stats = serde_json::from_value(json).unwrap();
+/// assert_eq!(stats, limit.stats());
+/// # }
+/// ```
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct Stats {
+    /// Memory limit, in bytes.
+    pub limit: usize,
+    /// Remaining memory, in bytes.
+    pub remaining: usize,
+    /// Memory currently allocated, in bytes.
+    pub allocated: usize,
+    /// Highest number of bytes allocated at the same time.
+    pub peak: usize,
+    /// Number of successful allocations.
+    pub alloc_count: usize,
+    /// Number of deallocations.
+    pub dealloc_count: usize,
+    /// Number of allocations that failed, either because of the limit or because the inner
+    /// allocator returned null.
+    pub failed: usize,
+    /// Number of allocations that failed because of the limit, this is included in `failed`.
+    pub rejected: usize,
+    /// Sum of the sizes of all the successful allocations. Growing an allocation using `realloc`
+    /// adds the difference.
+    pub bytes_allocated_total: usize,
+    /// Largest size passed to `alloc` or `realloc`, see `Limit::largest_request`.
+    pub largest_request: usize,
+    /// Largest successful allocation, see `Limit::largest_live`.
+    pub largest_live: usize,
+}
+
+impl<A: GlobalAlloc> Limit<A> {
+    pub const fn new(limit: usize, alloc: A) -> Self {
+        Self::with_accounting(limit, alloc, Accounting::Size)
+    }
+
+    /// Same as `new(usize::MAX, alloc)`: there is no limit until one is set using `set_limit`.
+    /// This is cheaper when a `Limit` stays installed as the global allocator in release builds:
+    /// without a limit, allocations update the allocated memory using a `fetch_add` instead of a
+    /// compare and swap loop. The statistics are still updated, so a limit set later applies to
+    /// the memory that is already allocated and the counters do not depend on when it was set.
+    /// The fast path is not used if `max_live_allocations` is set or with the `histogram`,
+    /// `debug-tracking` or `usable-size` options. Run `cargo bench --bench unlimited` to measure
+    /// the overhead compared to `System`.
+    ///
+    /// ```
+    /// use limit_alloc::Limit;
+    /// use std::alloc::{GlobalAlloc, Layout, System};
+    ///
+    /// let limit = Limit::unlimited(System);
+    /// let layout = Layout::from_size_align(600, 1).unwrap();
+    /// let ptr = unsafe { limit.alloc(layout) };
+    /// limit.set_limit(1_000);
+    /// assert_eq!(limit.remaining(), 400);
+    /// assert!(unsafe { limit.alloc(layout) }.is_null());
+    /// limit.set_limit(usize::MAX);
+    /// let other = unsafe { limit.alloc(layout) };
+    /// assert!(!other.is_null());
+    /// unsafe {
+    ///     limit.dealloc(ptr, layout);
+    ///     limit.dealloc(other, layout);
+    /// }
+    /// assert_eq!(limit.allocated(), 0);
+    /// ```
+    pub const fn unlimited(alloc: A) -> Self {
+        Self::new(usize::MAX, alloc)
+    }
+
+    /// Returns a builder to configure the options of a `Limit`, see `LimitBuilder`.
+    pub const fn builder(alloc: A) -> LimitBuilder<A> {
+        LimitBuilder::new(alloc)
+    }
+
+    /// Same as `new`, but allows to choose how many bytes are counted for each allocation.
+    ///
+    /// ```
+    /// use limit_alloc::{Accounting, Limit};
+    /// use std::alloc::{GlobalAlloc, Layout, System};
+    ///
+    /// let size = Limit::new(1_000, System);
+    /// let padded = Limit::with_accounting(1_000, System, Accounting::Padded);
+    /// let layout = Layout::from_size_align(1, 64).unwrap();
+    /// unsafe {
+    ///     let a = size.alloc(layout);
+    ///     let b = padded.alloc(layout);
+    ///     assert_eq!(size.allocated(), 1);
+    ///     assert_eq!(padded.allocated(), 64);
+    ///     // The same amount is subtracted when deallocating
+    ///     size.dealloc(a, layout);
+    ///     padded.dealloc(b, layout);
+    /// }
+    /// assert_eq!(padded.allocated(), 0);
+    /// ```
+    pub const fn with_accounting(limit: usize, alloc: A, accounting: Accounting) -> Self {
+        Self {
+            allocated: AtomicUsize::new(0),
+            peak: AtomicUsize::new(0),
+            limit: AtomicUsize::new(limit),
+            max_single_alloc: AtomicUsize::new(usize::MAX),
+            live_allocations: AtomicUsize::new(0),
+            max_live_allocations: usize::MAX,
+            #[cfg(feature = "std")]
+            reserve: 0,
+            #[cfg(feature = "std")]
+            reserve_used: AtomicUsize::new(0),
+            allocations: AtomicUsize::new(0),
+            deallocations: AtomicUsize::new(0),
+            failed_allocations: AtomicUsize::new(0),
+            rejected_allocations: AtomicUsize::new(0),
+            exhausted: AtomicBool::new(false),
+            bytes_allocated_total: AtomicUsize::new(0),
+            largest_request: AtomicUsize::new(0),
+            largest_live: AtomicUsize::new(0),
+            #[cfg(feature = "histogram")]
+            histogram: Histogram::new(),
+            oom_handler: AtomicPtr::new(ptr::null_mut()),
+            dealloc_hook: AtomicPtr::new(ptr::null_mut()),
+            #[cfg(feature = "std")]
+            free_notify: AtomicPtr::new(ptr::null_mut()),
+            leak_check: AtomicBool::new(false),
+            leak_report: AtomicPtr::new(ptr::null_mut()),
+            strict_accounting: AtomicBool::new(false),
+            over_credited: AtomicUsize::new(0),
+            overcommit: AtomicBool::new(false),
+            overcommitted_bytes: AtomicUsize::new(0),
+            watermarks: Watermarks::new(),
+            soft_limit: SoftLimit::new(),
+            #[cfg(feature = "std")]
+            tags: Tags::new(),
+            #[cfg(feature = "debug-tracking")]
+            tracker: Tracker::new(),
+            #[cfg(feature = "verify-accounting")]
+            reserved: AtomicUsize::new(0),
+            accounting,
+            ordering: CounterOrdering::SeqCst,
+            #[cfg(feature = "usable-size")]
+            usable_size: false,
+            alloc,
+        }
+    }
+
+    /// Same as `new`, but the bytes counted for each allocation are given by the policy `P`, see
+    /// `CountPolicy`.
+    ///
+    /// ```
+    /// use limit_alloc::{Limit, Quantized};
+    /// use std::alloc::{GlobalAlloc, Layout, System};
+    ///
+    /// let limit = Limit::with_policy::<Quantized<16>>(1_000, System);
+    /// let layout = Layout::from_size_align(20, 1).unwrap();
+    /// let ptr = unsafe { limit.alloc(layout) };
+    /// assert_eq!(limit.allocated(), 32);
+    /// unsafe { limit.dealloc(ptr, layout) };
+    /// assert_eq!(limit.allocated(), 0);
+    /// ```
+    pub const fn with_policy<P: CountPolicy>(limit: usize, alloc: A) -> Self {
+        Self::with_accounting(limit, alloc, Accounting::policy::<P>())
+    }
+
+    /// Same as `new`, but allows to choose the memory ordering used to update the allocated memory
+    /// and the peak.
+    ///
+    /// With many threads allocating at the same time, every allocation updates the same counter,
+    /// and a weaker ordering can make that cheaper on some architectures. The limit is still
+    /// respected with `CounterOrdering::Relaxed`, because each allocation reserves its size
+    /// atomically, but the counters will not synchronize with other memory operations. For
+    /// example after changing the limit with `set_limit`, other threads may keep using the old
+    /// limit for a short while.
+    pub const fn with_ordering(limit: usize, alloc: A, ordering: CounterOrdering) -> Self {
+        let mut l = Self::new(limit, alloc);
+        l.ordering = ordering;
+        l
+    }
+
+    /// Same as `new`, but also limits the number of allocations that are live at the same time.
+    /// An allocation fails if it would exceed either the limit in bytes or `max_live_allocations`.
+    /// This catches programs that create millions of tiny allocations, which a limit in bytes
+    /// barely notices. With `usize::MAX` the allocations are not counted, same as `new`.
+    ///
+    /// ```
+    /// use limit_alloc::Limit;
+    /// use std::alloc::{GlobalAlloc, Layout, System};
+    ///
+    /// let limit = Limit::with_counts(1_000_000, 2, System);
+    /// let layout = Layout::from_size_align(8, 8).unwrap();
+    /// unsafe {
+    ///     let a = limit.alloc(layout);
+    ///     let b = limit.alloc(layout);
+    ///     // Only 16 bytes are allocated, but there are already 2 live allocations
+    ///     assert!(limit.alloc(layout).is_null());
+    ///     assert_eq!(limit.live_allocations(), 2);
+    ///     limit.dealloc(a, layout);
+    ///     limit.dealloc(b, layout);
+    /// }
+    /// assert_eq!(limit.live_allocations(), 0);
+    /// ```
+    pub const fn with_counts(limit: usize, max_live_allocations: usize, alloc: A) -> Self {
+        let mut l = Self::new(limit, alloc);
+        l.max_live_allocations = max_live_allocations;
+        l
+    }
+
+    /// Same as `new`, but reads the limit from the environment variable `var`, which is a number
+    /// of bytes with an optional unit, for example `512K` or `4G`, see `parse_size`. Uses
+    /// `default` if the variable is not set or cannot be parsed.
+    ///
+    /// ```
+    /// use limit_alloc::Limit;
+    /// use std::alloc::System;
+    ///
+    /// std::env::set_var("MY_LIMIT", "4M");
+    /// let limit = Limit::from_env("MY_LIMIT", 1_000, System);
+    /// assert_eq!(limit.limit(), 4 * 1024 * 1024);
+    /// ```
+    ///
+    /// This cannot be used to initialize a `static`, so for a global allocator use `new` with the
+    /// default limit and set the real one from the environment at the start of `main`, using
+    /// `set_limit_from_env`. Allocations made before that use the default limit.
+    #[cfg(feature = "std")]
+    pub fn from_env(var: &str, default: usize, alloc: A) -> Self {
+        Self::new(limit_from_env(var, default), alloc)
+    }
+
+    /// Sets the limit from the environment variable `var`, see `from_env`. Returns the new limit.
+    ///
+    /// ```
+    /// use limit_alloc::Limit;
+    /// use std::alloc::System;
+    ///
+    /// #[global_allocator]
+    /// static A: Limit<System> = Limit::new(usize::MAX, System);
+    ///
+    /// fn main() {
+    ///     A.set_limit_from_env("LIMIT_ALLOC_BYTES", usize::MAX);
+    /// }
+    /// ```
+    #[cfg(feature = "std")]
+    pub fn set_limit_from_env(&self, var: &str, default: usize) -> usize {
+        let limit = limit_from_env(var, default);
+        self.set_limit(limit);
+        limit
+    }
+
+    /// Same as `new`, but the limit is `percent`% of the total physical memory of the machine.
+    /// Percentages above 100 are allowed, and 0 makes every allocation fail. Returns None if the
+    /// total memory cannot be queried on this platform, see `total_system_memory`.
+    ///
+    /// ```
+    /// use limit_alloc::{total_system_memory, Limit};
+    /// use std::alloc::System;
+    ///
+    /// if let Some(limit) = Limit::percent_of_system(50, System) {
+    ///     assert_eq!(limit.limit(), total_system_memory().unwrap() / 2);
+    /// }
+    /// ```
+    ///
+    /// The memory is only queried when this is called, so for a global allocator use `new` and
+    /// call `set_limit_percent_of_system` at the start of `main`.
+    #[cfg(feature = "system-memory")]
+    pub fn percent_of_system(percent: usize, alloc: A) -> Option<Self> {
+        let limit = system_memory::percent_of_memory(total_system_memory, percent)?;
+        Some(Self::new(limit, alloc))
+    }
+
+    /// Sets the limit to `percent`% of the total physical memory, see `percent_of_system`.
+    /// Returns the new limit, or None if the total memory cannot be queried, in that case the
+    /// limit is not modified.
+    #[cfg(feature = "system-memory")]
+    pub fn set_limit_percent_of_system(&self, percent: usize) -> Option<usize> {
+        let limit = system_memory::percent_of_memory(total_system_memory, percent)?;
+        self.set_limit(limit);
+        Some(limit)
+    }
+
+    /// Same as `new`, but the limit is the memory limit of the cgroup minus `headroom` bytes, for
+    /// example to leave some memory for the parts of the program that do not use this allocator.
+    /// If there is no cgroup limit, or this is not Linux, the limit is `usize::MAX`. See
+    /// `cgroup_memory_max`.
+    ///
+    /// ```
+    /// use limit_alloc::{cgroup_memory_max, Limit};
+    /// use std::alloc::System;
+    ///
+    /// let limit = Limit::from_cgroup(64 << 20, System);
+    /// match cgroup_memory_max() {
+    ///     Some(max) => assert_eq!(limit.limit(), max.saturating_sub(64 << 20)),
+    ///     None => assert_eq!(limit.limit(), usize::MAX),
+    /// }
+    /// ```
+    #[cfg(feature = "system-memory")]
+    pub fn from_cgroup(headroom: usize, alloc: A) -> Self {
+        let limit = cgroup_memory_max().map_or(usize::MAX, |max| max.saturating_sub(headroom));
+        Self::new(limit, alloc)
+    }
+
+    /// Same as `new`, but keeps an emergency reserve of `reserve` bytes above the limit. Normal
+    /// allocations can only use `limit` bytes, and allocations made inside `use_reserve` can use
+    /// the reserve as well. This is useful to log a failure and shut down cleanly after the limit
+    /// is exhausted. Memory allocated from the reserve is counted in `allocated`, so `remaining`
+    /// is 0 while it is used.
+    #[cfg(feature = "std")]
+    pub const fn with_reserve(limit: usize, reserve: usize, alloc: A) -> Self {
+        let mut l = Self::new(limit, alloc);
+        l.reserve = reserve;
+        l
+    }
+
+    /// Runs `f`, and allocations made through this `Limit` by the current thread inside `f` can
+    /// use the emergency reserve set by `with_reserve`. Allocations made by other threads, or
+    /// through other limits, are not affected.
+    ///
+    /// ```
+    /// use limit_alloc::Limit;
+    /// use std::alloc::{GlobalAlloc, Layout, System};
+    ///
+    /// let limit = Limit::with_reserve(1_000, 100, System);
+    /// let big = Layout::from_size_align(1_000, 1).unwrap();
+    /// let small = Layout::from_size_align(60, 1).unwrap();
+    /// unsafe {
+    ///     let a = limit.alloc(big);
+    ///     assert!(limit.alloc(small).is_null());
+    ///     let b = limit.use_reserve(|| limit.alloc(small));
+    ///     assert!(!b.is_null());
+    ///     assert_eq!(limit.remaining(), 0);
+    ///     assert_eq!(limit.reserve_used(), 60);
+    ///     // Only 40 bytes are left in the reserve
+    ///     assert!(limit.use_reserve(|| limit.alloc(small)).is_null());
+    ///     limit.dealloc(b, small);
+    ///     limit.dealloc(a, big);
+    /// }
+    /// assert_eq!(limit.reserve_used(), 0);
+    /// ```
+    #[cfg(feature = "std")]
+    pub fn use_reserve<R>(&self, f: impl FnOnce() -> R) -> R {
+        let _guard = emergency_reserve::ReserveGuard::new(self as *const Self as *const ());
+        f()
+    }
+
+    /// Returns the part of the emergency reserve that is currently allocated, in bytes. Only
+    /// allocations made inside `use_reserve` that went above the limit use the reserve, memory
+    /// above the limit because of `Overcommit::Allow` or because the limit was lowered does not.
+    /// Deallocations give the memory back to the reserve first.
+    #[cfg(feature = "std")]
+    pub fn reserve_used(&self) -> usize {
+        self.reserve_used.load(Relaxed)
+    }
+
+    /// Returns the number of allocations that are not deallocated yet. These are only counted
+    /// when using `with_counts`, otherwise this is always 0.
+    pub fn live_allocations(&self) -> usize {
+        self.live_allocations.load(SeqCst)
+    }
+
+    /// Returns the maximum number of live allocations, see `with_counts`.
+    pub fn max_live_allocations(&self) -> usize {
+        self.max_live_allocations
+    }
+
+    /// Returns the allocations recorded by the `debug-tracking` feature that are not deallocated
+    /// yet, sorted by address. This is empty in release builds. It is not named
+    /// `live_allocations` because that method returns the count of `with_counts`.
+    ///
+    /// The list is allocated using the global allocator, so when this `Limit` is the global
+    /// allocator the list includes itself. This must not be called from inside the allocator.
+    ///
+    /// ```
+    /// use limit_alloc::Limit;
+    /// use std::alloc::{GlobalAlloc, Layout, System};
+    ///
+    /// let limit = Limit::new(1_000, System);
+    /// let small = Layout::from_size_align(10, 2).unwrap();
+    /// let big = Layout::from_size_align(100, 8).unwrap();
+    /// unsafe {
+    ///     let a = limit.alloc(small);
+    ///     let b = limit.alloc(big);
+    ///     let live = limit.tracked_allocations();
+    ///     if cfg!(debug_assertions) {
+    ///         assert_eq!(live.len(), 2);
+    ///         assert!(live.iter().any(|l| l.address == a as usize && l.size == 10 && l.align == 2));
+    ///     }
+    ///     limit.dealloc(a, small);
+    ///     let live = limit.tracked_allocations();
+    ///     if cfg!(debug_assertions) {
+    ///         assert_eq!(live.len(), 1);
+    ///         assert_eq!((live[0].address, live[0].size), (b as usize, 100));
+    ///     }
+    ///     limit.dealloc(b, big);
+    /// }
+    /// assert!(limit.tracked_allocations().is_empty());
+    /// ```
+    #[cfg(feature = "debug-tracking")]
+    pub fn tracked_allocations(&self) -> std::vec::Vec<LiveAlloc> {
+        self.tracker.snapshot()
+    }
+
+    /// Writes a report of the allocations that are not deallocated yet, see
+    /// `tracked_allocations`, for example to find leaks or to see what uses the memory after an
+    /// allocation fails. Each line has the address, size and alignment of one allocation.
+    ///
+    /// ```
+    /// use limit_alloc::Limit;
+    /// use std::alloc::{GlobalAlloc, Layout, System};
+    ///
+    /// let limit = Limit::new(1_000, System);
+    /// let layout = Layout::from_size_align(100, 8).unwrap();
+    /// let ptr = unsafe { limit.alloc(layout) };
+    /// let mut report = String::new();
+    /// limit.dump_live(&mut report).unwrap();
+    /// if cfg!(debug_assertions) {
+    ///     assert!(report.starts_with("1 live allocations, 100 bytes\n"));
+    ///     assert!(report.contains(&format!("{:p}: size 100, align 8\n", ptr)));
+    /// }
+    /// unsafe { limit.dealloc(ptr, layout) };
+    /// ```
+    #[cfg(feature = "debug-tracking")]
+    pub fn dump_live(&self, writer: &mut dyn fmt::Write) -> fmt::Result {
+        // Take the snapshot first, the writer may allocate
+        let live = self.tracker.snapshot();
+        let bytes: usize = live.iter().map(|l| l.size).sum();
+        writeln!(writer, "{} live allocations, {} bytes", live.len(), bytes)?;
+        for l in &live {
+            writeln!(
+                writer,
+                "{:p}: size {}, align {}",
+                l.address as *const u8, l.size, l.align
+            )?;
+        }
+        let unknown = self.tracker.unknown_frees();
+        if unknown > 0 {
+            writeln!(writer, "{} deallocations of unknown pointers", unknown)?;
+        }
+        Ok(())
+    }
+
+    /// Returns the number of deallocations of pointers that were not allocated by this `Limit`,
+    /// detected by the `debug-tracking` feature. They are also reported by `assert_accounting`.
+    /// This is always 0 in release builds.
+    #[cfg(feature = "debug-tracking")]
+    pub fn unknown_frees(&self) -> usize {
+        self.tracker.unknown_frees()
+    }
+
+    /// Checks the accounting of this `Limit` against the allocations recorded by the
+    /// `debug-tracking` feature: `used()` must be the memory counted for the live allocations
+    /// plus the reserved memory that no allocation uses. If a deallocation was made with the
+    /// wrong layout or with an unknown pointer, that error is returned instead, because it makes
+    /// the counter drift.
+    ///
+    /// Allocations being made by other threads at the same time may be counted but not recorded
+    /// yet, so call this at a point where no other thread uses this `Limit`. Bytes taken using
+    /// `Reservation::consume` are also reported as drift, because they are counted but not
+    /// recorded. This does nothing in release builds.
+    ///
+    /// ```
+    /// use limit_alloc::{AccountingError, Limit};
+    /// use std::alloc::{GlobalAlloc, Layout, System};
+    ///
+    /// let limit = Limit::new(1_000, System);
+    /// let layout = Layout::from_size_align(100, 1).unwrap();
+    /// let ptr = unsafe { limit.alloc(layout) };
+    /// assert_eq!(limit.verify(), Ok(()));
+    ///
+    /// // Deallocating with the wrong size is recorded in debug builds, and the pointer is leaked
+    /// let wrong = Layout::from_size_align(60, 1).unwrap();
+    /// unsafe { limit.dealloc(ptr, wrong) };
+    /// if cfg!(debug_assertions) {
+    ///     assert_eq!(
+    ///         limit.verify(),
+    ///         Err(AccountingError::LayoutMismatch {
+    ///             address: ptr as usize,
+    ///             allocated: layout,
+    ///             deallocated: wrong,
+    ///         })
+    ///     );
+    /// #   unsafe { System.dealloc(ptr, layout) };
+    /// }
+    /// ```
+    #[cfg(feature = "verify-accounting")]
+    pub fn verify(&self) -> Result<(), AccountingError> {
+        self.tracker.verify(
+            || self.allocated(),
+            self.reserved.load(SeqCst),
+            // Safety: the pointers are live, they cannot be deallocated while the map is locked
+            |address, layout| unsafe { self.allocation_size(address as *mut u8, layout) },
+        )
+    }
+
+    /// Creates a limit that allocates through this one, so every allocation is counted by both
+    /// limits and must fit in both. The child can be exhausted while the parent still has memory
+    /// left, and when the parent is exhausted all the children fail as well. Children can have
+    /// their own children, each level has its own `remaining()`.
+    ///
+    /// The child checks its own limit first, and if the parent rejects the allocation the child
+    /// subtracts it again, so a failure never leaves memory counted by only one of them. In that
+    /// case the child counts a failed allocation, but only the parent calls its OOM handler.
+    ///
+    /// ```
+    /// use limit_alloc::Limit;
+    /// use std::alloc::{GlobalAlloc, Layout, System};
+    ///
+    /// let global = Limit::new(1_000, System);
+    /// let cache = global.child(100);
+    /// let parser = global.child(2_000);
+    ///
+    /// unsafe {
+    ///     // The child is exhausted, but the parent still has room
+    ///     assert!(cache.alloc(Layout::from_size_align(200, 1).unwrap()).is_null());
+    ///     let layout = Layout::from_size_align(1_000, 1).unwrap();
+    ///     let ptr = parser.alloc(layout);
+    ///     assert!(!ptr.is_null());
+    ///     assert_eq!(global.remaining(), 0);
+    ///     // Exhausting the parent blocks all the children
+    ///     assert!(cache.alloc(Layout::from_size_align(1, 1).unwrap()).is_null());
+    ///     parser.dealloc(ptr, layout);
+    /// }
+    /// assert_eq!(global.remaining(), 1_000);
+    /// assert_eq!(cache.remaining(), 100);
+    /// ```
+    pub const fn child(&self, limit: usize) -> ChildLimit<'_, A> {
+        Limit::new(limit, self)
+    }
+
+    /// Returns a reference to the inner allocator. Memory allocated using the inner allocator
+    /// directly is not counted, so it must also be deallocated using the inner allocator.
+    pub fn inner(&self) -> &A {
+        &self.alloc
+    }
+
+    /// Returns the inner allocator. Any memory that is still allocated must be deallocated using
+    /// the inner allocator. The leak check is not done.
+    pub fn into_inner(self) -> A {
+        #[allow(unused_mut)]
+        let mut this = ManuallyDrop::new(self);
+        // Safety: `this` is never used or dropped again. The map of the tracker owns memory, the
+        // other fields do not need to be dropped
+        #[cfg(feature = "debug-tracking")]
+        unsafe {
+            ptr::drop_in_place(&mut this.tracker);
+        }
+        unsafe { ptr::read(&this.alloc) }
+    }
+
+    /// Returns the remaining memory and the inner allocator, for example to create a new `Limit`
+    /// with the budget that is left. Same as `into_inner`, any memory that is still allocated
+    /// must be deallocated using the inner allocator.
+    ///
+    /// ```
+    /// use limit_alloc::Limit;
+    /// use std::alloc::{GlobalAlloc, Layout, System};
+    ///
+    /// let limit = Limit::new(1_000, System);
+    /// let layout = Layout::from_size_align(300, 1).unwrap();
+    /// let ptr = unsafe { limit.alloc(layout) };
+    /// let (remaining, alloc) = limit.into_parts();
+    /// assert_eq!(remaining, 700);
+    /// unsafe { alloc.dealloc(ptr, layout) };
+    /// let limit = Limit::new(remaining, alloc);
+    /// assert_eq!(limit.remaining(), 700);
+    /// ```
+    pub fn into_parts(self) -> (usize, A) {
+        (self.remaining(), self.into_inner())
+    }
+
+    /// Returns None if the memory limit would be exhausted after allocating. Otherwise returns
+    /// the result of the inner allocator, which is null if it failed. Use `try_alloc2` to know
+    /// why the allocation failed.
+    ///
+    /// # Safety
+    ///
+    /// The same restrictions as `GlobalAlloc::alloc`.
+    pub unsafe fn try_alloc(&self, layout: Layout) -> Option<*mut u8> {
+        discard_error(self.try_alloc2(layout))
+    }
+
+    /// Same as `try_alloc`, but returns the reason why the allocation failed. The pointer is
+    /// never null.
+    ///
+    /// ```
+    /// use limit_alloc::{AllocError, Limit};
+    /// use std::alloc::{Layout, System};
+    ///
+    /// let limit = Limit::new(16, System);
+    /// let layout = Layout::new::<[u8; 32]>();
+    /// let err = unsafe { limit.try_alloc2(layout) }.unwrap_err();
+    /// assert_eq!(
+    ///     err,
+    ///     AllocError::LimitExceeded {
+    ///         requested: 32,
+    ///         remaining: 16
+    ///     }
+    /// );
+    /// ```
+    ///
+    /// # Safety
+    ///
+    /// The same restrictions as `GlobalAlloc::alloc`.
+    pub unsafe fn try_alloc2(&self, layout: Layout) -> Result<*mut u8, AllocError> {
+        self.alloc_with(layout, |alloc, layout| alloc.alloc(layout))
+    }
+
+    /// Same as `try_alloc`, but the memory is zeroed. The inner allocator may have a faster way to
+    /// zero the memory, so this calls `alloc_zeroed`.
+    ///
+    /// # Safety
+    ///
+    /// The same restrictions as `GlobalAlloc::alloc_zeroed`.
+    pub unsafe fn try_alloc_zeroed(&self, layout: Layout) -> Option<*mut u8> {
+        discard_error(self.alloc_with(layout, |alloc, layout| alloc.alloc_zeroed(layout)))
+    }
+
+    /// Returns None if the memory limit would be exhausted after growing the allocation, in that
+    /// case the old allocation is still valid. Otherwise returns the result of the inner
+    /// allocator, which is null if it failed. Only the difference between the old and the new
+    /// size is charged or subtracted.
+    ///
+    /// # Safety
+    ///
+    /// The same restrictions as `GlobalAlloc::realloc`.
+    pub unsafe fn try_realloc(
+        &self,
+        ptr: *mut u8,
+        layout: Layout,
+        new_size: usize,
+    ) -> Option<*mut u8> {
+        let new_layout = Layout::from_size_align_unchecked(new_size, layout.align());
+        update_max(&self.largest_request, new_size, Relaxed);
+        let old_counted = self.allocation_size(ptr, layout);
+        let new_counted = self.accounting.size(new_layout);
+        if new_counted > old_counted {
+            // Only the difference needs to be charged, and if that fails the inner allocator is
+            // not called at all
+            let delta = new_counted - old_counted;
+            if !self.fits_single_alloc(new_layout) || !self.charge_alloc(delta) {
+                self.reject(new_layout);
+                return None;
+            }
+            let ret = self.alloc.realloc(ptr, layout, new_size);
+            if ret.is_null() {
+                // The old allocation is still valid, so only subtract the difference
+                self.credit(delta);
+                self.failed_allocations.fetch_add(1, Relaxed);
+            } else {
+                #[cfg(feature = "usable-size")]
+                let delta = self
+                    .adjust(new_counted, self.allocation_size(ret, new_layout))
+                    .saturating_sub(old_counted);
+                self.bytes_allocated_total.fetch_add(delta, Relaxed);
+                update_max(&self.largest_live, new_size, Relaxed);
+                #[cfg(feature = "histogram")]
+                self.histogram.reallocated(layout.size(), new_size);
+                #[cfg(feature = "debug-tracking")]
+                self.tracker.reallocated(ptr, layout, ret, new_layout);
+            }
+            Some(ret)
+        } else {
+            let ret = self.alloc.realloc(ptr, layout, new_size);
+            if ret.is_null() {
+                self.failed_allocations.fetch_add(1, Relaxed);
+            } else {
+                self.credit(old_counted - new_counted);
+                if old_counted != new_counted {
+                    self.notify_freed();
+                }
+                #[cfg(feature = "usable-size")]
+                self.adjust(new_counted, self.allocation_size(ret, new_layout));
+                #[cfg(feature = "histogram")]
+                self.histogram.reallocated(layout.size(), new_size);
+                #[cfg(feature = "debug-tracking")]
+                self.tracker.reallocated(ptr, layout, ret, new_layout);
+            }
+            Some(ret)
+        }
+    }
+
+    /// Safe version of `try_alloc`: returns a guard that owns the allocation and deallocates it
+    /// when dropped. Returns None if the memory limit would be exhausted or if the inner allocator
+    /// failed.
+    ///
+    /// ```
+    /// use limit_alloc::Limit;
+    /// use std::alloc::{Layout, System};
+    /// use std::panic::{self, AssertUnwindSafe};
+    ///
+    /// let limit = Limit::new(1_000, System);
+    /// let layout = Layout::from_size_align(600, 8).unwrap();
+    /// {
+    ///     let guard = limit.alloc_guard(layout).unwrap();
+    ///     assert_eq!(guard.len(), 600);
+    ///     assert_eq!(limit.allocated(), 600);
+    ///     assert!(limit.alloc_guard(layout).is_none());
+    /// }
+    /// assert_eq!(limit.allocated(), 0);
+    ///
+    /// // The memory is also deallocated when a panic unwinds
+    /// let result = panic::catch_unwind(AssertUnwindSafe(|| {
+    ///     let _guard = limit.alloc_guard(layout).unwrap();
+    ///     panic!("oops");
+    /// }));
+    /// assert!(result.is_err());
+    /// assert_eq!(limit.allocated(), 0);
+    ///
+    /// // Unless it is leaked
+    /// let ptr = limit.alloc_guard(layout).unwrap().leak();
+    /// assert_eq!(limit.allocated(), 600);
+    /// unsafe { std::alloc::GlobalAlloc::dealloc(&limit, ptr.as_ptr(), layout) };
+    /// assert_eq!(limit.allocated(), 0);
+    /// ```
+    pub fn alloc_guard(&self, layout: Layout) -> Option<AllocGuard<'_, A>> {
+        AllocGuard::new(self, layout)
+    }
+
+    /// Charge the size of `layout` and allocate using `f`. The counters are restored if `f`
+    /// returns null.
+    unsafe fn alloc_with(
+        &self,
+        layout: Layout,
+        f: impl FnOnce(&A, Layout) -> *mut u8,
+    ) -> Result<*mut u8, AllocError> {
+        if self.is_unlimited() {
+            return self.alloc_unlimited(layout, f);
+        }
+        let size = self.accounting.size(layout);
+        update_max(&self.largest_request, layout.size(), Relaxed);
+        if !self.fits_single_alloc(layout) {
+            self.reject(layout);
+            return Err(AllocError::TooLarge {
+                requested: layout.size(),
+                max: self.max_single_alloc(),
+            });
+        }
+        if !self.charge_alloc(size) {
+            self.reject(layout);
+            return Err(AllocError::LimitExceeded {
+                requested: size,
+                remaining: self.remaining(),
+            });
+        }
+        if !self.charge_count() {
+            self.credit(size);
+            self.reject(layout);
+            return Err(AllocError::TooManyAllocations {
+                max: self.max_live_allocations,
+            });
+        }
+        let ret = f(&self.alloc, layout);
+        if ret.is_null() {
+            // Nothing was actually allocated, so subtract the size
+            self.credit(size);
+            self.credit_count();
+            self.failed_allocations.fetch_add(1, Relaxed);
+            return Err(AllocError::InnerFailed);
+        }
+        #[cfg(feature = "usable-size")]
+        let size = self.adjust(size, self.allocation_size(ret, layout));
+        self.allocations.fetch_add(1, Relaxed);
+        #[cfg(feature = "std")]
+        measure::allocated(self as *const Self as *const ());
+        self.bytes_allocated_total.fetch_add(size, Relaxed);
+        update_max(&self.largest_live, layout.size(), Relaxed);
+        #[cfg(feature = "histogram")]
+        self.histogram.allocated(layout.size());
+        #[cfg(feature = "debug-tracking")]
+        self.tracker.allocated(ret, layout, size);
+
+        Ok(ret)
+    }
+
+    /// Same as `alloc_with`, for a `Limit` without a limit, see `is_unlimited`. The allocated
+    /// memory does not need a compare and swap loop, and the live allocations are not counted.
+    unsafe fn alloc_unlimited(
+        &self,
+        layout: Layout,
+        f: impl FnOnce(&A, Layout) -> *mut u8,
+    ) -> Result<*mut u8, AllocError> {
+        let size = self.accounting.size(layout);
+        update_max(&self.largest_request, layout.size(), Relaxed);
+        if !self.fits_single_alloc(layout) {
+            self.reject(layout);
+            return Err(AllocError::TooLarge {
+                requested: layout.size(),
+                max: self.max_single_alloc(),
+            });
+        }
+        // Without a limit this only fails if the counter would overflow
+        if !self.charge(size) {
+            self.reject(layout);
+            return Err(AllocError::LimitExceeded {
+                requested: size,
+                remaining: self.remaining(),
+            });
+        }
+        let ret = f(&self.alloc, layout);
+        if ret.is_null() {
+            self.credit(size);
+            self.failed_allocations.fetch_add(1, Relaxed);
+            return Err(AllocError::InnerFailed);
+        }
+        self.allocations.fetch_add(1, Relaxed);
+        #[cfg(feature = "std")]
+        measure::allocated(self as *const Self as *const ());
+        self.bytes_allocated_total.fetch_add(size, Relaxed);
+        update_max(&self.largest_live, layout.size(), Relaxed);
+
+        Ok(ret)
+    }
+
+    /// Returns true if there is no limit and no option that needs more work per allocation is
+    /// enabled, then allocations use `alloc_unlimited`, see `Limit::unlimited`.
+    fn is_unlimited(&self) -> bool {
+        #[cfg(feature = "usable-size")]
+        if self.usable_size {
+            return false;
+        }
+        !cfg!(any(feature = "debug-tracking", feature = "histogram"))
+            && self.max_live_allocations == usize::MAX
+            && self.limit.load(Relaxed) == usize::MAX
+    }
+
+    /// Returns remaining memory in bytes. This value does not guarantee that an allocation of x
+    /// bytes will succeed.
+    pub fn remaining(&self) -> usize {
+        // Saturate in case the limit was set below the allocated memory
+        self.limit().saturating_sub(self.allocated())
+    }
+
+    /// Returns true if `remaining() >= bytes`, which reads better than comparing the remaining
+    /// memory with a threshold. Same as `try_reserve`, this is only advisory.
+    ///
+    /// ```
+    /// use limit_alloc::Limit;
+    /// use std::alloc::{GlobalAlloc, Layout, System};
+    ///
+    /// const BATCH: usize = 400;
+    ///
+    /// let limit = Limit::new(1_000, System);
+    /// let layout = Layout::from_size_align(BATCH, 1).unwrap();
+    /// let mut batches = Vec::new();
+    /// while limit.has_headroom(BATCH) {
+    ///     batches.push(unsafe { limit.alloc(layout) });
+    /// }
+    /// assert_eq!(batches.len(), 2);
+    /// # for ptr in batches { unsafe { limit.dealloc(ptr, layout) } }
+    /// ```
+    pub fn has_headroom(&self, bytes: usize) -> bool {
+        self.remaining() >= bytes
+    }
+
+    /// Returns true if an allocation of `bytes` would fit in the remaining memory right now,
+    /// without allocating anything. Useful to fail early before building a big data structure.
+    ///
+    /// This is only advisory: other threads may allocate or deallocate memory before the actual
+    /// allocation happens, so a later allocation of `bytes` may still fail, and the inner
+    /// allocator may fail even when there is enough memory left. Use `reserve` to make sure that
+    /// the memory stays available.
+    pub fn try_reserve(&self, bytes: usize) -> bool {
+        add_within_limit(self.allocated(), bytes, self.limit()).is_some()
+    }
+
+    /// Reserves `bytes` of the remaining memory, so that other allocations cannot use them until
+    /// the returned `Reservation` is dropped. Allocations made through the `Reservation` use the
+    /// reserved bytes. Returns None if there is not enough remaining memory, in that case nothing
+    /// is reserved.
+    ///
+    /// Unlike `try_reserve`, this guarantees that the memory will be available.
+    ///
+    /// ```
+    /// use limit_alloc::Limit;
+    /// use std::alloc::{GlobalAlloc, Layout, System};
+    ///
+    /// let limit = Limit::new(1_000, System);
+    /// let reservation = limit.reserve(600).unwrap();
+    /// assert_eq!(limit.remaining(), 400);
+    ///
+    /// let layout = Layout::from_size_align(500, 1).unwrap();
+    /// unsafe {
+    ///     // Other allocations cannot use the reserved memory
+    ///     assert!(limit.alloc(layout).is_null());
+    ///     // But allocations through the reservation can
+    ///     let ptr = reservation.alloc(layout);
+    ///     assert!(!ptr.is_null());
+    ///     assert_eq!(reservation.remaining(), 100);
+    ///     reservation.dealloc(ptr, layout);
+    /// }
+    /// drop(reservation);
+    /// assert_eq!(limit.remaining(), 1_000);
+    /// ```
+    pub fn reserve(&self, bytes: usize) -> Option<Reservation<'_, A>> {
+        if self.charge(bytes) {
+            Some(Reservation::new(self, bytes))
+        } else {
+            None
+        }
+    }
+
+    /// Returns the memory that is currently allocated, in bytes.
+    pub fn allocated(&self) -> usize {
+        self.allocated.load(self.ordering.load())
+    }
+
+    /// Same as `allocated`. As long as the limit is not set below the allocated memory,
+    /// `used() + remaining() == limit()`.
+    pub fn used(&self) -> usize {
+        self.allocated()
+    }
+
+    /// Returns the memory limit in bytes.
+    pub fn limit(&self) -> usize {
+        self.limit.load(self.ordering.load())
+    }
+
+    /// Returns how many bytes the allocated memory is over the limit, or 0 if it is not. This can
+    /// happen after lowering the limit using `set_limit`.
+    pub fn overcommitted(&self) -> usize {
+        self.allocated().saturating_sub(self.limit())
+    }
+
+    /// Returns the allocated memory as a fraction of the limit, between 0.0 and 1.0. It is 1.0 if
+    /// the allocated memory is above the limit, or if the limit is 0.
+    ///
+    /// ```
+    /// use limit_alloc::Limit;
+    /// use std::alloc::{GlobalAlloc, Layout, System};
+    ///
+    /// let limit = Limit::new(1_000, System);
+    /// let layout = Layout::from_size_align(250, 1).unwrap();
+    /// let ptr = unsafe { limit.alloc(layout) };
+    /// assert_eq!(limit.usage_ratio(), 0.25);
+    /// assert!(limit.is_above(0.2));
+    /// assert!(!limit.is_above(0.8));
+    /// # unsafe { limit.dealloc(ptr, layout) };
+    /// ```
+    pub fn usage_ratio(&self) -> f64 {
+        let limit = self.limit();
+        if limit == 0 {
+            return 1.0;
+        }
+        (self.allocated() as f64 / limit as f64).min(1.0)
+    }
+
+    /// Returns true if `usage_ratio` is above `ratio`, for example a cache can evict entries
+    /// while `limit.is_above(0.8)`.
+    pub fn is_above(&self, ratio: f64) -> bool {
+        self.usage_ratio() > ratio
+    }
+
+    /// Changes the memory limit. Memory that is already allocated is still counted, so if the new
+    /// limit is lower than `allocated()`, `remaining()` will be 0 and allocations will fail until
+    /// enough memory is deallocated.
+    ///
+    /// This does not free any memory, it only changes whether future allocations will succeed.
+    /// `usize::MAX` means no limit, see `unlimited`. To change the limit relative to its current
+    /// value without losing concurrent changes, use `grow_limit` and `shrink_limit`.
+    pub fn set_limit(&self, new_limit: usize) {
+        self.limit.store(new_limit, self.ordering.store());
+    }
+
+    /// Alias of `grow_limit`, hidden from the docs so each operation has one name.
+    #[doc(hidden)]
+    #[inline]
+    pub fn increase_limit(&self, by: usize) {
+        self.grow_limit(by)
+    }
+
+    /// Alias of `shrink_limit`, hidden from the docs so each operation has one name.
+    #[doc(hidden)]
+    #[inline]
+    pub fn decrease_limit(&self, by: usize) {
+        self.shrink_limit(by)
+    }
+
+    /// Increases the memory limit by `bytes`, saturating at `usize::MAX`. `increase_limit` is an
+    /// alias of this method.
+    #[doc(alias = "increase_limit")]
+    pub fn grow_limit(&self, bytes: usize) {
+        let _ = self
+            .limit
+            .fetch_update(self.ordering.rmw(), self.ordering.load(), |old| {
+                Some(old.saturating_add(bytes))
+            });
+    }
+
+    /// Decreases the memory limit by `bytes`, saturating at 0. Same as `set_limit`, the new limit
+    /// can be lower than the allocated memory, in that case allocations will fail until enough
+    /// memory is deallocated. `remaining` is computed from the limit, so it is 0 until then.
+    /// `decrease_limit` is an alias of this method.
+    ///
+    /// ```
+    /// use limit_alloc::Limit;
+    /// use std::alloc::{GlobalAlloc, Layout, System};
+    ///
+    /// let limit = Limit::new(1_000, System);
+    /// let layout = Layout::from_size_align(600, 1).unwrap();
+    /// let ptr = unsafe { limit.alloc(layout) };
+    /// limit.shrink_limit(500);
+    /// assert_eq!(limit.limit(), 500);
+    /// assert_eq!(limit.remaining(), 0);
+    /// assert!(unsafe { limit.try_alloc(Layout::new::<u8>()) }.is_none());
+    /// limit.grow_limit(200);
+    /// assert_eq!(limit.remaining(), 100);
+    /// limit.shrink_limit(usize::MAX);
+    /// assert_eq!(limit.limit(), 0);
+    /// unsafe { limit.dealloc(ptr, layout) };
+    /// assert_eq!(limit.remaining(), 0);
+    /// ```
+    #[doc(alias = "decrease_limit")]
+    pub fn shrink_limit(&self, bytes: usize) {
+        let _ = self
+            .limit
+            .fetch_update(self.ordering.rmw(), self.ordering.load(), |old| {
+                Some(old.saturating_sub(bytes))
+            });
+    }
+
+    /// Atomically sets the limit to 0, so every allocation fails while deallocations still work,
+    /// for example during a graceful shutdown. Returns the old limit, which can be given back
+    /// using `thaw`. This is the whole limit and not only the remaining memory, because the
+    /// memory that is still allocated stays counted while frozen. Unlike `set_limit(0)` the
+    /// caller does not need to read the limit first, so a concurrent change of the limit is never
+    /// lost.
+    ///
+    /// ```
+    /// use limit_alloc::Limit;
+    /// use std::alloc::{GlobalAlloc, Layout, System};
+    ///
+    /// let limit = Limit::new(1_000, System);
+    /// let layout = Layout::from_size_align(100, 1).unwrap();
+    /// let ptr = unsafe { limit.alloc(layout) };
+    /// let frozen = limit.freeze();
+    /// assert_eq!(frozen, 1_000);
+    /// assert!(unsafe { limit.try_alloc(layout) }.is_none());
+    /// // Deallocating does not make room while frozen
+    /// unsafe { limit.dealloc(ptr, layout) };
+    /// assert_eq!(limit.remaining(), 0);
+    /// limit.thaw(frozen);
+    /// assert_eq!(limit.remaining(), 1_000);
+    /// ```
+    pub fn freeze(&self) -> usize {
+        self.limit.swap(0, self.ordering.rmw())
+    }
+
+    /// Gives back `amount` bytes of limit removed by `freeze`, this is the same as `grow_limit`.
+    /// Calling `freeze` again while frozen returns 0, so only the first amount needs to be given
+    /// back.
+    ///
+    /// `amount` is added to the current limit instead of replacing it, so a limit set using
+    /// `set_limit` while frozen is not kept: the limit becomes that value plus `amount`. To choose
+    /// a new limit while frozen, call `set_limit` instead of `thaw`.
+    ///
+    /// ```
+    /// use limit_alloc::Limit;
+    /// use std::alloc::System;
+    ///
+    /// let limit = Limit::new(1_000, System);
+    /// let frozen = limit.freeze();
+    /// limit.set_limit(500);
+    /// limit.thaw(frozen);
+    /// assert_eq!(limit.limit(), 1_500);
+    /// ```
+    pub fn thaw(&self, amount: usize) {
+        self.grow_limit(amount);
+    }
+
+    /// Increases the memory limit by `extra` until the returned guard is dropped, which can be
+    /// used to allow more memory during a short phase of the program. The limit is restored even
+    /// if that phase panics.
+    ///
+    /// The guard only subtracts `extra` again using `shrink_limit`, so if the limit is changed
+    /// while the guard is alive, that change is kept.
+    ///
+    /// ```
+    /// use limit_alloc::Limit;
+    /// use std::alloc::System;
+    ///
+    /// let limit = Limit::new(1_000, System);
+    /// {
+    ///     let _guard = limit.scoped_raise(500);
+    ///     assert_eq!(limit.limit(), 1_500);
+    /// }
+    /// assert_eq!(limit.limit(), 1_000);
+    /// ```
+    pub fn scoped_raise(&self, extra: usize) -> RaiseGuard<'_, A> {
+        // Same as grow_limit, but the guard must only subtract what was actually added
+        let old = self
+            .limit
+            .fetch_update(self.ordering.rmw(), self.ordering.load(), |old| {
+                Some(old.saturating_add(extra))
+            })
+            .unwrap();
+        RaiseGuard {
+            limit: self,
+            extra: old.saturating_add(extra) - old,
+        }
+    }
+
+    /// Lowers the limit so that at most `budget` more bytes can be allocated until the returned
+    /// guard is dropped. Useful in tests, to check that a block of code does not use too much
+    /// memory. If the remaining memory is already lower than `budget`, the limit is not changed.
+    ///
+    /// When the guard is dropped, also when unwinding, the limit is increased again by the same
+    /// amount, and memory allocated inside the scope is still counted. Guards can be nested. The
+    /// limit is shared by all the threads, so other threads are also constrained while the guard
+    /// is alive.
+    ///
+    /// ```
+    /// use limit_alloc::Limit;
+    /// use std::alloc::{GlobalAlloc, Layout, System};
+    ///
+    /// let limit = Limit::new(1_000, System);
+    /// let layout = Layout::from_size_align(100, 1).unwrap();
+    /// unsafe {
+    ///     let outer = limit.constrain_scope(300);
+    ///     let a = limit.alloc(layout);
+    ///     {
+    ///         let _inner = limit.constrain_scope(100);
+    ///         let b = limit.alloc(layout);
+    ///         assert!(!b.is_null());
+    ///         assert!(limit.alloc(layout).is_null());
+    ///         limit.dealloc(b, layout);
+    ///     }
+    ///     assert_eq!(limit.remaining(), 200);
+    ///     drop(outer);
+    ///     assert_eq!(limit.remaining(), 900);
+    ///     limit.dealloc(a, layout);
+    /// }
+    /// assert_eq!(limit.limit(), 1_000);
+    /// ```
+    pub fn constrain_scope(&self, budget: usize) -> ConstrainGuard<'_, A> {
+        let allocated = self.allocated();
+        let old = self
+            .limit
+            .fetch_update(self.ordering.rmw(), self.ordering.load(), |old| {
+                Some(old.min(allocated.saturating_add(budget)))
+            })
+            .unwrap();
+        ConstrainGuard {
+            limit: self,
+            removed: old - old.min(allocated.saturating_add(budget)),
+        }
+    }
+
+    /// Sets the maximum size of a single allocation in bytes, allocations larger than that will
+    /// fail even if there is enough remaining memory. This also applies to `realloc` when growing
+    /// an allocation above that size. Rejected allocations call the OOM handler. By default there
+    /// is no maximum, `usize::MAX`.
+    ///
+    /// ```
+    /// use limit_alloc::Limit;
+    /// use std::alloc::{GlobalAlloc, Layout, System};
+    ///
+    /// let limit = Limit::new(1_000, System);
+    /// limit.set_max_single_alloc(100);
+    ///
+    /// unsafe {
+    ///     assert!(limit.alloc(Layout::from_size_align(101, 1).unwrap()).is_null());
+    ///     assert_eq!(limit.allocated(), 0);
+    ///     let layout = Layout::from_size_align(100, 1).unwrap();
+    ///     let ptr = limit.alloc(layout);
+    ///     assert!(!ptr.is_null());
+    ///     limit.dealloc(ptr, layout);
+    /// }
+    /// ```
+    pub fn set_max_single_alloc(&self, bytes: usize) {
+        self.max_single_alloc.store(bytes, SeqCst);
+    }
+
+    /// Returns the maximum size of a single allocation, see `set_max_single_alloc`.
+    pub fn max_single_alloc(&self) -> usize {
+        self.max_single_alloc.load(SeqCst)
+    }
+
+    /// Returns the highest number of bytes that were allocated at the same time, since the
+    /// allocator was created or since the last call to `reset_peak`.
+    pub fn peak(&self) -> usize {
+        self.peak.load(self.ordering.load())
+    }
+
+    /// Returns the number of successful allocations. Comparing this with `dealloc_count` can help
+    /// to detect memory leaks. Not updated without a limit, see `unlimited`.
+    pub fn alloc_count(&self) -> usize {
+        self.allocations.load(Relaxed)
+    }
+
+    /// Returns the number of deallocations. Not updated without a limit, see `unlimited`.
+    pub fn dealloc_count(&self) -> usize {
+        self.deallocations.load(Relaxed)
+    }
+
+    /// Returns the number of allocations that failed, either because of the limit or because the
+    /// inner allocator returned null.
+    pub fn failed_allocations(&self) -> usize {
+        self.failed_allocations.load(Relaxed)
+    }
+
+    /// Returns the number of allocations that failed because of the limit, not counting the
+    /// ones where the inner allocator returned null. This includes the allocations rejected
+    /// because of `set_max_single_alloc` or `with_counts`.
+    pub fn rejected_allocations(&self) -> usize {
+        self.rejected_allocations.load(Relaxed)
+    }
+
+    /// Returns true if any allocation was rejected because of the limit since the allocator was
+    /// created or since the last call to `clear_exhausted`. Useful in tests to check that a
+    /// workload never reached the limit.
+    ///
+    /// ```
+    /// use limit_alloc::Limit;
+    /// use std::alloc::{GlobalAlloc, Layout, System};
+    ///
+    /// let limit = Limit::new(100, System);
+    /// let layout = Layout::from_size_align(60, 1).unwrap();
+    /// unsafe {
+    ///     let ptr = limit.alloc(layout);
+    ///     assert!(!limit.is_exhausted());
+    ///     assert!(limit.alloc(layout).is_null());
+    ///     assert!(limit.is_exhausted());
+    ///     assert_eq!(limit.rejected_allocations(), 1);
+    ///     limit.dealloc(ptr, layout);
+    /// }
+    /// limit.clear_exhausted();
+    /// assert!(!limit.is_exhausted());
+    /// ```
+    pub fn is_exhausted(&self) -> bool {
+        self.exhausted.load(Relaxed)
+    }
+
+    /// Resets the flag returned by `is_exhausted`. The counters are not modified.
+    pub fn clear_exhausted(&self) {
+        self.exhausted.store(false, Relaxed);
+    }
+
+    /// Returns the largest size ever passed to `alloc`, `alloc_zeroed` or `realloc`, including
+    /// the allocations that failed. Together with `largest_live`, this helps to tell whether the
+    /// limit was exhausted by one big allocation or by many small ones.
+    ///
+    /// ```
+    /// use limit_alloc::Limit;
+    /// use std::alloc::{GlobalAlloc, Layout, System};
+    ///
+    /// let limit = Limit::new(1_000, System);
+    /// unsafe {
+    ///     let small = Layout::from_size_align(10, 1).unwrap();
+    ///     let ptr = limit.alloc(small);
+    ///     assert!(limit.alloc(Layout::from_size_align(5_000, 1).unwrap()).is_null());
+    ///     let ptr = limit.realloc(ptr, small, 300);
+    ///     limit.dealloc(ptr, Layout::from_size_align(300, 1).unwrap());
+    /// }
+    /// assert_eq!(limit.largest_request(), 5_000);
+    /// assert_eq!(limit.largest_live(), 300);
+    /// ```
+    pub fn largest_request(&self) -> usize {
+        self.largest_request.load(Relaxed)
+    }
+
+    /// Returns the size of the largest successful allocation. This is an approximation of the
+    /// largest live allocation: it is not decreased when that allocation is deallocated, so
+    /// it may be bigger than any allocation that is live now.
+    pub fn largest_live(&self) -> usize {
+        self.largest_live.load(Relaxed)
+    }
+
+    /// Returns the number of allocations in each size class, the size classes are powers of two.
+    /// Requires the `histogram` feature.
+    ///
+    /// ```
+    /// use limit_alloc::Limit;
+    /// use std::alloc::{GlobalAlloc, Layout, System};
+    ///
+    /// let limit = Limit::new(1_000, System);
+    /// unsafe {
+    ///     let layout = Layout::from_size_align(20, 1).unwrap();
+    ///     let a = limit.alloc(layout);
+    ///     let b = limit.alloc(layout);
+    ///     limit.dealloc(a, layout);
+    ///     let histogram = limit.size_histogram();
+    ///     let class = histogram.class_of(20);
+    ///     assert_eq!((class.max_size, class.total, class.live), (32, 2, 1));
+    ///     assert_eq!(histogram.to_string(), "<= 32 B: 2 total, 1 live\n");
+    ///     limit.dealloc(b, layout);
+    /// }
+    /// ```
+    #[cfg(feature = "histogram")]
+    pub fn size_histogram(&self) -> SizeHistogram {
+        self.histogram.snapshot()
+    }
+
+    /// Returns a snapshot of all the statistics.
+    ///
+    /// The snapshot is not atomic: each value is read separately, so if other threads are
+    /// allocating at the same time the values may not be consistent with each other. The limit
+    /// and the allocated memory are read only once, so `remaining` is always computed from those
+    /// two values. The counters use relaxed ordering, so they may lag behind the allocated memory.
+    pub fn stats(&self) -> Stats {
+        let limit = self.limit();
+        let allocated = self.allocated();
+        Stats {
+            limit,
+            remaining: limit.saturating_sub(allocated),
+            allocated,
+            peak: self.peak(),
+            alloc_count: self.alloc_count(),
+            dealloc_count: self.dealloc_count(),
+            failed: self.failed_allocations(),
+            rejected: self.rejected_allocations(),
+            bytes_allocated_total: self.bytes_allocated_total.load(Relaxed),
+            largest_request: self.largest_request(),
+            largest_live: self.largest_live(),
+        }
+    }
+
+    /// Registers gauges and counters for the statistics of this limit in the `metrics` recorder,
+    /// with names starting with `prefix`. The metrics are updated when `LimitMetrics::flush` is
+    /// called.
+    ///
+    /// ```
+    /// use limit_alloc::Limit;
+    /// use std::alloc::System;
+    ///
+    /// static A: Limit<System> = Limit::new(1_000_000, System);
+    ///
+    /// let metrics = A.install_metrics("myapp_heap");
+    /// std::thread::spawn(move || loop {
+    ///     metrics.flush();
+    ///     std::thread::sleep(std::time::Duration::from_secs(10));
+    /// });
+    /// ```
+    #[cfg(feature = "metrics")]
+    pub fn install_metrics(&self, prefix: &str) -> LimitMetrics<'_, A> {
+        LimitMetrics::new(self, prefix)
+    }
+
+    /// Sets the peak back to the memory that is allocated right now. Useful to measure the peak
+    /// memory usage of different parts of the program.
+    pub fn reset_peak(&self) {
+        self.peak.store(self.allocated(), self.ordering.store());
+    }
+
+    /// Sets a function that will be called every time an allocation fails because it would exceed
+    /// the limit. The function receives the layout of the failed allocation and the remaining
+    /// memory. This can be used to log the error before the null pointer is returned.
+    ///
+    /// The handler runs inside the allocator, so it must not allocate memory: if that allocation
+    /// also fails the handler would be called recursively.
+    pub fn set_oom_handler(&self, f: fn(Layout, usize)) {
+        self.oom_handler.store(f as *mut (), SeqCst);
+    }
+
+    /// Removes the function set by `set_oom_handler`.
+    pub fn remove_oom_handler(&self) {
+        self.oom_handler.store(ptr::null_mut(), SeqCst);
+    }
+
+    /// Sets a function that will be called every time memory is deallocated, after the inner
+    /// allocator frees it and the counters are updated. The function receives the layout of the
+    /// deallocated memory. Allocations moved by `realloc` do not call it. This can be used
+    /// together with the statistics to trace allocations outside of this crate.
+    ///
+    /// Same as the OOM handler, the hook runs inside the allocator, so it must not allocate
+    /// memory: when this `Limit` is the global allocator, freeing that memory would call the hook
+    /// recursively.
+    ///
+    /// ```
+    /// use limit_alloc::Limit;
+    /// use std::alloc::{GlobalAlloc, Layout, System};
+    /// use std::sync::atomic::{AtomicUsize, Ordering};
+    ///
+    /// static FREED: AtomicUsize = AtomicUsize::new(0);
+    ///
+    /// let limit = Limit::new(1_000, System);
+    /// limit.set_dealloc_hook(|layout| {
+    ///     FREED.fetch_add(layout.size(), Ordering::Relaxed);
+    /// });
+    /// let layout = Layout::from_size_align(100, 1).unwrap();
+    /// unsafe { limit.dealloc(limit.alloc(layout), layout) };
+    /// assert_eq!(FREED.load(Ordering::Relaxed), 100);
+    /// ```
+    pub fn set_dealloc_hook(&self, f: fn(Layout)) {
+        self.dealloc_hook.store(f as *mut (), SeqCst);
+    }
+
+    /// Removes the function set by `set_dealloc_hook`.
+    pub fn remove_dealloc_hook(&self) {
+        self.dealloc_hook.store(ptr::null_mut(), SeqCst);
+    }
+
+    /// Sets a function that will be called every time memory is returned to the limit: after a
+    /// deallocation, after `realloc` shrinks an allocation, and when a `Reservation` with unused
+    /// bytes is dropped. The function receives the remaining memory after the memory was returned,
+    /// so it can wake up a task that waits for memory to be available.
+    ///
+    /// The function runs inside the allocator. If it deallocates memory through this `Limit`,
+    /// for example when this `Limit` is the global allocator, the function is not called again for
+    /// that deallocation, because a thread local flag is set while it runs. Same as the OOM
+    /// handler, it should still avoid allocating.
+    ///
+    /// ```
+    /// use limit_alloc::Limit;
+    /// use std::alloc::{GlobalAlloc, Layout, System};
+    /// use std::sync::atomic::{AtomicUsize, Ordering};
+    ///
+    /// static REMAINING: AtomicUsize = AtomicUsize::new(0);
+    ///
+    /// let limit = Limit::new(1_000, System);
+    /// limit.set_free_notify(|remaining| REMAINING.store(remaining, Ordering::Relaxed));
+    /// let layout = Layout::from_size_align(100, 1).unwrap();
+    /// unsafe {
+    ///     let a = limit.alloc(layout);
+    ///     let b = limit.alloc(layout);
+    ///     limit.dealloc(a, layout);
+    ///     assert_eq!(REMAINING.load(Ordering::Relaxed), 900);
+    ///     let b = limit.realloc(b, layout, 40);
+    ///     assert_eq!(REMAINING.load(Ordering::Relaxed), 960);
+    ///     limit.dealloc(b, Layout::from_size_align(40, 1).unwrap());
+    /// }
+    /// assert_eq!(REMAINING.load(Ordering::Relaxed), 1_000);
+    /// ```
+    #[cfg(feature = "std")]
+    pub fn set_free_notify(&self, f: fn(usize)) {
+        self.free_notify.store(f as *mut (), SeqCst);
+    }
+
+    /// Removes the function set by `set_free_notify`.
+    #[cfg(feature = "std")]
+    pub fn remove_free_notify(&self) {
+        self.free_notify.store(ptr::null_mut(), SeqCst);
+    }
+
+    /// Calls the function set by `set_free_notify`, if any.
+    fn notify_freed(&self) {
+        #[cfg(feature = "std")]
+        {
+            let f = self.free_notify.load(SeqCst);
+            if !f.is_null() {
+                // Safety: the only non-null values stored in free_notify are fn(usize)
+                let f: fn(usize) = unsafe { mem::transmute(f) };
+                free_notify::notify(|| f(self.remaining()));
+            }
+        }
+    }
+
+    /// Checks for leaks when this `Limit` is dropped: if some memory is still allocated, `report`
+    /// is called with the number of leaked bytes, or if `report` is None, the drop panics. This is
+    /// useful for limits that are dropped, for example an `ArcLimit` used by a single task or a
+    /// `Limit` used through the `Allocator` trait, a global allocator is never dropped.
+    ///
+    /// ```
+    /// use limit_alloc::Limit;
+    /// use std::alloc::{GlobalAlloc, Layout, System};
+    /// use std::sync::atomic::{AtomicUsize, Ordering};
+    ///
+    /// static LEAKED: AtomicUsize = AtomicUsize::new(0);
+    ///
+    /// let limit = Limit::new(1_000, System);
+    /// limit.set_leak_check(Some(|bytes| LEAKED.store(bytes, Ordering::SeqCst)));
+    /// let layout = Layout::from_size_align(100, 1).unwrap();
+    /// let ptr = unsafe { limit.alloc(layout) };
+    /// drop(limit);
+    /// assert_eq!(LEAKED.load(Ordering::SeqCst), 100);
+    /// # unsafe { System.dealloc(ptr, layout) };
+    /// ```
+    pub fn set_leak_check(&self, report: Option<fn(usize)>) {
+        self.leak_report
+            .store(report.map_or(ptr::null_mut(), |f| f as *mut ()), SeqCst);
+        self.leak_check.store(true, SeqCst);
+    }
+
+    /// Panics if any memory is still allocated, with the number of leaked bytes.
+    ///
+    /// ```should_panic
+    /// use limit_alloc::Limit;
+    /// use std::alloc::{GlobalAlloc, Layout, System};
+    ///
+    /// let limit = Limit::new(1_000, System);
+    /// let _leaked = unsafe { limit.alloc(Layout::from_size_align(100, 1).unwrap()) };
+    /// limit.assert_no_leaks();
+    /// ```
+    #[track_caller]
+    pub fn assert_no_leaks(&self) {
+        let allocated = self.allocated();
+        assert!(
+            allocated == 0,
+            "memory leak: {} bytes still allocated",
+            allocated
+        );
+    }
+
+    /// Records the deallocations that subtract more bytes than are allocated, so that
+    /// `assert_accounting` panics. This happens when memory that was not allocated through this
+    /// `Limit` is deallocated through it, for example a pointer allocated before the limit was
+    /// installed. By default the allocated memory saturates at 0, so the remaining memory can never
+    /// grow above the limit, but the bug goes unnoticed. This is meant to be enabled in tests.
+    ///
+    /// The deallocation itself does not panic, because a global allocator must not unwind.
+    ///
+    /// ```should_panic
+    /// use limit_alloc::Limit;
+    /// use std::alloc::{GlobalAlloc, Layout, System};
+    ///
+    /// let limit = Limit::new(1_000, System);
+    /// limit.set_strict_accounting(true);
+    /// let layout = Layout::from_size_align(100, 1).unwrap();
+    /// // Allocated without the limit, so this is not counted
+    /// let foreign = unsafe { System.alloc(layout) };
+    /// unsafe { limit.dealloc(foreign, layout) };
+    /// assert_eq!(limit.remaining(), 1_000);
+    /// limit.assert_accounting();
+    /// ```
+    ///
+    /// Without strict accounting the free is ignored, unless the `debug-tracking` feature is
+    /// enabled, which also reports it:
+    ///
+    /// ```
+    /// use limit_alloc::Limit;
+    /// use std::alloc::{GlobalAlloc, Layout, System};
+    ///
+    /// let limit = Limit::new(1_000, System);
+    /// let layout = Layout::from_size_align(100, 1).unwrap();
+    /// # #[cfg(not(feature = "debug-tracking"))]
+    /// unsafe {
+    ///     let ptr = limit.alloc(layout);
+    ///     let foreign = System.alloc(layout);
+    ///     limit.dealloc(foreign, layout);
+    ///     assert_eq!(limit.remaining(), 1_000);
+    ///     limit.dealloc(ptr, layout);
+    /// }
+    /// assert_eq!(limit.remaining(), 1_000);
+    /// ```
+    pub fn set_strict_accounting(&self, strict: bool) {
+        self.strict_accounting.store(strict, SeqCst);
+    }
+
+    /// Panics if a deallocation subtracted more bytes than were allocated while strict
+    /// accounting was enabled, with the number of extra bytes. See `set_strict_accounting`.
+    ///
+    /// With the `debug-tracking` feature, this also panics if a pointer was deallocated with a
+    /// different layout than it was allocated with, or if it was not allocated by this `Limit`.
+    /// Those pointers are not deallocated, because passing them to the inner allocator could be
+    /// a double free, so their memory is leaked and stays counted.
+    ///
+    /// ```
+    /// use limit_alloc::Limit;
+    /// use std::alloc::{GlobalAlloc, Layout, System};
+    /// use std::panic;
+    ///
+    /// let limit = Limit::new(1_000, System);
+    /// let layout = Layout::from_size_align(100, 1).unwrap();
+    /// let ptr = unsafe { limit.alloc(layout) };
+    /// let wrong = Layout::from_size_align(60, 1).unwrap();
+    /// unsafe { limit.dealloc(ptr, wrong) };
+    /// if cfg!(all(feature = "debug-tracking", debug_assertions)) {
+    ///     assert_eq!(limit.allocated(), 100);
+    ///     assert!(panic::catch_unwind(|| limit.assert_accounting()).is_err());
+    /// #   unsafe { System.dealloc(ptr, layout) };
+    /// }
+    /// ```
+    #[track_caller]
+    pub fn assert_accounting(&self) {
+        #[cfg(feature = "debug-tracking")]
+        self.tracker.assert_no_errors();
+        let over = self.over_credited.load(Relaxed);
+        assert!(
+            over == 0,
+            "deallocated {} bytes more than were allocated",
+            over
+        );
+    }
+
+    /// Sets what happens to allocations that would exceed the limit. With `Overcommit::Allow`
+    /// they succeed anyway, for example for best effort background work where failing is worse
+    /// than using too much memory. The allocated memory is then above the limit, see
+    /// `overcommitted`, and other allocations keep failing until it goes below the limit again if
+    /// the policy is changed back to `Overcommit::Reject`. `reserve` always fails if the memory is
+    /// not available.
+    ///
+    /// ```
+    /// use limit_alloc::{Limit, Overcommit};
+    /// use std::alloc::{GlobalAlloc, Layout, System};
+    ///
+    /// let limit = Limit::new(1_000, System);
+    /// limit.set_overcommit(Overcommit::Allow);
+    /// let layout = Layout::from_size_align(600, 1).unwrap();
+    /// unsafe {
+    ///     let a = limit.alloc(layout);
+    ///     let b = limit.alloc(layout);
+    ///     assert!(!b.is_null());
+    ///     assert_eq!(limit.overcommitted(), 200);
+    ///     assert_eq!(limit.overcommitted_bytes(), 200);
+    ///     limit.set_overcommit(Overcommit::Reject);
+    ///     assert!(limit.alloc(layout).is_null());
+    ///     limit.dealloc(a, layout);
+    ///     limit.dealloc(b, layout);
+    /// }
+    /// ```
+    pub fn set_overcommit(&self, policy: Overcommit) {
+        self.overcommit.store(policy == Overcommit::Allow, SeqCst);
+    }
+
+    /// Returns the policy set by `set_overcommit`.
+    pub fn overcommit(&self) -> Overcommit {
+        if self.overcommit.load(SeqCst) {
+            Overcommit::Allow
+        } else {
+            Overcommit::Reject
+        }
+    }
+
+    /// Returns the total number of bytes above the limit that were allocated because of
+    /// `Overcommit::Allow`. This only increases, unlike `overcommitted` which is the memory above
+    /// the limit right now.
+    pub fn overcommitted_bytes(&self) -> usize {
+        self.overcommitted_bytes.load(Relaxed)
+    }
+
+    /// Sets functions that will be called when the allocated memory crosses a threshold, this can
+    /// be used to start freeing memory before allocations start to fail. Each element is a
+    /// threshold in bytes and the function to call, which receives the allocated memory and the
+    /// limit. Replaces any previously set watermarks, at most `MAX_WATERMARKS` are supported.
+    ///
+    /// Each function is called once when the allocated memory reaches its threshold, and it will
+    /// not be called again until the allocated memory drops below the threshold minus 1/16 of the
+    /// threshold. Same as the OOM handler, these functions run inside the allocator so they must
+    /// not allocate memory.
+    ///
+    /// # Panics
+    ///
+    /// If more than `MAX_WATERMARKS` watermarks are passed, or if a threshold is 0. A threshold
+    /// of 0 is always reached, so it could never be re-armed.
+    pub fn set_watermarks(&self, watermarks: &[(usize, WatermarkCallback)]) {
+        self.watermarks.set(watermarks);
+    }
+
+    /// Sets a soft limit in bytes. Unlike the limit, the soft limit never makes allocations fail:
+    /// while the allocated memory is above it, `is_over_soft_limit` returns true, and `hook` is
+    /// called every time that the allocated memory goes above it. The hook receives the allocated
+    /// memory and the soft limit. Same as the OOM handler, the hook runs inside the allocator so
+    /// it must not allocate memory.
+    ///
+    /// The flag is cleared as soon as the allocated memory is not above the soft limit, and then
+    /// the hook will be called again on the next crossing. Setting the soft limit below the
+    /// allocated memory sets the flag without calling the hook. By default the soft limit is
+    /// `usize::MAX`, so it is never crossed.
+    ///
+    /// ```
+    /// use limit_alloc::Limit;
+    /// use std::alloc::{GlobalAlloc, Layout, System};
+    ///
+    /// let limit = Limit::new(1_000, System);
+    /// limit.set_soft_limit(500, None);
+    ///
+    /// let layout = Layout::from_size_align(600, 1).unwrap();
+    /// unsafe {
+    ///     // Above the soft limit, the allocation succeeds but the flag is set
+    ///     let ptr = limit.alloc(layout);
+    ///     assert!(!ptr.is_null());
+    ///     assert!(limit.is_over_soft_limit());
+    ///     // Above the limit, the allocation fails
+    ///     assert!(limit.alloc(layout).is_null());
+    ///     limit.dealloc(ptr, layout);
+    /// }
+    /// assert!(!limit.is_over_soft_limit());
+    /// ```
+    pub fn set_soft_limit(&self, bytes: usize, hook: Option<WatermarkCallback>) {
+        self.soft_limit.set(bytes, hook, self.allocated());
+    }
+
+    /// Returns the soft limit in bytes, see `set_soft_limit`.
+    pub fn soft_limit(&self) -> usize {
+        self.soft_limit.threshold()
+    }
+
+    /// Returns true if the allocated memory is above the soft limit.
+    pub fn is_over_soft_limit(&self) -> bool {
+        self.soft_limit.is_over()
+    }
+
+    /// Registers a tag that can be used with `tag_scope`. Returns false if `MAX_TAGS` tags are
+    /// already registered. Registering the same tag again does nothing.
+    #[cfg(feature = "std")]
+    pub fn register_tag(&self, tag: &'static str) -> bool {
+        self.tags.register(tag)
+    }
+
+    /// Runs `f`, and attributes the memory allocated and deallocated through this `Limit` by the
+    /// current thread inside `f` to `tag`, which must have been registered using `register_tag`.
+    /// Scopes can be nested, then the memory is attributed to the innermost tag only.
+    ///
+    /// Memory is attributed to the tag that is current when it is allocated or deallocated, the
+    /// allocator does not know which tag allocated a block. So memory allocated inside the scope
+    /// and deallocated outside of it, or by another thread, stays attributed to the tag, and
+    /// memory allocated outside of any scope is not attributed to any tag.
+    ///
+    /// ```
+    /// use limit_alloc::Limit;
+    /// use std::alloc::{GlobalAlloc, Layout, System};
+    ///
+    /// let limit = Limit::new(1_000, System);
+    /// limit.register_tag("parser");
+    /// limit.register_tag("cache");
+    /// let layout = Layout::from_size_align(100, 1).unwrap();
+    /// let (a, b) = limit.tag_scope("parser", || unsafe {
+    ///     let a = limit.alloc(layout);
+    ///     let b = limit.tag_scope("cache", || limit.alloc(layout));
+    ///     (a, b)
+    /// });
+    /// assert_eq!(limit.tag_usage("parser"), 100);
+    /// assert_eq!(limit.tag_usage("cache"), 100);
+    /// limit.tag_scope("cache", || unsafe { limit.dealloc(b, layout) });
+    /// assert_eq!(limit.tag_usage("cache"), 0);
+    /// # unsafe { limit.dealloc(a, layout) };
+    /// ```
+    ///
+    /// # Panics
+    ///
+    /// If `tag` is not registered.
+    #[cfg(feature = "std")]
+    pub fn tag_scope<R>(&self, tag: &str, f: impl FnOnce() -> R) -> R {
+        let _guard = self.tags.scope(self as *const Self as *const (), tag);
+        f()
+    }
+
+    /// Runs `f` and returns the memory that the current thread allocated and deallocated through
+    /// this `Limit` inside `f`. Allocations made by other threads at the same time are not
+    /// counted, and neither are allocations that use a `Reservation`, since their memory was
+    /// already counted when it was reserved. Measurements of the same `Limit` can be nested, the
+    /// outer one also counts the memory of the inner one.
+    ///
+    /// ```
+    /// use limit_alloc::Limit;
+    /// use std::alloc::System;
+    ///
+    /// #[global_allocator]
+    /// static A: Limit<System> = Limit::unlimited(System);
+    ///
+    /// fn main() {
+    ///     let (v, delta) = A.measure(|| {
+    ///         let mut v = Vec::<u8>::with_capacity(1_000);
+    ///         // Temporary allocations count in the peak but not in the net
+    ///         drop(vec![0u8; 4_000]);
+    ///         v.extend_from_slice(&[1; 100]);
+    ///         v
+    ///     });
+    ///     assert_eq!(v.capacity(), 1_000);
+    ///     assert!(delta.net() >= 1_000 && delta.net() < 1_100);
+    ///     assert!(delta.peak >= 5_000);
+    ///     assert!(delta.allocations >= 2);
+    ///     let ((), delta) = A.measure(|| drop(v));
+    ///     assert_eq!(delta.net(), -1_000);
+    /// }
+    /// ```
+    #[cfg(feature = "std")]
+    pub fn measure<R>(&self, f: impl FnOnce() -> R) -> (R, MemoryDelta) {
+        measure::measure(self as *const Self as *const (), f)
+    }
 
-pub struct Limit<A> {
-    remaining: AtomicUsize,
-    alloc: A,
-}
+    /// Returns the memory attributed to `tag` in bytes, or 0 if it is not registered.
+    #[cfg(feature = "std")]
+    pub fn tag_usage(&self, tag: &str) -> usize {
+        self.tags.usage(tag)
+    }
 
-impl<A: GlobalAlloc> Limit<A> {
-    pub const fn new(limit: usize, alloc: A) -> Self {
-        Self {
-            remaining: AtomicUsize::new(limit),
-            alloc,
+    /// Returns the registered tags and the memory attributed to each of them.
+    #[cfg(feature = "std")]
+    pub fn tags(&self) -> impl Iterator<Item = (&'static str, usize)> + '_ {
+        self.tags.iter()
+    }
+
+    /// Called when an allocation of `layout` fails because of the limit.
+    fn reject(&self, layout: Layout) {
+        self.count_rejection();
+        #[cfg(any(feature = "tracing", feature = "log"))]
+        report::rejected(layout, self.remaining(), self.limit());
+        let handler = self.oom_handler.load(SeqCst);
+        if !handler.is_null() {
+            // Safety: the only non-null values stored in oom_handler are fn(Layout, usize)
+            let handler: fn(Layout, usize) = unsafe { mem::transmute(handler) };
+            handler(layout, self.remaining());
         }
     }
 
-    /// Returns None if the memory limit would be exhausted after allocating.
-    ///
-    /// # Safety
-    ///
-    /// The same restrictions as `GlobalAlloc::alloc`.
-    pub unsafe fn try_alloc(&self, layout: Layout) -> Option<*mut u8> {
-        match self
-            .remaining
-            .fetch_update(SeqCst, SeqCst, |old| old.checked_sub(layout.size()))
+    /// Updates the counters after an allocation fails because of the limit.
+    fn count_rejection(&self) {
+        self.failed_allocations.fetch_add(1, Relaxed);
+        self.rejected_allocations.fetch_add(1, Relaxed);
+        self.exhausted.store(true, Relaxed);
+    }
+
+    /// Returns true if `layout` is not larger than the maximum size of a single allocation.
+    fn fits_single_alloc(&self, layout: Layout) -> bool {
+        layout.size() <= self.max_single_alloc.load(SeqCst)
+    }
+
+    /// Returns the number of bytes counted for an existing allocation.
+    unsafe fn allocation_size(&self, ptr: *mut u8, layout: Layout) -> usize {
+        #[cfg(feature = "usable-size")]
+        if self.usable_size {
+            return usable_size::usable_size(ptr, layout);
+        }
+        let _ = ptr;
+        self.accounting.size(layout)
+    }
+
+    /// Called after an allocation succeeds, when the size that was charged before allocating may
+    /// be different from the size of the allocation. The difference is charged even if that
+    /// exceeds the limit, because the memory is already allocated. Returns `actual`.
+    #[cfg(feature = "usable-size")]
+    fn adjust(&self, charged: usize, actual: usize) -> usize {
+        if actual > charged {
+            let extra = actual - charged;
+            let old = self.allocated.fetch_add(extra, self.ordering.rmw());
+            self.increased(old + extra, self.limit.load(self.ordering.load()));
+        } else {
+            self.credit(charged - actual);
+        }
+        actual
+    }
+
+    /// Add `size` to the allocated memory. Returns false if that would exceed the limit, in that
+    /// case the counter is not modified.
+    fn charge(&self, size: usize) -> bool {
+        let ordering = self.ordering;
+        let limit = self.limit.load(ordering.load());
+        let result = if limit == usize::MAX {
+            // Without a limit a fetch_add is enough, it is cheaper than a compare and swap loop
+            let old = self.allocated.fetch_add(size, ordering.rmw());
+            if old.checked_add(size).is_none() {
+                self.allocated.fetch_sub(size, ordering.rmw());
+                return false;
+            }
+            Ok(old)
+        } else {
+            let max = self.max_allocated(limit);
+            self.allocated
+                .fetch_update(ordering.rmw(), ordering.load(), |old| {
+                    add_within_limit(old, size, max)
+                })
+        };
+        match result {
+            Ok(old) => {
+                // Only allocations made inside use_reserve can go above the limit here, besides
+                // the ones made while reporting
+                #[cfg(feature = "std")]
+                if old + size > limit && !reporting() {
+                    let from_reserve = (old + size - limit).min(size);
+                    self.reserve_used.fetch_add(from_reserve, Relaxed);
+                }
+                self.increased(old + size, limit);
+                #[cfg(feature = "std")]
+                {
+                    self.tags.charged(self as *const Self as *const (), size);
+                    measure::charged(self as *const Self as *const (), size);
+                }
+                true
+            }
+            Err(_e) => false,
+        }
+    }
+
+    /// Same as `charge`, but with `Overcommit::Allow` the size is added even if that exceeds the
+    /// limit. Used for allocations, not for reservations.
+    fn charge_alloc(&self, size: usize) -> bool {
+        if self.charge(size) {
+            return true;
+        }
+        if !self.overcommit.load(Relaxed) {
+            return false;
+        }
+        let ordering = self.ordering;
+        let Ok(old) = self
+            .allocated
+            .fetch_update(ordering.rmw(), ordering.load(), |old| old.checked_add(size))
+        else {
+            return false;
+        };
+        let limit = self.limit.load(ordering.load());
+        let new = old + size;
+        let above = new.saturating_sub(self.max_allocated(limit)).min(size);
+        self.overcommitted_bytes.fetch_add(above, Relaxed);
+        self.increased(new, limit);
+        #[cfg(feature = "std")]
         {
-            Ok(_size) => {}
-            Err(_e) => return None,
+            self.tags.charged(self as *const Self as *const (), size);
+            measure::charged(self as *const Self as *const (), size);
         }
-        let ret = self.alloc.alloc(layout);
-        if ret.is_null() {
-            // Nothing was actually allocated, so add back the size
-            self.remaining.fetch_add(layout.size(), SeqCst);
+        true
+    }
+
+    /// Returns the maximum allocated memory allowed for allocations made by the current thread.
+    fn max_allocated(&self, limit: usize) -> usize {
+        // Allocations made while reporting a rejected allocation are not limited, otherwise the
+        // report itself could fail
+        if reporting() {
+            return usize::MAX;
+        }
+        #[cfg(feature = "std")]
+        if emergency_reserve::in_use(self as *const Self as *const ()) {
+            return limit.saturating_add(self.reserve);
+        }
+        limit
+    }
+
+    /// Add one to the live allocations. Returns false if that would exceed the maximum, in that
+    /// case the counter is not modified.
+    fn charge_count(&self) -> bool {
+        if self.max_live_allocations == usize::MAX {
+            return true;
         }
+        self.live_allocations
+            .fetch_update(SeqCst, SeqCst, |old| {
+                add_within_limit(old, 1, self.max_live_allocations)
+            })
+            .is_ok()
+    }
 
-        Some(ret)
+    /// Subtract one from the live allocations.
+    fn credit_count(&self) {
+        if self.max_live_allocations == usize::MAX {
+            return;
+        }
+        // Saturate in case the allocation was not allocated by this allocator
+        let _ = self
+            .live_allocations
+            .fetch_update(SeqCst, SeqCst, |old| old.checked_sub(1));
     }
 
-    /// Returns remaining memory in bytes. This value does not guarantee that an allocation of x
-    /// bytes will succeed.
-    pub fn remaining(&self) -> usize {
-        self.remaining.load(SeqCst)
+    /// Called after the allocated memory increases to `allocated`.
+    fn increased(&self, allocated: usize, limit: usize) {
+        self.update_peak(allocated);
+        self.watermarks.increased(allocated, limit);
+        self.soft_limit.increased(allocated);
+    }
+
+    /// Subtract `size` from the allocated memory.
+    fn credit(&self, size: usize) {
+        // Saturate in case a dealloc adds back more bytes than were allocated, a fetch_sub could
+        // wrap around and make the remaining memory bigger than the limit
+        let ordering = self.ordering;
+        let old = match self
+            .allocated
+            .fetch_update(ordering.rmw(), ordering.load(), |old| {
+                Some(old.saturating_sub(size))
+            }) {
+            Ok(old) | Err(old) => old,
+        };
+        let allocated = old.saturating_sub(size);
+        #[cfg(feature = "std")]
+        if self.reserve_used.load(Relaxed) != 0 {
+            let _ = self
+                .reserve_used
+                .fetch_update(Relaxed, Relaxed, |used| Some(used.saturating_sub(size)));
+        }
+        self.watermarks.decreased(allocated);
+        self.soft_limit.decreased(allocated);
+        #[cfg(feature = "std")]
+        {
+            self.tags.credited(self as *const Self as *const (), size);
+            measure::credited(self as *const Self as *const (), size);
+        }
+        // This may run inside the global allocator, which must not unwind, so the error is only
+        // recorded and assert_accounting panics later
+        if size > old && self.strict_accounting.load(Relaxed) {
+            self.over_credited.fetch_add(size - old, Relaxed);
+        }
+    }
+
+    fn update_peak(&self, used: usize) {
+        // Equivalent to a compare-and-swap loop that only ever increases the peak
+        update_max(&self.peak, used, self.ordering.rmw());
+    }
+}
+
+/// Same as `counter.fetch_max(value, ordering)`, but it only writes if `value` is bigger than
+/// the current value. That is the uncommon case, and otherwise this is a load instead of a read
+/// modify write, which is much cheaper.
+fn update_max(counter: &AtomicUsize, value: usize, ordering: Ordering) {
+    if value > counter.load(Relaxed) {
+        counter.fetch_max(value, ordering);
+    }
+}
+
+/// Converts the result of an allocation to the `Option` returned by the `try_alloc` methods: None
+/// if the limit rejected it, or null if the inner allocator failed.
+fn discard_error(result: Result<*mut u8, AllocError>) -> Option<*mut u8> {
+    match result {
+        Ok(ptr) => Some(ptr),
+        Err(AllocError::InnerFailed) => Some(ptr::null_mut()),
+        Err(_) => None,
+    }
+}
+
+/// Returns true if the current thread is reporting a rejected allocation, see the `report`
+/// module.
+fn reporting() -> bool {
+    #[cfg(any(feature = "tracing", feature = "log"))]
+    return report::is_reporting();
+    #[cfg(not(any(feature = "tracing", feature = "log")))]
+    false
+}
+
+/// Reads a limit from the environment variable `var`, or returns `default` if it is not set or
+/// cannot be parsed.
+#[cfg(feature = "std")]
+fn limit_from_env(var: &str, default: usize) -> usize {
+    std::env::var(var)
+        .ok()
+        .and_then(|value| parse_size(&value).ok())
+        .unwrap_or(default)
+}
+
+/// Returns `allocated + size`, or None if that would exceed the limit.
+fn add_within_limit(allocated: usize, size: usize, limit: usize) -> Option<usize> {
+    let new = allocated.checked_add(size)?;
+    if new > limit {
+        None
+    } else {
+        Some(new)
+    }
+}
+
+#[cfg(feature = "usable-size")]
+impl Limit<std::alloc::System> {
+    /// Limit that counts the real size of the blocks returned by the system allocator, using
+    /// `malloc_usable_size` on Linux, Android and FreeBSD, and `malloc_size` on macOS. malloc
+    /// rounds small allocations up, so this is more accurate when there are many small
+    /// allocations. On other platforms this counts `layout.size()`, the same as `new`.
+    ///
+    /// Because the real size is only known after allocating, an allocation may exceed the limit
+    /// by a few bytes.
+    ///
+    /// ```
+    /// use limit_alloc::Limit;
+    /// use std::alloc::{GlobalAlloc, Layout};
+    ///
+    /// let limit = Limit::with_usable_size(1_000);
+    /// let layout = Layout::from_size_align(20, 1).unwrap();
+    /// unsafe {
+    ///     let ptr = limit.alloc(layout);
+    ///     assert!(limit.allocated() >= 20);
+    ///     let ptr = limit.realloc(ptr, layout, 200);
+    ///     assert!(limit.allocated() >= 200);
+    ///     limit.dealloc(ptr, Layout::from_size_align(200, 1).unwrap());
+    /// }
+    /// assert_eq!(limit.allocated(), 0);
+    /// ```
+    pub const fn with_usable_size(limit: usize) -> Self {
+        let mut l = Self::new(limit, std::alloc::System);
+        l.usable_size = true;
+        l
+    }
+}
+
+impl<A> Drop for Limit<A> {
+    fn drop(&mut self) {
+        if !*self.leak_check.get_mut() {
+            return;
+        }
+        let leaked = *self.allocated.get_mut();
+        if leaked == 0 {
+            return;
+        }
+        let report = *self.leak_report.get_mut();
+        if !report.is_null() {
+            // Safety: the only non-null values stored in leak_report are fn(usize)
+            let report: fn(usize) = unsafe { mem::transmute(report) };
+            report(leaked);
+            return;
+        }
+        // Panicking while already panicking would abort
+        #[cfg(feature = "std")]
+        if std::thread::panicking() {
+            return;
+        }
+        panic!("memory leak: {} bytes still allocated", leaked);
+    }
+}
+
+/// Prints the limit and the allocated and remaining memory, the inner allocator is not printed.
+impl<A: GlobalAlloc> fmt::Debug for Limit<A> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let limit = self.limit();
+        let allocated = self.allocated();
+        f.debug_struct("Limit")
+            .field("limit", &limit)
+            .field("allocated", &allocated)
+            .field("remaining", &limit.saturating_sub(allocated))
+            .finish()
     }
 }
 
@@ -68,12 +2453,38 @@ unsafe impl<A: GlobalAlloc> GlobalAlloc for Limit<A> {
     }
 
     unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        let size = self.allocation_size(ptr, layout);
+        // Leak the pointer if it is not a live allocation, see `assert_accounting`
+        #[cfg(feature = "debug-tracking")]
+        if !self.tracker.deallocated(ptr, layout, size) {
+            return;
+        }
         self.alloc.dealloc(ptr, layout);
-        self.remaining.fetch_add(layout.size(), SeqCst);
+        self.credit(size);
+        self.credit_count();
+        self.deallocations.fetch_add(1, Relaxed);
+        #[cfg(feature = "histogram")]
+        self.histogram.deallocated(layout.size());
+        let hook = self.dealloc_hook.load(SeqCst);
+        if !hook.is_null() {
+            // Safety: the only non-null values stored in dealloc_hook are fn(Layout)
+            let hook: fn(Layout) = unsafe { mem::transmute(hook) };
+            hook(layout);
+        }
+        self.notify_freed();
+    }
+
+    unsafe fn alloc_zeroed(&self, layout: Layout) -> *mut u8 {
+        self.try_alloc_zeroed(layout).unwrap_or(ptr::null_mut())
+    }
+
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        self.try_realloc(ptr, layout, new_size)
+            .unwrap_or(ptr::null_mut())
     }
 }
 
-unsafe impl<'a, A: GlobalAlloc> GlobalAlloc for &'a Limit<A> {
+unsafe impl<A: GlobalAlloc> GlobalAlloc for &Limit<A> {
     unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
         Limit::alloc(self, layout)
     }
@@ -81,43 +2492,217 @@ unsafe impl<'a, A: GlobalAlloc> GlobalAlloc for &'a Limit<A> {
     unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
         Limit::dealloc(self, ptr, layout)
     }
+
+    unsafe fn alloc_zeroed(&self, layout: Layout) -> *mut u8 {
+        Limit::alloc_zeroed(self, layout)
+    }
+
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        Limit::realloc(self, ptr, layout, new_size)
+    }
 }
 
-pub struct ArcLimit<A>(Arc<Limit<A>>);
+/// Guard returned by `Limit::scoped_raise`, restores the limit when dropped.
+#[must_use = "the limit is restored when the guard is dropped"]
+pub struct RaiseGuard<'a, A: GlobalAlloc> {
+    limit: &'a Limit<A>,
+    extra: usize,
+}
 
-impl<A> Clone for ArcLimit<A> {
-    fn clone(&self) -> Self {
-        Self(Arc::clone(&self.0))
+impl<A: GlobalAlloc> Drop for RaiseGuard<'_, A> {
+    fn drop(&mut self) {
+        self.limit.shrink_limit(self.extra);
     }
 }
 
-impl<A: GlobalAlloc> ArcLimit<A> {
-    pub fn new(l: Limit<A>) -> Self {
-        Self(Arc::new(l))
+/// Guard returned by `Limit::constrain_scope`, restores the limit when dropped.
+#[must_use = "the limit is restored when the guard is dropped"]
+pub struct ConstrainGuard<'a, A: GlobalAlloc> {
+    limit: &'a Limit<A>,
+    /// Bytes subtracted from the limit.
+    removed: usize,
+}
+
+impl<A: GlobalAlloc> Drop for ConstrainGuard<'_, A> {
+    fn drop(&mut self) {
+        self.limit.grow_limit(self.removed);
     }
 }
 
-unsafe impl<A: GlobalAlloc> GlobalAlloc for ArcLimit<A> {
-    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
-        Limit::alloc(&self.0, layout)
+/// Limit created using `Limit::child`, it counts its allocations and allocates through its parent.
+pub type ChildLimit<'a, A> = Limit<&'a Limit<A>>;
+
+/// Counters used by `ConstLimit`. Each `ConstLimitTag` has its own `ConstCounter` stored in a
+/// static, use `const_limit_tag!` to create one.
+pub struct ConstCounter {
+    /// Memory allocated by all the `ConstLimit` that use this counter, in bytes.
+    allocated: AtomicUsize,
+    /// Highest value of `allocated`.
+    peak: AtomicUsize,
+    /// Statistics, these are only informational so they use relaxed ordering.
+    failed_allocations: AtomicUsize,
+    rejected_allocations: AtomicUsize,
+    exhausted: AtomicBool,
+}
+
+impl ConstCounter {
+    pub const fn new() -> Self {
+        Self {
+            allocated: AtomicUsize::new(0),
+            peak: AtomicUsize::new(0),
+            failed_allocations: AtomicUsize::new(0),
+            rejected_allocations: AtomicUsize::new(0),
+            exhausted: AtomicBool::new(false),
+        }
     }
+}
 
-    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
-        Limit::dealloc(&self.0, ptr, layout)
+impl Default for ConstCounter {
+    fn default() -> Self {
+        Self::new()
     }
 }
 
-/// Total memory allocated by `ConstLimit`, in bytes.
-static ALLOCATED: AtomicUsize = AtomicUsize::new(0);
+/// Selects the counter used by a `ConstLimit`. Two `ConstLimit` with different tags track their
+/// memory independently, but all the `ConstLimit` with the same tag share the same counter, even
+/// if they have different limits. Use `const_limit_tag!` to implement this trait.
+pub trait ConstLimitTag {
+    fn counter() -> &'static ConstCounter;
+}
+
+/// Declares a new type that implements `ConstLimitTag` with its own counter.
+///
+/// ```
+/// use limit_alloc::{const_limit_tag, ConstLimit};
+/// use std::alloc::{GlobalAlloc, Layout, System};
+///
+/// const_limit_tag!(pub CacheTag);
+/// const_limit_tag!(pub BufferTag);
+///
+/// static CACHE: ConstLimit<System, 1_000, CacheTag> = ConstLimit::new(System);
+/// static BUFFER: ConstLimit<System, 2_000, BufferTag> = ConstLimit::new(System);
+///
+/// let layout = Layout::from_size_align(1_500, 1).unwrap();
+/// unsafe {
+///     let ptr = BUFFER.alloc(layout);
+///     assert!(!ptr.is_null());
+///     // Memory allocated using BUFFER is not counted by CACHE
+///     assert_eq!(BUFFER.allocated(), 1_500);
+///     assert_eq!(CACHE.remaining(), 1_000);
+///     BUFFER.dealloc(ptr, layout);
+/// }
+/// ```
+#[macro_export]
+macro_rules! const_limit_tag {
+    ($vis:vis $name:ident) => {
+        #[derive(Clone, Copy, Debug, Default)]
+        $vis struct $name;
+
+        impl $crate::ConstLimitTag for $name {
+            fn counter() -> &'static $crate::ConstCounter {
+                static COUNTER: $crate::ConstCounter = $crate::ConstCounter::new();
+                &COUNTER
+            }
+        }
+    };
+}
+
+const_limit_tag!(pub DefaultTag);
+
+/// Declares a `Limit` as the global allocator, and a function `global_limit()` that returns it,
+/// so the rest of the program can read the statistics without naming the static. The limit can
+/// be any constant expression. The inner allocator is `System` by default, and can be passed as a
+/// path to a unit struct, or as a type and a constant expression to initialize it.
+///
+/// ```
+/// limit_alloc::limit_global!(64 * 1024 * 1024);
+///
+/// fn main() {
+///     let v: Vec<u8> = Vec::with_capacity(1_000);
+///     assert!(global_limit().allocated() >= 1_000);
+///     # drop(v);
+/// }
+/// ```
+///
+/// With a custom inner allocator:
+///
+/// ```
+/// use std::alloc::System;
+///
+/// limit_alloc::limit_global!(4_000_000, System);
+/// // Or, if the allocator is not a unit struct:
+/// // limit_alloc::limit_global!(4_000_000, MyAlloc = MyAlloc::new());
+/// # fn main() {
+/// #     assert_eq!(global_limit().limit(), 4_000_000);
+/// # }
+/// ```
+///
+/// The limit must be a constant expression:
+///
+/// ```compile_fail
+/// limit_alloc::limit_global!(std::env::args().count());
+/// # fn main() {}
+/// ```
+///
+/// And there can only be one global allocator:
+///
+/// ```compile_fail
+/// limit_alloc::limit_global!(4_000_000);
+///
+/// mod other {
+///     limit_alloc::limit_global!(4_000_000);
+/// }
+/// # fn main() {}
+/// ```
+#[cfg(feature = "std")]
+#[macro_export]
+macro_rules! limit_global {
+    ($limit:expr) => {
+        $crate::limit_global!($limit, ::std::alloc::System = ::std::alloc::System);
+    };
+    ($limit:expr, $alloc:ty = $init:expr) => {
+        #[global_allocator]
+        static LIMIT_ALLOC_GLOBAL: $crate::Limit<$alloc> = $crate::Limit::new($limit, $init);
+
+        /// Returns the global allocator declared using `limit_global!`.
+        pub fn global_limit() -> &'static $crate::Limit<$alloc> {
+            &LIMIT_ALLOC_GLOBAL
+        }
+    };
+    ($limit:expr, $alloc:path) => {
+        $crate::limit_global!($limit, $alloc = $alloc);
+    };
+}
 
-#[derive(Clone)]
-pub struct ConstLimit<A, const L: usize> {
+/// Allocator with a memory limit known at compile time. The allocated memory is stored in a static
+/// counter selected by the tag `T`, so this type is zero-sized if the inner allocator is
+/// zero-sized. By default all the `ConstLimit` share the counter of `DefaultTag`.
+pub struct ConstLimit<A, const L: usize, T = DefaultTag> {
     alloc: A,
+    tag: PhantomData<fn() -> T>,
+}
+
+impl<A: Clone, const L: usize, T> Clone for ConstLimit<A, L, T> {
+    fn clone(&self) -> Self {
+        Self {
+            alloc: self.alloc.clone(),
+            tag: PhantomData,
+        }
+    }
 }
 
-impl<A: GlobalAlloc, const L: usize> ConstLimit<A, L> {
+impl<A: GlobalAlloc, const L: usize, T: ConstLimitTag> ConstLimit<A, L, T> {
     pub const fn new(alloc: A) -> Self {
-        Self { alloc }
+        Self {
+            alloc,
+            tag: PhantomData,
+        }
+    }
+
+    /// Returns a reference to the inner allocator. Memory allocated using the inner allocator
+    /// directly is not counted, so it must also be deallocated using the inner allocator.
+    pub fn inner(&self) -> &A {
+        &self.alloc
     }
 
     /// Returns None if the memory limit would be exhausted after allocating.
@@ -126,41 +2711,692 @@ impl<A: GlobalAlloc, const L: usize> ConstLimit<A, L> {
     ///
     /// The same restrictions as `GlobalAlloc::alloc`.
     pub unsafe fn try_alloc(&self, layout: Layout) -> Option<*mut u8> {
-        match ALLOCATED.fetch_update(SeqCst, SeqCst, |old| {
-            let new = old.checked_add(layout.size())?;
-            if new > L {
-                None
+        self.alloc_with(layout, |alloc, layout| alloc.alloc(layout))
+    }
+
+    /// Same as `try_alloc`, but the memory is zeroed.
+    ///
+    /// # Safety
+    ///
+    /// The same restrictions as `GlobalAlloc::alloc_zeroed`.
+    pub unsafe fn try_alloc_zeroed(&self, layout: Layout) -> Option<*mut u8> {
+        self.alloc_with(layout, |alloc, layout| alloc.alloc_zeroed(layout))
+    }
+
+    /// Returns None if the memory limit would be exhausted after growing the allocation, in that
+    /// case the old allocation is still valid. Otherwise returns the result of the inner
+    /// allocator, which is null if it failed.
+    ///
+    /// # Safety
+    ///
+    /// The same restrictions as `GlobalAlloc::realloc`.
+    pub unsafe fn try_realloc(
+        &self,
+        ptr: *mut u8,
+        layout: Layout,
+        new_size: usize,
+    ) -> Option<*mut u8> {
+        let old_size = layout.size();
+        if new_size > old_size {
+            let delta = new_size - old_size;
+            if !Self::charge(delta) {
+                Self::reject();
+                return None;
+            }
+            let ret = self.alloc.realloc(ptr, layout, new_size);
+            if ret.is_null() {
+                // The old allocation is still valid, so only subtract the difference
+                Self::credit(delta);
+                T::counter().failed_allocations.fetch_add(1, Relaxed);
+            }
+            Some(ret)
+        } else {
+            let ret = self.alloc.realloc(ptr, layout, new_size);
+            if ret.is_null() {
+                T::counter().failed_allocations.fetch_add(1, Relaxed);
             } else {
-                Some(new)
+                Self::credit(old_size - new_size);
             }
-        }) {
-            Ok(_size) => {}
-            Err(_e) => return None,
+            Some(ret)
+        }
+    }
+
+    /// Charge the size of `layout` and allocate using `f`. The counter is restored if `f` returns
+    /// null.
+    unsafe fn alloc_with(
+        &self,
+        layout: Layout,
+        f: impl FnOnce(&A, Layout) -> *mut u8,
+    ) -> Option<*mut u8> {
+        if !Self::charge(layout.size()) {
+            Self::reject();
+            return None;
         }
-        let ret = self.alloc.alloc(layout);
+        let ret = f(&self.alloc, layout);
         if ret.is_null() {
             // Nothing was actually allocated, so subtract the size
-            ALLOCATED.fetch_sub(layout.size(), SeqCst);
+            Self::credit(layout.size());
+            T::counter().failed_allocations.fetch_add(1, Relaxed);
+        } else {
+            #[cfg(feature = "std")]
+            measure::allocated(measure::const_counter::<T>());
         }
 
         Some(ret)
     }
 
+    /// Called when an allocation fails because of the limit.
+    fn reject() {
+        let counter = T::counter();
+        counter.failed_allocations.fetch_add(1, Relaxed);
+        counter.rejected_allocations.fetch_add(1, Relaxed);
+        counter.exhausted.store(true, Relaxed);
+    }
+
     /// Returns remaining memory in bytes. This value does not guarantee that an allocation of x
     /// bytes will succeed.
     pub fn remaining(&self) -> usize {
-        L.checked_sub(ALLOCATED.load(SeqCst))
-            .expect("bug: allocated more memory than the limit")
+        // Saturate because ConstLimit with the same tag and a bigger limit may have allocated more
+        // memory than L
+        L.saturating_sub(T::counter().allocated.load(SeqCst))
+    }
+
+    /// Returns true if `remaining() >= bytes`, see `Limit::has_headroom`.
+    pub fn has_headroom(&self, bytes: usize) -> bool {
+        self.remaining() >= bytes
+    }
+
+    /// Returns how many bytes the allocated memory is over the limit, or 0 if it is not. This can
+    /// happen because all the `ConstLimit` with the same tag share the counter, even if they have
+    /// different limits.
+    ///
+    /// ```
+    /// use limit_alloc::{const_limit_tag, ConstLimit};
+    /// use std::alloc::{GlobalAlloc, Layout, System};
+    ///
+    /// const_limit_tag!(SharedTag);
+    /// static SMALL: ConstLimit<System, 100, SharedTag> = ConstLimit::new(System);
+    /// static BIG: ConstLimit<System, 1_000, SharedTag> = ConstLimit::new(System);
+    ///
+    /// let layout = Layout::from_size_align(300, 1).unwrap();
+    /// unsafe {
+    ///     let ptr = BIG.alloc(layout);
+    ///     assert_eq!(SMALL.remaining(), 0);
+    ///     assert_eq!(SMALL.overcommitted(), 200);
+    ///     assert_eq!(BIG.overcommitted(), 0);
+    ///     BIG.dealloc(ptr, layout);
+    /// }
+    /// ```
+    pub fn overcommitted(&self) -> usize {
+        self.allocated().saturating_sub(L)
+    }
+
+    /// Returns the memory that is currently allocated by all the `ConstLimit` allocators with the
+    /// same tag, in bytes.
+    pub fn allocated(&self) -> usize {
+        T::counter().allocated.load(SeqCst)
+    }
+
+    /// Same as `allocated`. As long as `overcommitted()` is 0, `used() + remaining() == limit()`.
+    pub fn used(&self) -> usize {
+        self.allocated()
+    }
+
+    /// Returns the memory limit in bytes, this is always `L`.
+    pub fn limit(&self) -> usize {
+        L
+    }
+
+    /// Returns the highest number of bytes that were allocated at the same time, since the
+    /// program started or since the last call to `reset_peak`.
+    pub fn peak(&self) -> usize {
+        T::counter().peak.load(SeqCst)
+    }
+
+    /// Sets the memory allocated by all the `ConstLimit` with the same tag. Intended for test
+    /// setup: the counter is static, so it keeps whatever the previous test left allocated.
+    ///
+    /// # Safety
+    ///
+    /// This does not break memory safety, but it breaks the accounting: memory that is live when
+    /// this is called and later deallocated will be subtracted from the new value. So this must
+    /// only be called when no memory allocated through a `ConstLimit` with this tag is live, or
+    /// when that memory will never be deallocated.
+    pub unsafe fn reset_allocated(&self, to: usize) {
+        let counter = T::counter();
+        counter.allocated.store(to, SeqCst);
+        counter.peak.store(to, SeqCst);
+    }
+
+    /// Sets the allocated memory and the peak of the counter of the tag `T` back to 0. This is an
+    /// associated function, so it can be called in a test without access to the allocator, for
+    /// example `ConstLimit::<System, 1_000, MyTag>::reset()`. Only the counter of `T` is reset,
+    /// the `ConstLimit` with other tags are not affected.
+    ///
+    /// # Safety
+    ///
+    /// Same as `reset_allocated`, no memory allocated through a `ConstLimit` with this tag may be
+    /// live.
+    pub unsafe fn reset() {
+        let counter = T::counter();
+        counter.allocated.store(0, SeqCst);
+        counter.peak.store(0, SeqCst);
+    }
+
+    /// Returns the number of allocations that failed, either because of the limit or because the
+    /// inner allocator returned null. Same as the allocated memory, this is shared by all the
+    /// `ConstLimit` with the same tag.
+    pub fn failed_allocations(&self) -> usize {
+        T::counter().failed_allocations.load(Relaxed)
+    }
+
+    /// Returns the number of allocations that failed because of the limit, not counting the
+    /// ones where the inner allocator returned null.
+    pub fn rejected_allocations(&self) -> usize {
+        T::counter().rejected_allocations.load(Relaxed)
+    }
+
+    /// Returns true if any allocation was rejected because of the limit since the program started
+    /// or since the last call to `clear_exhausted`.
+    pub fn is_exhausted(&self) -> bool {
+        T::counter().exhausted.load(Relaxed)
+    }
+
+    /// Resets the flag returned by `is_exhausted`. The counters are not modified.
+    pub fn clear_exhausted(&self) {
+        T::counter().exhausted.store(false, Relaxed);
+    }
+
+    /// Sets the peak back to the memory that is allocated right now. Useful to measure the peak
+    /// memory usage of different parts of the program.
+    pub fn reset_peak(&self) {
+        let counter = T::counter();
+        counter.peak.store(counter.allocated.load(SeqCst), SeqCst);
+    }
+
+    /// Add `size` to the allocated memory. Returns false if that would exceed the limit, in that
+    /// case the counter is not modified.
+    fn charge(size: usize) -> bool {
+        let counter = T::counter();
+        match counter
+            .allocated
+            .fetch_update(SeqCst, SeqCst, |old| add_within_limit(old, size, L))
+        {
+            Ok(old) => {
+                counter.peak.fetch_max(old + size, SeqCst);
+                #[cfg(feature = "std")]
+                measure::charged(measure::const_counter::<T>(), size);
+                true
+            }
+            Err(_e) => false,
+        }
+    }
+
+    /// Subtract `size` from the allocated memory.
+    fn credit(size: usize) {
+        // Saturate in case a dealloc adds back more bytes than were allocated, for example memory
+        // that was allocated before this allocator was installed
+        let _ = T::counter()
+            .allocated
+            .fetch_update(SeqCst, SeqCst, |old| Some(old.saturating_sub(size)));
+        #[cfg(feature = "std")]
+        measure::credited(measure::const_counter::<T>(), size);
+    }
+}
+
+/// Restores the allocated memory and the peak of the counter of the tag `T` when dropped. This
+/// allows tests that exhaust a `ConstLimit` to run one after another without interfering: memory
+/// leaked or still allocated by one test is not counted in the next one. Tests that use the same
+/// tag must still not run at the same time.
+///
+/// ```
+/// use limit_alloc::{const_limit_tag, ConstLimit, ConstLimitTestGuard};
+/// use std::alloc::{GlobalAlloc, Layout, System};
+///
+/// const_limit_tag!(TestTag);
+/// static LIMIT: ConstLimit<System, 100, TestTag> = ConstLimit::new(System);
+///
+/// fn exhaust() {
+///     let _guard = unsafe { ConstLimitTestGuard::<TestTag>::new() };
+///     let layout = Layout::from_size_align(100, 1).unwrap();
+///     unsafe {
+///         // Leaked on purpose, the guard restores the counter anyway
+///         assert!(!LIMIT.alloc(layout).is_null());
+///         assert!(LIMIT.alloc(layout).is_null());
+///     }
+/// }
+///
+/// exhaust();
+/// exhaust();
+/// assert_eq!(LIMIT.allocated(), 0);
+/// ```
+#[must_use = "the counter is restored when the guard is dropped"]
+pub struct ConstLimitTestGuard<T: ConstLimitTag> {
+    allocated: usize,
+    peak: usize,
+    tag: PhantomData<fn() -> T>,
+}
+
+impl<T: ConstLimitTag> ConstLimitTestGuard<T> {
+    /// Takes a snapshot of the counter of the tag `T`.
+    ///
+    /// # Safety
+    ///
+    /// Same as `ConstLimit::reset_allocated`: memory allocated through a `ConstLimit` with this
+    /// tag while the guard is alive must not be deallocated after the guard is dropped.
+    pub unsafe fn new() -> Self {
+        let counter = T::counter();
+        Self {
+            allocated: counter.allocated.load(SeqCst),
+            peak: counter.peak.load(SeqCst),
+            tag: PhantomData,
+        }
+    }
+}
+
+impl<T: ConstLimitTag> Drop for ConstLimitTestGuard<T> {
+    fn drop(&mut self) {
+        let counter = T::counter();
+        counter.allocated.store(self.allocated, SeqCst);
+        counter.peak.store(self.peak, SeqCst);
+    }
+}
+
+/// Prints the limit and the allocated and remaining memory, the inner allocator is not printed.
+impl<A: GlobalAlloc, const L: usize, T: ConstLimitTag> fmt::Debug for ConstLimit<A, L, T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let allocated = self.allocated();
+        f.debug_struct("ConstLimit")
+            .field("limit", &L)
+            .field("allocated", &allocated)
+            .field("remaining", &L.saturating_sub(allocated))
+            .finish()
     }
 }
 
-unsafe impl<A: GlobalAlloc, const L: usize> GlobalAlloc for ConstLimit<A, L> {
+unsafe impl<A: GlobalAlloc, const L: usize, T: ConstLimitTag> GlobalAlloc for ConstLimit<A, L, T> {
     unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
         self.try_alloc(layout).unwrap_or(ptr::null_mut())
     }
 
     unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
         self.alloc.dealloc(ptr, layout);
-        ALLOCATED.fetch_sub(layout.size(), SeqCst);
+        Self::credit(layout.size());
+    }
+
+    unsafe fn alloc_zeroed(&self, layout: Layout) -> *mut u8 {
+        self.try_alloc_zeroed(layout).unwrap_or(ptr::null_mut())
+    }
+
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        self.try_realloc(ptr, layout, new_size)
+            .unwrap_or(ptr::null_mut())
+    }
+}
+
+#[cfg(all(test, feature = "std"))]
+mod tests {
+    use super::*;
+    use std::alloc::System;
+    use std::thread;
+
+    const LAYOUT: Layout = unsafe { Layout::from_size_align_unchecked(64, 8) };
+
+    #[test]
+    fn unlimited_counts_concurrent_allocations() {
+        let limit = Limit::unlimited(System);
+        thread::scope(|s| {
+            for _ in 0..4 {
+                s.spawn(|| {
+                    for _ in 0..10_000 {
+                        let ptr = unsafe { limit.alloc(LAYOUT) };
+                        assert!(!ptr.is_null());
+                        unsafe { limit.dealloc(ptr, LAYOUT) };
+                    }
+                });
+            }
+        });
+        assert_eq!(limit.allocated(), 0);
+    }
+
+    #[test]
+    fn unlimited_keeps_the_statistics() {
+        let limit = Limit::unlimited(System);
+        let ptr = unsafe { limit.alloc(LAYOUT) };
+        assert_eq!(limit.allocated(), 64);
+        assert_eq!(limit.peak(), 64);
+        assert_eq!(limit.alloc_count(), 1);
+        assert_eq!(limit.largest_request(), 64);
+        limit.set_limit(1_000);
+        let other = unsafe { limit.alloc(LAYOUT) };
+        assert_eq!(limit.alloc_count(), 2);
+        unsafe {
+            limit.dealloc(ptr, LAYOUT);
+            limit.dealloc(other, LAYOUT);
+        }
+        // Memory allocated before the limit was set is counted the same way when it is freed
+        assert_eq!(limit.dealloc_count(), 2);
+        assert_eq!(limit.stats().bytes_allocated_total, 128);
+        assert_eq!(limit.allocated(), 0);
+        assert_eq!(limit.peak(), 128);
+        limit.set_limit(usize::MAX);
+        let ptr = unsafe { limit.alloc(LAYOUT) };
+        unsafe { limit.dealloc(ptr, LAYOUT) };
+        assert_eq!((limit.alloc_count(), limit.dealloc_count()), (3, 3));
+    }
+
+    // Debug tracking leaks the pointers it did not allocate instead of deallocating them
+    #[cfg(not(feature = "debug-tracking"))]
+    #[test]
+    fn unlimited_foreign_free_does_not_reject_allocations() {
+        use crate::test_util::foreign;
+
+        let limit = Limit::unlimited(System);
+        thread::scope(|s| {
+            s.spawn(|| {
+                for _ in 0..10_000 {
+                    // Credits 64 bytes that were never charged, the counter must stay at 0
+                    // instead of wrapping around, even for a moment
+                    unsafe { limit.dealloc(foreign(LAYOUT), LAYOUT) };
+                }
+            });
+            for _ in 0..4 {
+                s.spawn(|| {
+                    for _ in 0..10_000 {
+                        let ptr = unsafe { limit.try_alloc(LAYOUT) }.unwrap();
+                        assert!(!ptr.is_null());
+                        unsafe { limit.dealloc(ptr, LAYOUT) };
+                    }
+                });
+            }
+        });
+        assert_eq!(limit.allocated(), 0);
+        assert_eq!(limit.rejected_allocations(), 0);
+    }
+
+    #[cfg(not(feature = "debug-tracking"))]
+    #[test]
+    fn foreign_free_does_not_raise_the_limit() {
+        use crate::test_util::foreign;
+
+        let limit = Limit::new(1_000, System);
+        let live = unsafe { limit.alloc(LAYOUT) };
+        for _ in 0..100 {
+            unsafe { limit.dealloc(foreign(LAYOUT), LAYOUT) };
+            assert_eq!(limit.allocated(), 0);
+            assert_eq!(limit.remaining(), 1_000);
+        }
+        // The counter saturated at 0 instead of wrapping around, so the limit still applies
+        let big = Layout::from_size_align(1_000, 1).unwrap();
+        let ptr = unsafe { limit.alloc(big) };
+        assert!(!ptr.is_null());
+        assert!(unsafe { limit.alloc(LAYOUT) }.is_null());
+        unsafe {
+            limit.dealloc(ptr, big);
+            limit.dealloc(live, LAYOUT);
+        }
+        assert_eq!(limit.allocated(), 0);
+        // Not enabled, so nothing was recorded
+        limit.assert_accounting();
+    }
+
+    #[cfg(not(feature = "debug-tracking"))]
+    #[test]
+    fn concurrent_foreign_frees_never_exceed_the_limit() {
+        use crate::test_util::foreign;
+
+        let limit = Limit::new(1_000, System);
+        thread::scope(|s| {
+            s.spawn(|| {
+                for _ in 0..10_000 {
+                    unsafe { limit.dealloc(foreign(LAYOUT), LAYOUT) };
+                    assert!(limit.remaining() <= 1_000);
+                }
+            });
+            for _ in 0..4 {
+                s.spawn(|| {
+                    for _ in 0..10_000 {
+                        let ptr = unsafe { limit.alloc(LAYOUT) };
+                        assert!(limit.allocated() <= 1_000);
+                        if !ptr.is_null() {
+                            unsafe { limit.dealloc(ptr, LAYOUT) };
+                        }
+                    }
+                });
+            }
+        });
+        assert_eq!(limit.allocated(), 0);
+    }
+
+    #[cfg(not(feature = "debug-tracking"))]
+    #[test]
+    fn strict_accounting_records_foreign_frees() {
+        use crate::test_util::foreign;
+        use std::panic;
+
+        let limit = Limit::new(1_000, System);
+        limit.set_strict_accounting(true);
+        let ptr = unsafe { limit.alloc(LAYOUT) };
+        unsafe { limit.dealloc(ptr, LAYOUT) };
+        limit.assert_accounting();
+        // 64 bytes are allocated, so only 36 of the 100 deallocated bytes are an error
+        let ptr = unsafe { limit.alloc(LAYOUT) };
+        let layout = Layout::from_size_align(100, 1).unwrap();
+        unsafe { limit.dealloc(foreign(layout), layout) };
+        assert_eq!(limit.allocated(), 0);
+        let err = panic::catch_unwind(|| limit.assert_accounting()).unwrap_err();
+        assert_eq!(
+            err.downcast_ref::<std::string::String>().unwrap(),
+            "deallocated 36 bytes more than were allocated"
+        );
+        unsafe { System.dealloc(ptr, LAYOUT) };
+    }
+
+    #[test]
+    fn decrease_limit_below_the_allocated_memory() {
+        let limit = Limit::new(1_000, System);
+        let ptr = unsafe { limit.alloc(LAYOUT) };
+        limit.decrease_limit(990);
+        assert_eq!(limit.limit(), 10);
+        assert_eq!(limit.remaining(), 0);
+        assert!(unsafe { limit.alloc(LAYOUT) }.is_null());
+        limit.increase_limit(100);
+        assert_eq!(limit.remaining(), 46);
+        limit.decrease_limit(usize::MAX);
+        assert_eq!(limit.limit(), 0);
+        unsafe { limit.dealloc(ptr, LAYOUT) };
+        assert_eq!(limit.remaining(), 0);
+        limit.increase_limit(usize::MAX);
+        assert_eq!(limit.limit(), usize::MAX);
+    }
+
+    #[test]
+    fn alloc_zeroed_calls_the_inner_alloc_zeroed() {
+        use crate::test_util::MockAlloc;
+        use core::sync::atomic::Ordering::SeqCst;
+
+        const_limit_tag!(ZeroedTag);
+
+        let big = Layout::from_size_align(1 << 20, 8).unwrap();
+        let check = |alloc: &dyn GlobalAlloc, mock: &MockAlloc, allocated: &dyn Fn() -> usize| {
+            let ptr = unsafe { alloc.alloc_zeroed(big) };
+            assert!(!ptr.is_null());
+            assert!(unsafe { core::slice::from_raw_parts(ptr, big.size()) }
+                .iter()
+                .all(|&b| b == 0));
+            assert_eq!(mock.zeroed.load(SeqCst), 1);
+            assert_eq!(mock.allocs.load(SeqCst), 0);
+            assert_eq!(allocated(), big.size());
+            unsafe { alloc.dealloc(ptr, big) };
+            // A null result is not counted
+            mock.set_fail(true);
+            assert!(unsafe { alloc.alloc_zeroed(big) }.is_null());
+            assert_eq!(mock.zeroed.load(SeqCst), 2);
+            assert_eq!(allocated(), 0);
+        };
+        let limit = Limit::new(2 << 20, MockAlloc::new());
+        check(&limit, limit.inner(), &|| limit.allocated());
+        assert_eq!(limit.failed_allocations(), 1);
+        let arc = ArcLimit::new(Limit::new(2 << 20, MockAlloc::new()));
+        check(&arc, arc.inner(), &|| arc.allocated());
+        let limit = ConstLimit::<_, { 2 << 20 }, ZeroedTag>::new(MockAlloc::new());
+        check(&limit, limit.inner(), &|| limit.allocated());
+    }
+
+    #[test]
+    fn peak_under_concurrent_allocations() {
+        const_limit_tag!(PeakTag);
+
+        fn churn(alloc: &(dyn GlobalAlloc + Sync)) {
+            thread::scope(|s| {
+                for i in 1..=4 {
+                    s.spawn(move || {
+                        let layout = Layout::from_size_align(i * 1_000, 1).unwrap();
+                        for _ in 0..1_000 {
+                            let ptr = unsafe { alloc.alloc(layout) };
+                            assert!(!ptr.is_null());
+                            unsafe { alloc.dealloc(ptr, layout) };
+                        }
+                    });
+                }
+            });
+        }
+
+        let limit = Limit::new(1 << 20, System);
+        let kept = unsafe { limit.alloc(LAYOUT) };
+        churn(&limit);
+        // At least the largest single allocation was live at the same time as `kept`
+        assert!(limit.peak() >= 4_000 + 64, "{}", limit.peak());
+        assert!(limit.peak() <= 10_000 + 64, "{}", limit.peak());
+        limit.reset_peak();
+        assert_eq!(limit.peak(), 64);
+        assert!(limit.peak() >= limit.allocated());
+        unsafe { limit.dealloc(kept, LAYOUT) };
+        assert_eq!(limit.peak(), 64);
+
+        let limit = ConstLimit::<System, { 1 << 20 }, PeakTag>::new(System);
+        churn(&limit);
+        assert!((4_000..=10_000).contains(&limit.peak()), "{}", limit.peak());
+        limit.reset_peak();
+        assert_eq!(limit.peak(), 0);
+    }
+
+    #[test]
+    fn counter_never_underflows_with_any_ordering() {
+        use core::sync::atomic::AtomicBool;
+
+        for ordering in [
+            CounterOrdering::SeqCst,
+            CounterOrdering::AcqRel,
+            CounterOrdering::Relaxed,
+        ] {
+            let limit = Limit::with_ordering(1_000, System, ordering);
+            let done = AtomicBool::new(false);
+            thread::scope(|s| {
+                let workers: std::vec::Vec<_> = (0..4)
+                    .map(|_| {
+                        s.spawn(|| {
+                            for i in 0..5_000 {
+                                let ptr = unsafe { limit.alloc(LAYOUT) };
+                                if ptr.is_null() {
+                                    continue;
+                                }
+                                let ptr = unsafe { limit.realloc(ptr, LAYOUT, 32 + i % 64) };
+                                assert!(!ptr.is_null());
+                                let layout = Layout::from_size_align(32 + i % 64, 8).unwrap();
+                                unsafe { limit.dealloc(ptr, layout) };
+                            }
+                        })
+                    })
+                    .collect();
+                s.spawn(|| {
+                    // A counter that went below 0 would wrap around to a huge value
+                    while !done.load(SeqCst) {
+                        assert!(limit.allocated() <= 1_000, "{:?}", ordering);
+                    }
+                });
+                let results: std::vec::Vec<_> = workers.into_iter().map(|w| w.join()).collect();
+                // Stop the watcher before a failure of a worker is propagated
+                done.store(true, SeqCst);
+                for result in results {
+                    result.unwrap();
+                }
+            });
+            assert_eq!(limit.allocated(), 0, "{:?}", ordering);
+        }
+    }
+
+    #[test]
+    fn failed_try_realloc_and_try_alloc_zeroed_restore_the_counter() {
+        use crate::test_util::MockAlloc;
+        use core::sync::atomic::Ordering::SeqCst;
+
+        const_limit_tag!(RollbackTag);
+
+        let small = Layout::from_size_align(100, 8).unwrap();
+        const LAYOUT_2K: Layout = unsafe { Layout::from_size_align_unchecked(2_000, 8) };
+        const LAYOUT_500: Layout = unsafe { Layout::from_size_align_unchecked(500, 8) };
+        const LAYOUT_200: Layout = unsafe { Layout::from_size_align_unchecked(200, 8) };
+        // Same checks for each type, the methods are not part of a trait
+        macro_rules! check {
+            ($limit:expr) => {{
+                let limit = $limit;
+                let ptr = unsafe { limit.try_alloc(small) }.unwrap();
+                assert!(!ptr.is_null());
+                limit.inner().set_fail(true);
+                // The inner allocator failed: the old allocation is still valid and counted
+                assert!(unsafe { limit.try_realloc(ptr, small, 500) }
+                    .unwrap()
+                    .is_null());
+                assert_eq!(limit.allocated(), 100);
+                assert!(unsafe { limit.try_alloc_zeroed(small) }.unwrap().is_null());
+                assert_eq!(limit.allocated(), 100);
+                // Over the limit the inner allocator is not called
+                let reallocs = limit.inner().reallocs.load(SeqCst);
+                assert!(unsafe { limit.try_realloc(ptr, small, 2_000) }.is_none());
+                assert_eq!(limit.inner().reallocs.load(SeqCst), reallocs);
+                assert!(unsafe { limit.try_alloc_zeroed(LAYOUT_2K) }.is_none());
+                assert_eq!(limit.allocated(), 100);
+                limit.inner().set_fail(false);
+                let ptr = unsafe { limit.try_realloc(ptr, small, 500) }.unwrap();
+                assert!(!ptr.is_null());
+                assert_eq!(limit.allocated(), 500);
+                let ptr = unsafe { limit.try_realloc(ptr, LAYOUT_500, 200) }.unwrap();
+                assert_eq!(limit.allocated(), 200);
+                unsafe { limit.dealloc(ptr, LAYOUT_200) };
+                assert_eq!(limit.allocated(), 0);
+            }};
+        }
+        check!(Limit::new(1_000, MockAlloc::new()));
+        check!(ArcLimit::new(Limit::new(1_000, MockAlloc::new())));
+        check!(ConstLimit::<_, 1_000, RollbackTag>::new(MockAlloc::new()));
+    }
+
+    #[test]
+    fn only_use_reserve_counts_as_reserve_used() {
+        let limit = Limit::with_reserve(1_000, 100, System);
+        let big = Layout::from_size_align(900, 1).unwrap();
+        let ptr = unsafe { limit.alloc(big) };
+        // Lowering the limit below the allocated memory does not use the reserve
+        limit.set_limit(500);
+        assert_eq!(limit.overcommitted(), 400);
+        assert_eq!(limit.reserve_used(), 0);
+        // Neither does Overcommit::Allow
+        limit.set_overcommit(Overcommit::Allow);
+        let other = unsafe { limit.alloc(LAYOUT) };
+        assert!(!other.is_null());
+        assert_eq!(limit.reserve_used(), 0);
+        unsafe { limit.dealloc(other, LAYOUT) };
+        limit.set_overcommit(Overcommit::Reject);
+        limit.set_limit(1_000);
+        let small = Layout::from_size_align(150, 1).unwrap();
+        let from_reserve = limit.use_reserve(|| unsafe { limit.alloc(small) });
+        assert!(!from_reserve.is_null());
+        assert_eq!(limit.reserve_used(), 50);
+        // Deallocations give the memory back to the reserve first
+        unsafe { limit.dealloc(ptr, big) };
+        assert_eq!(limit.reserve_used(), 0);
+        unsafe { limit.dealloc(from_reserve, small) };
+        assert_eq!(limit.allocated(), 0);
     }
 }
@@ -0,0 +1,125 @@
+//! Export the statistics of a `Limit` using the `metrics` crate.
+use crate::Limit;
+use core::alloc::GlobalAlloc;
+use metrics::{Counter, Gauge};
+use std::format;
+
+/// Metrics of a `Limit`, created using `Limit::install_metrics`. The values are only updated when
+/// `flush` is called, never inside the allocator, because the recorder may allocate. For example
+/// call `flush` periodically from a background thread, or before the metrics are scraped.
+///
+/// With the prefix `myapp_heap`, these metrics are exported:
+///
+/// * Gauges: `myapp_heap_used`, `myapp_heap_remaining` and `myapp_heap_limit`, in bytes.
+/// * Counters: `myapp_heap_allocations`, `myapp_heap_deallocations` and `myapp_heap_failures`.
+pub struct LimitMetrics<'a, A> {
+    limit: &'a Limit<A>,
+    used: Gauge,
+    remaining: Gauge,
+    limit_bytes: Gauge,
+    allocations: Counter,
+    deallocations: Counter,
+    failures: Counter,
+}
+
+impl<'a, A: GlobalAlloc> LimitMetrics<'a, A> {
+    pub(crate) fn new(limit: &'a Limit<A>, prefix: &str) -> Self {
+        Self {
+            limit,
+            used: metrics::gauge!(format!("{}_used", prefix)),
+            remaining: metrics::gauge!(format!("{}_remaining", prefix)),
+            limit_bytes: metrics::gauge!(format!("{}_limit", prefix)),
+            allocations: metrics::counter!(format!("{}_allocations", prefix)),
+            deallocations: metrics::counter!(format!("{}_deallocations", prefix)),
+            failures: metrics::counter!(format!("{}_failures", prefix)),
+        }
+    }
+
+    /// Updates the metrics with the current statistics of the limit.
+    pub fn flush(&self) {
+        let stats = self.limit.stats();
+        self.used.set(stats.allocated as f64);
+        self.remaining.set(stats.remaining as f64);
+        self.limit_bytes.set(stats.limit as f64);
+        self.allocations.absolute(stats.alloc_count as u64);
+        self.deallocations.absolute(stats.dealloc_count as u64);
+        self.failures.absolute(stats.failed as u64);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use core::alloc::Layout;
+    use metrics_util::debugging::{DebugValue, DebuggingRecorder};
+    use metrics_util::MetricKind;
+    use std::alloc::System;
+    use std::string::{String, ToString};
+    use std::vec::Vec;
+
+    #[test]
+    fn exported_values_match_the_stats() {
+        let recorder = DebuggingRecorder::new();
+        let snapshotter = recorder.snapshotter();
+        let limit = Limit::new(1_000, System);
+        let metrics = metrics::with_local_recorder(&recorder, || limit.install_metrics("heap"));
+        let exported = || {
+            let mut values = snapshotter
+                .snapshot()
+                .into_vec()
+                .into_iter()
+                .map(|(key, _unit, _description, value)| {
+                    let value = match value {
+                        DebugValue::Gauge(v) => v.into_inner(),
+                        DebugValue::Counter(v) => v as f64,
+                        DebugValue::Histogram(_) => unreachable!(),
+                    };
+                    (key.kind(), key.key().name().to_string(), value)
+                })
+                .collect::<Vec<(MetricKind, String, f64)>>();
+            values.sort_by(|a, b| a.1.cmp(&b.1));
+            values
+        };
+        let expected = |limit: &Limit<System>| {
+            let stats = limit.stats();
+            let mut values = [
+                (MetricKind::Counter, "heap_allocations", stats.alloc_count),
+                (
+                    MetricKind::Counter,
+                    "heap_deallocations",
+                    stats.dealloc_count,
+                ),
+                (MetricKind::Counter, "heap_failures", stats.failed),
+                (MetricKind::Gauge, "heap_limit", stats.limit),
+                (MetricKind::Gauge, "heap_remaining", stats.remaining),
+                (MetricKind::Gauge, "heap_used", stats.allocated),
+            ]
+            .map(|(kind, name, value)| (kind, name.to_string(), value as f64));
+            values.sort_by(|a, b| a.1.cmp(&b.1));
+            values.to_vec()
+        };
+
+        let layout = Layout::from_size_align(600, 8).unwrap();
+        let ptr = unsafe { limit.alloc(layout) };
+        // Nothing is exported until flush is called
+        assert!(exported().iter().all(|(_, _, value)| *value == 0.0));
+        metrics.flush();
+        assert_eq!(exported(), expected(&limit));
+        assert!(unsafe { limit.alloc(layout) }.is_null());
+        unsafe { limit.dealloc(ptr, layout) };
+        metrics.flush();
+        let values = expected(&limit);
+        assert_eq!(exported(), values);
+        assert_eq!(
+            values,
+            [
+                (MetricKind::Counter, "heap_allocations".to_string(), 1.0),
+                (MetricKind::Counter, "heap_deallocations".to_string(), 1.0),
+                (MetricKind::Counter, "heap_failures".to_string(), 1.0),
+                (MetricKind::Gauge, "heap_limit".to_string(), 1_000.0),
+                (MetricKind::Gauge, "heap_remaining".to_string(), 1_000.0),
+                (MetricKind::Gauge, "heap_used".to_string(), 0.0),
+            ]
+        );
+    }
+}
@@ -0,0 +1,174 @@
+//! Memory allocated by a closure, see `Limit::measure`.
+use crate::{ConstCounter, ConstLimitTag};
+use core::ptr;
+use core::sync::atomic::AtomicUsize;
+use core::sync::atomic::Ordering::Relaxed;
+use std::cell::Cell;
+
+/// Number of measurements running in all the threads, so allocations only read the thread local
+/// while something is measured.
+static ACTIVE: AtomicUsize = AtomicUsize::new(0);
+
+alloc_local! {
+    /// Measurement of the current thread, its counter is null if there is none.
+    static CURRENT: Cell<Measurement> = Cell::new(Measurement::new(ptr::null()));
+}
+
+/// Memory allocated and deallocated by the current thread while running a closure, returned by
+/// `Limit::measure` and `measure_const`.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct MemoryDelta {
+    /// Bytes allocated, including the growth of reallocations.
+    pub allocated: usize,
+    /// Bytes deallocated, including the shrinking of reallocations.
+    pub deallocated: usize,
+    /// Highest number of bytes allocated at the same time, on top of the memory that was
+    /// allocated before the closure started.
+    pub peak: usize,
+    /// Number of successful allocations, not counting reallocations.
+    pub allocations: usize,
+}
+
+impl MemoryDelta {
+    /// Returns the bytes that are still allocated after the closure returned. This is negative if
+    /// the closure deallocated memory that was allocated before it started.
+    pub fn net(&self) -> isize {
+        (self.allocated as isize).wrapping_sub(self.deallocated as isize)
+    }
+}
+
+#[derive(Clone, Copy)]
+struct Measurement {
+    /// Address of the counter that is measured, the `Limit` or the `ConstCounter`.
+    counter: *const (),
+    delta: MemoryDelta,
+}
+
+impl Measurement {
+    const fn new(counter: *const ()) -> Self {
+        Self {
+            counter,
+            delta: MemoryDelta {
+                allocated: 0,
+                deallocated: 0,
+                peak: 0,
+                allocations: 0,
+            },
+        }
+    }
+
+    fn current(&self) -> usize {
+        self.delta.allocated.saturating_sub(self.delta.deallocated)
+    }
+}
+
+/// Restores the previous measurement of the current thread when dropped, so measurements can be
+/// nested.
+struct MeasureGuard {
+    previous: Measurement,
+}
+
+impl MeasureGuard {
+    fn new(counter: *const ()) -> Self {
+        ACTIVE.fetch_add(1, Relaxed);
+        let previous = CURRENT
+            .try_with(|current| current.replace(Measurement::new(counter)))
+            .unwrap_or(Measurement::new(ptr::null()));
+        Self { previous }
+    }
+}
+
+impl Drop for MeasureGuard {
+    fn drop(&mut self) {
+        let mut previous = self.previous;
+        let _ = CURRENT.try_with(|current| {
+            let inner = current.get();
+            // The memory measured by the inner measurement also belongs to the outer one
+            if previous.counter == inner.counter {
+                let peak = previous.current().saturating_add(inner.delta.peak);
+                let outer = &mut previous.delta;
+                outer.peak = outer.peak.max(peak);
+                outer.allocated += inner.delta.allocated;
+                outer.deallocated += inner.delta.deallocated;
+                outer.allocations += inner.delta.allocations;
+            }
+            current.set(previous);
+        });
+        ACTIVE.fetch_sub(1, Relaxed);
+    }
+}
+
+/// Runs `f` while measuring the memory that the current thread allocates through `counter`.
+pub(crate) fn measure<R>(counter: *const (), f: impl FnOnce() -> R) -> (R, MemoryDelta) {
+    let guard = MeasureGuard::new(counter);
+    let ret = f();
+    let delta = CURRENT
+        .try_with(|current| current.get().delta)
+        .unwrap_or_default();
+    drop(guard);
+    (ret, delta)
+}
+
+/// Calls `f` with the measurement of the current thread, if it measures `counter`.
+fn update(counter: *const (), f: impl FnOnce(&mut Measurement)) {
+    // Fast path when nothing is measured, which does not need the thread local
+    if ACTIVE.load(Relaxed) == 0 {
+        return;
+    }
+    let _ = CURRENT.try_with(|current| {
+        let mut m = current.get();
+        if m.counter == counter {
+            f(&mut m);
+            current.set(m);
+        }
+    });
+}
+
+/// Called after `size` bytes are charged to `counter`.
+pub(crate) fn charged(counter: *const (), size: usize) {
+    update(counter, |m| {
+        m.delta.allocated = m.delta.allocated.saturating_add(size);
+        m.delta.peak = m.delta.peak.max(m.current());
+    });
+}
+
+/// Called after `size` bytes are credited to `counter`.
+pub(crate) fn credited(counter: *const (), size: usize) {
+    update(counter, |m| {
+        m.delta.deallocated = m.delta.deallocated.saturating_add(size)
+    });
+}
+
+/// Called after a successful allocation through `counter`.
+pub(crate) fn allocated(counter: *const ()) {
+    update(counter, |m| m.delta.allocations += 1);
+}
+
+/// Address of the counter of the tag `T`, used to measure the `ConstLimit` with that tag.
+pub(crate) fn const_counter<T: ConstLimitTag>() -> *const () {
+    T::counter() as *const ConstCounter as *const ()
+}
+
+/// Same as `Limit::measure`, but measures the memory allocated through all the `ConstLimit` with
+/// the tag `T`, by the current thread.
+///
+/// ```
+/// use limit_alloc::{measure_const, ConstLimit, DefaultTag};
+/// use std::alloc::System;
+///
+/// #[global_allocator]
+/// static A: ConstLimit<System, { 64 << 20 }> = ConstLimit::new(System);
+///
+/// fn main() {
+///     let (v, delta) = measure_const::<DefaultTag, _>(|| Vec::<u8>::with_capacity(1_000));
+///     assert!(delta.allocated >= 1_000);
+///     assert!(delta.peak >= 1_000);
+///     assert!(delta.allocations >= 1);
+///     let ((), delta) = measure_const::<DefaultTag, _>(|| drop(v));
+///     assert_eq!(delta.deallocated, 1_000);
+///     assert_eq!(delta.net(), -1_000);
+/// }
+/// ```
+pub fn measure_const<T: ConstLimitTag, R>(f: impl FnOnce() -> R) -> (R, MemoryDelta) {
+    measure(const_counter::<T>(), f)
+}
@@ -0,0 +1,229 @@
+//! Allocator that limits how fast memory can be allocated.
+use crate::Limit;
+use core::alloc::{GlobalAlloc, Layout};
+use core::ops::Deref;
+use core::ptr;
+use core::sync::atomic::Ordering::SeqCst;
+use core::sync::atomic::{AtomicU64, AtomicUsize};
+use std::sync::OnceLock;
+use std::time::{Duration, Instant};
+
+/// `Limit` that also limits the rate of allocation, for example to at most 10MB per second, to
+/// smooth out bursts of memory pressure. It derefs to the inner `Limit`, so the statistics are
+/// available, use `Limit::new(usize::MAX, alloc)` to only limit the rate.
+///
+/// This is a token bucket: each allocation takes `layout.size()` tokens from the bucket, and fails
+/// if there are not enough of them. Growing an allocation using `realloc` takes the difference,
+/// and deallocating does not give anything back. The bucket holds at most `burst` tokens, and it
+/// is refilled with `bytes_per_second` tokens per second, in steps of `refill_interval`. The clock
+/// is only read when the bucket does not have enough tokens, so allocations that fit in the
+/// bucket are as cheap as with a `Limit`. A longer `refill_interval` means fewer updates when
+/// many threads are waiting for tokens, but a coarser rate.
+///
+/// The bucket starts full, so right after startup `burst` bytes can be allocated at once, before
+/// the rate applies. Allocations bigger than `burst` always fail. Each allocation that fails
+/// because of the rate counts as a rejected allocation and calls the OOM handler of the inner
+/// `Limit`.
+///
+/// ```
+/// use limit_alloc::{Limit, RateLimit};
+/// use std::alloc::{GlobalAlloc, Layout, System};
+/// use std::time::Duration;
+///
+/// let limit = RateLimit::with_refill(
+///     Limit::new(usize::MAX, System),
+///     1_000_000,
+///     1_000,
+///     Duration::from_millis(1),
+/// );
+/// let layout = Layout::from_size_align(600, 1).unwrap();
+/// unsafe {
+///     // The bucket starts full
+///     let a = limit.alloc(layout);
+///     assert!(!a.is_null());
+///     // It is refilled with 1_000 bytes every millisecond
+///     while limit.tokens() < 600 {
+///         std::thread::sleep(Duration::from_millis(1));
+///     }
+///     let b = limit.alloc(layout);
+///     assert!(!b.is_null());
+///     limit.dealloc(a, layout);
+///     limit.dealloc(b, layout);
+/// }
+/// ```
+pub struct RateLimit<A> {
+    limit: Limit<A>,
+    bytes_per_second: usize,
+    burst: usize,
+    /// `refill_interval` in nanoseconds, at least 1.
+    interval: u64,
+    /// Tokens in the bucket, in bytes.
+    tokens: AtomicUsize,
+    /// Time of the last refill in nanoseconds since `start`, always a multiple of `interval`.
+    refilled: AtomicU64,
+    /// Time of the first allocation.
+    start: OnceLock<Instant>,
+}
+
+impl<A: GlobalAlloc> RateLimit<A> {
+    /// Allocates at most `bytes_per_second` bytes per second, and at most that many at once.
+    /// The bucket is refilled every millisecond.
+    pub const fn new(limit: Limit<A>, bytes_per_second: usize) -> Self {
+        Self::with_refill(
+            limit,
+            bytes_per_second,
+            bytes_per_second,
+            Duration::from_millis(1),
+        )
+    }
+
+    /// Allocates at most `bytes_per_second` bytes per second, and at most `burst` bytes at once.
+    /// The bucket is refilled every `refill_interval`.
+    pub const fn with_refill(
+        limit: Limit<A>,
+        bytes_per_second: usize,
+        burst: usize,
+        refill_interval: Duration,
+    ) -> Self {
+        let interval = refill_interval.as_nanos();
+        let interval = if interval == 0 {
+            1
+        } else if interval > u64::MAX as u128 {
+            u64::MAX
+        } else {
+            interval as u64
+        };
+        Self {
+            limit,
+            bytes_per_second,
+            burst,
+            interval,
+            tokens: AtomicUsize::new(burst),
+            refilled: AtomicU64::new(0),
+            start: OnceLock::new(),
+        }
+    }
+
+    /// Returns the rate, in bytes per second.
+    pub fn bytes_per_second(&self) -> usize {
+        self.bytes_per_second
+    }
+
+    /// Returns the maximum number of tokens in the bucket, in bytes.
+    pub fn burst(&self) -> usize {
+        self.burst
+    }
+
+    /// Returns the number of tokens in the bucket right now, in bytes. This refills the bucket
+    /// first.
+    pub fn tokens(&self) -> usize {
+        self.refill();
+        self.tokens.load(SeqCst)
+    }
+
+    /// Returns None if the rate or the memory limit would be exceeded, see `Limit::try_alloc`.
+    ///
+    /// # Safety
+    ///
+    /// The same restrictions as `GlobalAlloc::alloc`.
+    pub unsafe fn try_alloc(&self, layout: Layout) -> Option<*mut u8> {
+        self.with_tokens(layout, layout.size(), || self.limit.try_alloc(layout))
+    }
+
+    /// Takes `size` tokens and calls `f`, and gives them back if `f` fails. Returns None if there
+    /// are not enough tokens.
+    fn with_tokens(
+        &self,
+        layout: Layout,
+        size: usize,
+        f: impl FnOnce() -> Option<*mut u8>,
+    ) -> Option<*mut u8> {
+        // The clock starts on the first allocation, after that it is only read when the bucket
+        // does not have enough tokens
+        self.start.get_or_init(Instant::now);
+        if !self.take(size) {
+            self.refill();
+            if !self.take(size) {
+                self.limit.reject(layout);
+                return None;
+            }
+        }
+        let ret = f();
+        if !matches!(ret, Some(ptr) if !ptr.is_null()) {
+            self.give_back(size);
+        }
+        ret
+    }
+
+    fn take(&self, size: usize) -> bool {
+        self.tokens
+            .fetch_update(SeqCst, SeqCst, |old| old.checked_sub(size))
+            .is_ok()
+    }
+
+    fn give_back(&self, size: usize) {
+        let _ = self.tokens.fetch_update(SeqCst, SeqCst, |old| {
+            Some(old.saturating_add(size).min(self.burst))
+        });
+    }
+
+    /// Adds the tokens of the intervals that elapsed since the last refill.
+    fn refill(&self) {
+        let start = *self.start.get_or_init(Instant::now);
+        let now = u64::try_from(start.elapsed().as_nanos()).unwrap_or(u64::MAX);
+        let last = self.refilled.load(SeqCst);
+        let Some(elapsed) = now.checked_sub(last) else {
+            return;
+        };
+        let next = last + elapsed / self.interval * self.interval;
+        if next == last {
+            return;
+        }
+        // Only the thread that moves the refill time adds the tokens
+        if self
+            .refilled
+            .compare_exchange(last, next, SeqCst, SeqCst)
+            .is_err()
+        {
+            return;
+        }
+        // Compute the tokens from the start, so the rounding errors do not accumulate
+        let tokens_at = |nanos: u64| nanos as u128 * self.bytes_per_second as u128 / 1_000_000_000;
+        let added = usize::try_from(tokens_at(next) - tokens_at(last)).unwrap_or(usize::MAX);
+        self.give_back(added);
+    }
+}
+
+impl<A> Deref for RateLimit<A> {
+    type Target = Limit<A>;
+
+    fn deref(&self) -> &Limit<A> {
+        &self.limit
+    }
+}
+
+unsafe impl<A: GlobalAlloc> GlobalAlloc for RateLimit<A> {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        self.try_alloc(layout).unwrap_or(ptr::null_mut())
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        self.limit.dealloc(ptr, layout)
+    }
+
+    unsafe fn alloc_zeroed(&self, layout: Layout) -> *mut u8 {
+        self.with_tokens(layout, layout.size(), || {
+            self.limit.try_alloc_zeroed(layout)
+        })
+        .unwrap_or(ptr::null_mut())
+    }
+
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        let grown = new_size.saturating_sub(layout.size());
+        let new_layout = Layout::from_size_align_unchecked(new_size, layout.align());
+        self.with_tokens(new_layout, grown, || {
+            self.limit.try_realloc(ptr, layout, new_size)
+        })
+        .unwrap_or(ptr::null_mut())
+    }
+}
@@ -0,0 +1,256 @@
+//! Report rejected allocations to `tracing` or `log`. Emitting a message may allocate, so a thread
+//! local flag is set while reporting: allocations made by the subscriber or logger ignore the byte
+//! limit, and a rejection that happens anyway is not reported again, so the report can never
+//! recurse.
+use core::alloc::Layout;
+use std::cell::Cell;
+
+alloc_local! {
+    static REPORTING: Cell<bool> = Cell::new(false);
+}
+
+/// Returns true while the current thread is reporting a rejected allocation.
+pub(crate) fn is_reporting() -> bool {
+    REPORTING.try_with(Cell::get).unwrap_or(false)
+}
+
+/// Clears the flag when dropped, also if the subscriber panics.
+struct ReportingGuard;
+
+impl Drop for ReportingGuard {
+    fn drop(&mut self) {
+        let _ = REPORTING.try_with(|reporting| reporting.set(false));
+    }
+}
+
+/// Runs `f` with the reporting flag set, unless the current thread is already reporting.
+fn report(f: impl FnOnce()) {
+    // Also skip the message if the thread local is not available, because then allocations made
+    // by the subscriber cannot be detected
+    if !matches!(
+        REPORTING.try_with(|reporting| reporting.replace(true)),
+        Ok(false)
+    ) {
+        return;
+    }
+    let _guard = ReportingGuard;
+    f();
+}
+
+/// Emits a message for an allocation of `layout` that was rejected by the limit.
+pub(crate) fn rejected(layout: Layout, remaining: usize, limit: usize) {
+    report(|| {
+        #[cfg(feature = "tracing")]
+        tracing::warn!(
+            size = layout.size(),
+            align = layout.align(),
+            remaining,
+            limit,
+            "allocation rejected by the memory limit"
+        );
+        #[cfg(feature = "log")]
+        log::error!(
+            "allocation rejected by the memory limit: size {}, align {}, {} of {} bytes remaining",
+            layout.size(),
+            layout.align(),
+            remaining,
+            limit
+        );
+    });
+}
+
+/// Emits an event when the allocated memory reaches the watermark `threshold`.
+#[cfg(feature = "tracing")]
+pub(crate) fn watermark(allocated: usize, threshold: usize, limit: usize) {
+    report(|| {
+        tracing::info!(
+            allocated,
+            threshold,
+            limit,
+            "allocated memory reached a watermark"
+        );
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    #[cfg(feature = "log")]
+    #[test]
+    fn one_log_message_per_rejection() {
+        use crate::Limit;
+        use core::alloc::{GlobalAlloc, Layout};
+        use std::alloc::System;
+        use std::string::{String, ToString};
+        use std::sync::Mutex;
+        use std::thread::{self, ThreadId};
+        use std::vec::Vec;
+
+        /// Memory limit that is always exhausted, the logger allocates through it.
+        static FULL: Limit<System> = Limit::new(0, System);
+
+        /// Keeps the messages with the thread that logged them, other tests run in parallel.
+        struct Capture(Mutex<Vec<(ThreadId, String)>>);
+
+        impl log::Log for Capture {
+            fn enabled(&self, _metadata: &log::Metadata<'_>) -> bool {
+                true
+            }
+
+            fn log(&self, record: &log::Record<'_>) {
+                // The logger may allocate through the limit that rejected the allocation
+                let layout = Layout::new::<[u8; 64]>();
+                let ptr = unsafe { FULL.alloc(layout) };
+                assert!(!ptr.is_null());
+                unsafe { FULL.dealloc(ptr, layout) };
+                let message = record.args().to_string();
+                let mut records = self.0.lock().unwrap();
+                records.push((thread::current().id(), message));
+            }
+
+            fn flush(&self) {}
+        }
+
+        static CAPTURE: Capture = Capture(Mutex::new(Vec::new()));
+
+        log::set_logger(&CAPTURE).unwrap();
+        log::set_max_level(log::LevelFilter::Trace);
+        let messages = || {
+            let records = CAPTURE.0.lock().unwrap();
+            let id = thread::current().id();
+            records
+                .iter()
+                .filter(|(thread, _)| *thread == id)
+                .map(|(_, message)| message.clone())
+                .collect::<Vec<_>>()
+        };
+
+        let limit = Limit::new(1_000, System);
+        let layout = Layout::from_size_align(800, 8).unwrap();
+        let ptr = unsafe { limit.alloc(layout) };
+        assert!(messages().is_empty());
+        for failures in 1..=3 {
+            assert!(unsafe { limit.try_alloc(layout) }.is_none());
+            let messages = messages();
+            assert_eq!(messages.len(), failures);
+            assert_eq!(
+                messages[failures - 1],
+                "allocation rejected by the memory limit: size 800, align 8, \
+                 200 of 1000 bytes remaining"
+            );
+        }
+        // The allocation made by the logger was not rejected, so it did not log
+        assert_eq!(FULL.failed_allocations(), 0);
+        assert_eq!(FULL.allocated(), 0);
+        unsafe { limit.dealloc(ptr, layout) };
+    }
+
+    #[cfg(feature = "tracing")]
+    #[test]
+    fn rejection_and_watermark_events() {
+        use crate::Limit;
+        use core::alloc::{GlobalAlloc, Layout};
+        use core::fmt;
+        use std::alloc::System;
+        use std::string::{String, ToString};
+        use std::sync::Mutex;
+        use std::thread::{self, ThreadId};
+        use std::vec::Vec;
+        use tracing::field::{Field, Visit};
+        use tracing::span::{Attributes, Id, Record};
+        use tracing::{Event, Metadata, Subscriber};
+
+        /// Memory limit that is always exhausted, the subscriber allocates through it.
+        static FULL: Limit<System> = Limit::new(0, System);
+
+        /// Fields of an event, formatted as `name=value`.
+        #[derive(Default)]
+        struct Fields(Vec<String>);
+
+        impl Visit for Fields {
+            fn record_u64(&mut self, field: &Field, value: u64) {
+                self.0.push(std::format!("{}={}", field.name(), value));
+            }
+
+            fn record_debug(&mut self, field: &Field, value: &dyn fmt::Debug) {
+                self.0.push(std::format!("{}={:?}", field.name(), value));
+            }
+        }
+
+        /// Events with the thread that emitted them, other tests run in parallel.
+        static EVENTS: Mutex<Vec<(ThreadId, tracing::Level, Vec<String>)>> = Mutex::new(Vec::new());
+
+        struct Capture;
+
+        impl Subscriber for Capture {
+            fn enabled(&self, _metadata: &Metadata<'_>) -> bool {
+                true
+            }
+
+            fn new_span(&self, _span: &Attributes<'_>) -> Id {
+                Id::from_u64(1)
+            }
+
+            fn record(&self, _span: &Id, _values: &Record<'_>) {}
+
+            fn record_follows_from(&self, _span: &Id, _follows: &Id) {}
+
+            fn event(&self, event: &Event<'_>) {
+                // The subscriber may allocate through the limit that emitted the event
+                let layout = Layout::new::<[u8; 64]>();
+                let ptr = unsafe { FULL.alloc(layout) };
+                assert!(!ptr.is_null());
+                unsafe { FULL.dealloc(ptr, layout) };
+                let mut fields = Fields::default();
+                event.record(&mut fields);
+                let level = *event.metadata().level();
+                let mut events = EVENTS.lock().unwrap();
+                events.push((thread::current().id(), level, fields.0));
+            }
+
+            fn enter(&self, _span: &Id) {}
+
+            fn exit(&self, _span: &Id) {}
+        }
+
+        tracing::subscriber::set_global_default(Capture).unwrap();
+        let events = || {
+            let events = EVENTS.lock().unwrap();
+            let id = thread::current().id();
+            events
+                .iter()
+                .filter(|(thread, _, _)| *thread == id)
+                .map(|(_, level, fields)| (*level, fields.join(" ")))
+                .collect::<Vec<_>>()
+        };
+
+        let limit = Limit::new(1_000, System);
+        fn ignore(_allocated: usize, _limit: usize) {}
+        limit.set_watermarks(&[(500, ignore)]);
+        let layout = Layout::from_size_align(800, 8).unwrap();
+        let ptr = unsafe { limit.alloc(layout) };
+        assert_eq!(
+            events(),
+            [(
+                tracing::Level::INFO,
+                "message=allocated memory reached a watermark allocated=800 threshold=500 \
+                 limit=1000"
+                    .to_string()
+            )]
+        );
+        assert!(unsafe { limit.try_alloc(layout) }.is_none());
+        let events = events();
+        assert_eq!(events.len(), 2);
+        assert_eq!(
+            events[1],
+            (
+                tracing::Level::WARN,
+                "message=allocation rejected by the memory limit size=800 align=8 remaining=200 \
+                 limit=1000"
+                    .to_string()
+            )
+        );
+        // The allocation made by the subscriber was not rejected
+        assert_eq!(FULL.failed_allocations(), 0);
+        unsafe { limit.dealloc(ptr, layout) };
+    }
+}
@@ -0,0 +1,259 @@
+//! Memory reserved in advance, see `Limit::reserve`.
+use crate::Limit;
+use core::alloc::{GlobalAlloc, Layout};
+use core::ptr;
+use core::sync::atomic::AtomicUsize;
+use core::sync::atomic::Ordering::{Relaxed, SeqCst};
+
+/// Part of the memory of a `Limit` that was reserved using `Limit::reserve`. The reserved bytes
+/// are counted as allocated, so other users of the limit cannot use them, and they are returned
+/// to the limit when the `Reservation` is dropped.
+///
+/// A `Reservation` is also an allocator: allocations made through it use the reserved bytes
+/// instead of the remaining memory of the limit, and fail if there are not enough reserved bytes
+/// left. Deallocating through the `Reservation` returns the bytes to the reservation, and
+/// deallocating through the `Limit` returns them to the limit, so memory allocated using a
+/// `Reservation` can outlive it.
+pub struct Reservation<'a, A: GlobalAlloc> {
+    limit: &'a Limit<A>,
+    /// Reserved bytes that are not used by any allocation.
+    available: AtomicUsize,
+}
+
+impl<'a, A: GlobalAlloc> Reservation<'a, A> {
+    pub(crate) fn new(limit: &'a Limit<A>, bytes: usize) -> Self {
+        #[cfg(feature = "verify-accounting")]
+        limit.reserved.fetch_add(bytes, SeqCst);
+        Self {
+            limit,
+            available: AtomicUsize::new(bytes),
+        }
+    }
+
+    /// Returns the reserved bytes that are not used yet.
+    pub fn remaining(&self) -> usize {
+        self.available.load(SeqCst)
+    }
+
+    /// Takes `bytes` out of the reservation, they stay counted as allocated by the limit and will
+    /// not be returned when the reservation is dropped. Use this when the memory is allocated
+    /// using the inner allocator directly, and will be deallocated through the `Limit`, but note
+    /// that the `debug-tracking` feature reports those deallocations as unknown pointers. Returns
+    /// false if there are not enough reserved bytes left, in that case nothing is modified.
+    pub fn consume(&self, bytes: usize) -> bool {
+        let consumed = self
+            .available
+            .fetch_update(SeqCst, SeqCst, |old| old.checked_sub(bytes))
+            .is_ok();
+        #[cfg(feature = "verify-accounting")]
+        if consumed {
+            self.limit.reserved.fetch_sub(bytes, SeqCst);
+        }
+        consumed
+    }
+
+    /// Returns `bytes` to the reservation.
+    fn give_back(&self, bytes: usize) {
+        self.available.fetch_add(bytes, SeqCst);
+        #[cfg(feature = "verify-accounting")]
+        self.limit.reserved.fetch_add(bytes, SeqCst);
+    }
+
+    /// Called after an allocation through the reservation succeeds, when `counted` bytes were
+    /// taken from the reservation but the limit counts `actual` bytes for it, see
+    /// `Limit::allocation_size`. Extra bytes are charged to the limit even if that exceeds it,
+    /// because the memory is already allocated, and missing bytes go back to the reservation.
+    /// Either way, deallocating through the `Limit` or the `Reservation` credits `actual`.
+    #[cfg(feature = "usable-size")]
+    fn settle(&self, counted: usize, actual: usize) {
+        if actual > counted {
+            self.limit.adjust(0, actual - counted);
+        } else {
+            self.give_back(counted - actual);
+        }
+    }
+
+    unsafe fn alloc_with(&self, layout: Layout, f: impl FnOnce(&A, Layout) -> *mut u8) -> *mut u8 {
+        let size = self.limit.accounting.size(layout);
+        if !self.consume(size) {
+            self.limit.count_rejection();
+            return ptr::null_mut();
+        }
+        // The number of live allocations is not reserved
+        if !self.limit.charge_count() {
+            self.give_back(size);
+            self.limit.count_rejection();
+            return ptr::null_mut();
+        }
+        let ret = f(&self.limit.alloc, layout);
+        if ret.is_null() {
+            self.give_back(size);
+            self.limit.credit_count();
+            self.limit.failed_allocations.fetch_add(1, Relaxed);
+        } else {
+            let size = self.limit.allocation_size(ret, layout);
+            #[cfg(feature = "usable-size")]
+            self.settle(self.limit.accounting.size(layout), size);
+            self.limit.allocations.fetch_add(1, Relaxed);
+            self.limit.bytes_allocated_total.fetch_add(size, Relaxed);
+            #[cfg(feature = "histogram")]
+            self.limit.histogram.allocated(layout.size());
+            #[cfg(feature = "debug-tracking")]
+            self.limit.tracker.allocated(ret, layout, size);
+        }
+        ret
+    }
+}
+
+impl<A: GlobalAlloc> Drop for Reservation<'_, A> {
+    fn drop(&mut self) {
+        #[cfg(feature = "verify-accounting")]
+        self.limit
+            .reserved
+            .fetch_sub(*self.available.get_mut(), SeqCst);
+        let available = *self.available.get_mut();
+        self.limit.credit(available);
+        if available != 0 {
+            self.limit.notify_freed();
+        }
+    }
+}
+
+unsafe impl<A: GlobalAlloc> GlobalAlloc for Reservation<'_, A> {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        self.alloc_with(layout, |alloc, layout| alloc.alloc(layout))
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        // Same size as `Limit::dealloc`, so memory can be deallocated through either
+        let size = self.limit.allocation_size(ptr, layout);
+        // Leak the pointer if it is not a live allocation, see `Limit::assert_accounting`
+        #[cfg(feature = "debug-tracking")]
+        if !self.limit.tracker.deallocated(ptr, layout, size) {
+            return;
+        }
+        self.limit.alloc.dealloc(ptr, layout);
+        self.give_back(size);
+        self.limit.credit_count();
+        self.limit.deallocations.fetch_add(1, Relaxed);
+        #[cfg(feature = "histogram")]
+        self.limit.histogram.deallocated(layout.size());
+    }
+
+    unsafe fn alloc_zeroed(&self, layout: Layout) -> *mut u8 {
+        self.alloc_with(layout, |alloc, layout| alloc.alloc_zeroed(layout))
+    }
+
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        let new_layout = Layout::from_size_align_unchecked(new_size, layout.align());
+        let old_counted = self.limit.allocation_size(ptr, layout);
+        let new_counted = self.limit.accounting.size(new_layout);
+        if new_counted > old_counted {
+            let delta = new_counted - old_counted;
+            if !self.consume(delta) {
+                self.limit.count_rejection();
+                return ptr::null_mut();
+            }
+            let ret = self.limit.alloc.realloc(ptr, layout, new_size);
+            if ret.is_null() {
+                self.give_back(delta);
+                self.limit.failed_allocations.fetch_add(1, Relaxed);
+            } else {
+                #[cfg(feature = "usable-size")]
+                let delta = {
+                    let actual = self.limit.allocation_size(ret, new_layout);
+                    self.settle(new_counted, actual);
+                    actual.saturating_sub(old_counted)
+                };
+                self.limit.bytes_allocated_total.fetch_add(delta, Relaxed);
+                #[cfg(feature = "histogram")]
+                self.limit.histogram.reallocated(layout.size(), new_size);
+                #[cfg(feature = "debug-tracking")]
+                self.limit.tracker.reallocated(ptr, layout, ret, new_layout);
+            }
+            ret
+        } else {
+            let ret = self.limit.alloc.realloc(ptr, layout, new_size);
+            if ret.is_null() {
+                self.limit.failed_allocations.fetch_add(1, Relaxed);
+            } else {
+                self.give_back(old_counted - new_counted);
+                #[cfg(feature = "usable-size")]
+                self.settle(new_counted, self.limit.allocation_size(ret, new_layout));
+                #[cfg(feature = "histogram")]
+                self.limit.histogram.reallocated(layout.size(), new_size);
+                #[cfg(feature = "debug-tracking")]
+                self.limit.tracker.reallocated(ptr, layout, ret, new_layout);
+            }
+            ret
+        }
+    }
+}
+
+#[cfg(all(test, feature = "std"))]
+mod tests {
+    use super::*;
+    use std::alloc::System;
+
+    const LAYOUT: Layout = unsafe { Layout::from_size_align_unchecked(100, 8) };
+
+    #[test]
+    fn memory_can_be_deallocated_through_the_limit() {
+        let limit = Limit::new(1_000, System);
+        let reservation = limit.reserve(500).unwrap();
+        let a = unsafe { reservation.alloc(LAYOUT) };
+        let b = unsafe { reservation.alloc(LAYOUT) };
+        assert_eq!(reservation.remaining(), 300);
+        unsafe { limit.dealloc(a, LAYOUT) };
+        unsafe { reservation.dealloc(b, LAYOUT) };
+        assert_eq!(reservation.remaining(), 400);
+        assert_eq!(limit.allocated(), 400);
+        drop(reservation);
+        assert_eq!(limit.allocated(), 0);
+        assert_eq!((limit.alloc_count(), limit.dealloc_count()), (2, 2));
+    }
+
+    #[cfg(feature = "usable-size")]
+    #[test]
+    fn usable_size_is_charged_and_credited_the_same_way_as_the_limit() {
+        // malloc rounds 20 bytes up, so the limit counts more than the requested size
+        let small = Layout::from_size_align(20, 1).unwrap();
+        let limit = Limit::with_usable_size(10_000);
+        let reservation = limit.reserve(1_000).unwrap();
+        let ptr = unsafe { reservation.alloc(small) };
+        let usable = unsafe { limit.allocation_size(ptr, small) };
+        assert!(usable >= small.size());
+        assert_eq!(limit.allocated(), 1_000 - 20 + usable);
+        // Deallocating through the limit credits the usable size, which was charged
+        unsafe { limit.dealloc(ptr, small) };
+        assert_eq!(limit.allocated(), 1_000 - 20);
+        drop(reservation);
+        assert_eq!(limit.allocated(), 0);
+
+        let reservation = limit.reserve(1_000).unwrap();
+        let ptr = unsafe { reservation.alloc(small) };
+        let ptr = unsafe { reservation.realloc(ptr, small, 300) };
+        assert!(!ptr.is_null());
+        let grown = Layout::from_size_align(300, 1).unwrap();
+        let ptr = unsafe { reservation.realloc(ptr, grown, 10) };
+        assert!(!ptr.is_null());
+        unsafe { reservation.dealloc(ptr, Layout::from_size_align(10, 1).unwrap()) };
+        drop(reservation);
+        assert_eq!(limit.allocated(), 0);
+    }
+
+    #[cfg(feature = "histogram")]
+    #[test]
+    fn histogram_counts_reservation_allocations() {
+        let limit = Limit::new(1_000, System);
+        let reservation = limit.reserve(500).unwrap();
+        let a = unsafe { reservation.alloc(LAYOUT) };
+        let b = unsafe { reservation.alloc(LAYOUT) };
+        let class = limit.size_histogram().class_of(LAYOUT.size());
+        assert_eq!((class.total, class.live), (2, 2));
+        unsafe { limit.dealloc(a, LAYOUT) };
+        unsafe { reservation.dealloc(b, LAYOUT) };
+        let class = limit.size_histogram().class_of(LAYOUT.size());
+        assert_eq!((class.total, class.live), (2, 0));
+    }
+}
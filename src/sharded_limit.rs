@@ -0,0 +1,291 @@
+//! Allocator that splits the limit between a few counters, to reduce contention when many threads
+//! allocate at the same time.
+use core::alloc::{GlobalAlloc, Layout};
+use core::hint;
+use core::ptr;
+use core::sync::atomic::Ordering::{Acquire, Relaxed, Release, SeqCst};
+use core::sync::atomic::{AtomicBool, AtomicUsize};
+use std::cell::Cell;
+
+/// Counter aligned to a cache line, so that threads using different shards do not slow down each
+/// other.
+#[repr(align(64))]
+struct Shard {
+    /// Memory that can still be allocated from this shard, in bytes.
+    available: AtomicUsize,
+}
+
+/// Index of the next shard assigned to a thread.
+static NEXT_SHARD: AtomicUsize = AtomicUsize::new(0);
+
+alloc_local! {
+    static SHARD: Cell<usize> = Cell::new(usize::MAX);
+}
+
+/// Returns a number that is different for each thread, until there are too many threads.
+fn thread_shard() -> usize {
+    SHARD
+        .try_with(|shard| {
+            if shard.get() == usize::MAX {
+                shard.set(NEXT_SHARD.fetch_add(1, Relaxed));
+            }
+            shard.get()
+        })
+        // The thread local is not available, any shard works
+        .unwrap_or(0)
+}
+
+/// Allocator with a memory limit, same as `Limit`, but the remaining memory is split between
+/// `SHARDS` counters. Each thread allocates from its own shard, so with many threads allocating
+/// small blocks at the same time they do not all update the same atomic counter. Use this when a
+/// benchmark shows that the counter of `Limit` is a bottleneck, otherwise `Limit` is simpler and
+/// has more features.
+///
+/// When the shard of a thread does not have enough memory left, the thread takes the remaining
+/// memory of all the other shards. Only one thread does this at a time, so an allocation only
+/// fails if the sum of all the shards is not enough, or if other threads deallocate memory at the
+/// same time. Deallocated memory is given back to the shard of the thread that deallocates it.
+/// The sum of all the shards plus the allocated memory is always equal to the limit.
+///
+/// ```
+/// use limit_alloc::ShardedLimit;
+/// use std::alloc::System;
+///
+/// #[global_allocator]
+/// static A: ShardedLimit<System, 16> = ShardedLimit::new(1_000_000_000, System);
+/// ```
+pub struct ShardedLimit<A, const SHARDS: usize> {
+    shards: [Shard; SHARDS],
+    limit: usize,
+    /// Only one thread can move memory between shards at the same time.
+    rebalancing: AtomicBool,
+    alloc: A,
+}
+
+impl<A: GlobalAlloc, const SHARDS: usize> ShardedLimit<A, SHARDS> {
+    pub const fn new(limit: usize, alloc: A) -> Self {
+        const { assert!(SHARDS > 0, "ShardedLimit needs at least one shard") };
+        let mut shards = [const {
+            Shard {
+                available: AtomicUsize::new(0),
+            }
+        }; SHARDS];
+        let mut i = 0;
+        while i < SHARDS {
+            let mut available = limit / SHARDS;
+            if i == 0 {
+                available += limit % SHARDS;
+            }
+            shards[i] = Shard {
+                available: AtomicUsize::new(available),
+            };
+            i += 1;
+        }
+        Self {
+            shards,
+            limit,
+            rebalancing: AtomicBool::new(false),
+            alloc,
+        }
+    }
+
+    /// Returns None if the memory limit would be exhausted after allocating.
+    ///
+    /// # Safety
+    ///
+    /// The same restrictions as `GlobalAlloc::alloc`.
+    pub unsafe fn try_alloc(&self, layout: Layout) -> Option<*mut u8> {
+        self.alloc_with(layout, |alloc, layout| alloc.alloc(layout))
+    }
+
+    /// Returns remaining memory in bytes, the sum of all the shards. This value does not
+    /// guarantee that an allocation of x bytes will succeed.
+    pub fn remaining(&self) -> usize {
+        self.shards
+            .iter()
+            .map(|shard| shard.available.load(SeqCst))
+            .sum()
+    }
+
+    /// Returns the memory that is currently allocated, in bytes. Same as `remaining`, this reads
+    /// each shard separately, so it is only approximate while other threads are allocating.
+    pub fn allocated(&self) -> usize {
+        self.limit.saturating_sub(self.remaining())
+    }
+
+    /// Returns the memory limit in bytes.
+    pub fn limit(&self) -> usize {
+        self.limit
+    }
+
+    unsafe fn alloc_with(
+        &self,
+        layout: Layout,
+        f: impl FnOnce(&A, Layout) -> *mut u8,
+    ) -> Option<*mut u8> {
+        if !self.charge(layout.size()) {
+            return None;
+        }
+        let ret = f(&self.alloc, layout);
+        if ret.is_null() {
+            // Nothing was actually allocated, so give back the size
+            self.credit(layout.size());
+        }
+
+        Some(ret)
+    }
+
+    fn shard(&self) -> &Shard {
+        &self.shards[thread_shard() % SHARDS]
+    }
+
+    /// Take `size` bytes from the shard of the current thread, or from all the shards if that one
+    /// does not have enough. Returns false if there is not enough memory, in that case the
+    /// shards have the same total as before.
+    fn charge(&self, size: usize) -> bool {
+        let shard = self.shard();
+        if shard
+            .available
+            .fetch_update(SeqCst, SeqCst, |old| old.checked_sub(size))
+            .is_ok()
+        {
+            return true;
+        }
+        self.rebalance(shard, size)
+    }
+
+    /// Slow path of `charge`: move the memory of all the shards to `shard`, and take `size`
+    /// bytes from it.
+    #[cold]
+    fn rebalance(&self, shard: &Shard, size: usize) -> bool {
+        while self
+            .rebalancing
+            .compare_exchange_weak(false, true, Acquire, Relaxed)
+            .is_err()
+        {
+            hint::spin_loop();
+        }
+        let mut gathered: usize = 0;
+        for other in &self.shards {
+            gathered += other.available.swap(0, SeqCst);
+            if gathered >= size {
+                break;
+            }
+        }
+        let ok = gathered >= size;
+        if ok {
+            gathered -= size;
+        }
+        // Give back what was not used, this keeps the total unchanged on failure
+        shard.available.fetch_add(gathered, SeqCst);
+        self.rebalancing.store(false, Release);
+        ok
+    }
+
+    /// Give back `size` bytes to the shard of the current thread.
+    fn credit(&self, size: usize) {
+        self.shard().available.fetch_add(size, SeqCst);
+    }
+}
+
+unsafe impl<A: GlobalAlloc, const SHARDS: usize> GlobalAlloc for ShardedLimit<A, SHARDS> {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        self.try_alloc(layout).unwrap_or(ptr::null_mut())
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        self.alloc.dealloc(ptr, layout);
+        self.credit(layout.size());
+    }
+
+    unsafe fn alloc_zeroed(&self, layout: Layout) -> *mut u8 {
+        self.alloc_with(layout, |alloc, layout| alloc.alloc_zeroed(layout))
+            .unwrap_or(ptr::null_mut())
+    }
+
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        let old_size = layout.size();
+        if new_size > old_size {
+            let delta = new_size - old_size;
+            if !self.charge(delta) {
+                return ptr::null_mut();
+            }
+            let ret = self.alloc.realloc(ptr, layout, new_size);
+            if ret.is_null() {
+                // The old allocation is still valid, so only give back the difference
+                self.credit(delta);
+            }
+            ret
+        } else {
+            let ret = self.alloc.realloc(ptr, layout, new_size);
+            if !ret.is_null() {
+                self.credit(old_size - new_size);
+            }
+            ret
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::alloc::System;
+    use std::thread;
+    use std::vec::Vec;
+
+    #[test]
+    fn one_thread_can_use_all_the_shards() {
+        let limit = ShardedLimit::<_, 4>::new(1_003, System);
+        assert_eq!(limit.remaining(), 1_003);
+        // Other threads leave their memory in different shards
+        let layout = Layout::from_size_align(100, 1).unwrap();
+        let others: Vec<usize> = thread::scope(|s| {
+            (0..3)
+                .map(|_| s.spawn(|| unsafe { limit.alloc(layout) } as usize))
+                .collect::<Vec<_>>()
+                .into_iter()
+                .map(|t| t.join().unwrap())
+                .collect()
+        });
+        assert_eq!(limit.allocated(), 300);
+        // The rest is spread over several shards, but it can still be allocated at once
+        let big = Layout::from_size_align(703, 1).unwrap();
+        let ptr = unsafe { limit.alloc(big) };
+        assert!(!ptr.is_null());
+        assert_eq!(limit.remaining(), 0);
+        assert!(unsafe { limit.try_alloc(Layout::new::<u8>()) }.is_none());
+        unsafe {
+            limit.dealloc(ptr, big);
+            for ptr in others {
+                limit.dealloc(ptr as *mut u8, layout);
+            }
+        }
+        assert_eq!(limit.remaining(), 1_003);
+    }
+
+    #[test]
+    fn live_memory_never_exceeds_the_limit() {
+        let limit = ShardedLimit::<_, 4>::new(10_000, System);
+        let live = AtomicUsize::new(0);
+        thread::scope(|s| {
+            for t in 0..8 {
+                let (limit, live) = (&limit, &live);
+                s.spawn(move || {
+                    for i in 0..5_000 {
+                        let layout = Layout::from_size_align(1 + (t * 131 + i) % 1_500, 1).unwrap();
+                        let ptr = unsafe { limit.alloc(layout) };
+                        if ptr.is_null() {
+                            continue;
+                        }
+                        let now = live.fetch_add(layout.size(), SeqCst) + layout.size();
+                        assert!(now <= 10_000, "{}", now);
+                        live.fetch_sub(layout.size(), SeqCst);
+                        unsafe { limit.dealloc(ptr, layout) };
+                    }
+                });
+            }
+        });
+        assert_eq!(limit.remaining(), 10_000);
+        assert_eq!(limit.allocated(), 0);
+    }
+}
@@ -0,0 +1,190 @@
+//! Parse human readable sizes, such as `512K` or `1.5 GiB`.
+use core::fmt;
+
+/// Error returned by `parse_size`.
+#[non_exhaustive]
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum ParseSizeError {
+    /// The string is empty or only contains whitespace.
+    Empty,
+    /// The number before the unit is not valid.
+    InvalidNumber,
+    /// The unit after the number is not known.
+    InvalidUnit,
+    /// The size does not fit in a `usize`.
+    Overflow,
+}
+
+impl fmt::Display for ParseSizeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            ParseSizeError::Empty => "empty size",
+            ParseSizeError::InvalidNumber => "invalid number in size",
+            ParseSizeError::InvalidUnit => "invalid unit in size",
+            ParseSizeError::Overflow => "size is too big",
+        })
+    }
+}
+
+#[cfg(feature = "std")]
+impl std::error::Error for ParseSizeError {}
+
+/// Parses a number of bytes with an optional unit, for example to set the limit from a command
+/// line flag. The number can have a decimal part, and whitespace is allowed around the number and
+/// the unit. Units are not case sensitive:
+///
+/// * `B` or no unit: bytes.
+/// * `K`, `M`, `G`, `T`: powers of 1024, same as `KiB`, `MiB`, `GiB` and `TiB`.
+/// * `KB`, `MB`, `GB`, `TB`: powers of 1000.
+///
+/// Fractions of a byte are truncated.
+///
+/// ```
+/// use limit_alloc::{parse_size, ParseSizeError};
+///
+/// assert_eq!(parse_size("4096"), Ok(4096));
+/// assert_eq!(parse_size("512K"), Ok(512 * 1024));
+/// assert_eq!(parse_size("4MB"), Ok(4_000_000));
+/// assert_eq!(parse_size("2 GiB"), Ok(2 << 30));
+/// assert_eq!(parse_size(" 1.5M "), Ok(1_572_864));
+/// assert_eq!(parse_size("0.5kb"), Ok(500));
+/// assert_eq!(parse_size(""), Err(ParseSizeError::Empty));
+/// assert_eq!(parse_size("M"), Err(ParseSizeError::InvalidNumber));
+/// assert_eq!(parse_size("1.2.3K"), Err(ParseSizeError::InvalidNumber));
+/// assert_eq!(parse_size("4 MX"), Err(ParseSizeError::InvalidUnit));
+/// assert_eq!(parse_size("-1"), Err(ParseSizeError::InvalidNumber));
+/// assert_eq!(parse_size("20000000000000000000000"), Err(ParseSizeError::Overflow));
+/// ```
+pub fn parse_size(s: &str) -> Result<usize, ParseSizeError> {
+    let s = s.trim();
+    if s.is_empty() {
+        return Err(ParseSizeError::Empty);
+    }
+    let unit_start = s
+        .find(|c: char| !c.is_ascii_digit() && c != '.')
+        .unwrap_or(s.len());
+    let (number, unit) = s.split_at(unit_start);
+    let (integer, fraction) = number.split_once('.').unwrap_or((number, ""));
+    if integer.is_empty() && fraction.is_empty() || fraction.contains('.') {
+        return Err(ParseSizeError::InvalidNumber);
+    }
+    let multiplier = unit_multiplier(unit.trim_start()).ok_or(ParseSizeError::InvalidUnit)?;
+
+    // Compute integer * multiplier + fraction * multiplier / 10^digits using integers, so the
+    // result is exact
+    let mut bytes = 0u128;
+    for digit in integer.bytes() {
+        bytes = bytes * 10 + u128::from(digit - b'0');
+        if bytes > usize::MAX as u128 {
+            return Err(ParseSizeError::Overflow);
+        }
+    }
+    bytes *= multiplier;
+    // More digits are always less than a byte, and would overflow
+    let (mut numerator, mut denominator) = (0u128, 1u128);
+    for digit in fraction.bytes().take(24) {
+        numerator = numerator * 10 + u128::from(digit - b'0');
+        denominator *= 10;
+    }
+    bytes += numerator * multiplier / denominator;
+    usize::try_from(bytes).map_err(|_| ParseSizeError::Overflow)
+}
+
+/// Returns the number of bytes of `unit`, or None if it is not known.
+fn unit_multiplier(unit: &str) -> Option<u128> {
+    let (prefix, rest) = match unit.as_bytes().first() {
+        None => return Some(1),
+        Some(prefix) => (prefix.to_ascii_uppercase(), unit.get(1..)?),
+    };
+    let power = match prefix {
+        b'B' if rest.is_empty() => return Some(1),
+        b'K' => 1,
+        b'M' => 2,
+        b'G' => 3,
+        b'T' => 4,
+        _ => return None,
+    };
+    let base: u128 = if rest.eq_ignore_ascii_case("B") {
+        1000
+    } else if rest.is_empty() || rest.eq_ignore_ascii_case("iB") {
+        1024
+    } else {
+        return None;
+    };
+    Some(base.pow(power))
+}
+
+#[cfg(all(test, feature = "std"))]
+mod tests {
+    use super::*;
+    use std::string::ToString;
+
+    #[test]
+    fn fractions() {
+        assert_eq!(parse_size("1.5M"), Ok(1_572_864));
+        assert_eq!(parse_size("1.5MB"), Ok(1_500_000));
+        assert_eq!(parse_size(".5K"), Ok(512));
+        assert_eq!(parse_size("5.K"), Ok(5 * 1024));
+        assert_eq!(parse_size("0.001K"), Ok(1));
+        // Fractions of a byte are truncated
+        assert_eq!(parse_size("1.9"), Ok(1));
+        assert_eq!(parse_size("0.1B"), Ok(0));
+        assert_eq!(
+            parse_size("1.99999999999999999999999999999999999T"),
+            Ok((2 << 40) - 1)
+        );
+    }
+
+    #[test]
+    fn whitespace() {
+        assert_eq!(parse_size(" 1.5 M "), Ok(1_572_864));
+        assert_eq!(parse_size("\t2\tKiB\n"), Ok(2048));
+        assert_eq!(parse_size("   "), Err(ParseSizeError::Empty));
+        assert_eq!(parse_size("1 2K"), Err(ParseSizeError::InvalidUnit));
+        assert_eq!(parse_size("2 K iB"), Err(ParseSizeError::InvalidUnit));
+    }
+
+    #[test]
+    fn units() {
+        assert_eq!(parse_size("1b"), Ok(1));
+        assert_eq!(parse_size("1k"), Ok(1 << 10));
+        assert_eq!(parse_size("1kib"), Ok(1 << 10));
+        assert_eq!(parse_size("1Kb"), Ok(1_000));
+        assert_eq!(parse_size("1G"), Ok(1 << 30));
+        assert_eq!(parse_size("1GB"), Ok(1_000_000_000));
+        assert_eq!(parse_size("1T"), Ok(1 << 40));
+        assert_eq!(parse_size("1TB"), Ok(1_000_000_000_000));
+        assert_eq!(parse_size("1BB"), Err(ParseSizeError::InvalidUnit));
+        assert_eq!(parse_size("1P"), Err(ParseSizeError::InvalidUnit));
+        assert_eq!(parse_size("1Mi"), Err(ParseSizeError::InvalidUnit));
+        assert_eq!(parse_size("1µB"), Err(ParseSizeError::InvalidUnit));
+    }
+
+    #[test]
+    fn invalid_numbers() {
+        assert_eq!(parse_size("."), Err(ParseSizeError::InvalidNumber));
+        assert_eq!(parse_size(".K"), Err(ParseSizeError::InvalidNumber));
+        assert_eq!(parse_size("+1"), Err(ParseSizeError::InvalidNumber));
+        assert_eq!(parse_size("1..5"), Err(ParseSizeError::InvalidNumber));
+        assert_eq!(parse_size("1,5M"), Err(ParseSizeError::InvalidUnit));
+        assert_eq!(parse_size("0x10"), Err(ParseSizeError::InvalidUnit));
+    }
+
+    #[test]
+    fn overflow() {
+        assert_eq!(parse_size(&usize::MAX.to_string()), Ok(usize::MAX));
+        let too_big = (usize::MAX as u128 + 1).to_string();
+        assert_eq!(parse_size(&too_big), Err(ParseSizeError::Overflow));
+        assert_eq!(
+            parse_size("99999999999999999999999999999999999999999"),
+            Err(ParseSizeError::Overflow)
+        );
+        if usize::BITS == 64 {
+            assert_eq!(parse_size("16777216T"), Err(ParseSizeError::Overflow));
+            assert_eq!(
+                parse_size("16777215.5T"),
+                Ok((16_777_215 << 40) + (1 << 39))
+            );
+        }
+    }
+}
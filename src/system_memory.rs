@@ -0,0 +1,364 @@
+//! Query the total physical memory of the machine, see `Limit::percent_of_system`, and the
+//! memory limit of the cgroup, see `Limit::from_cgroup`.
+
+/// Returns the total physical memory of the machine in bytes, or None if it cannot be queried on
+/// this platform. This does not allocate.
+pub fn total_system_memory() -> Option<usize> {
+    imp::total_system_memory()
+}
+
+/// Returns the memory limit of the cgroup of the current process in bytes, or None if there is no
+/// limit, if it cannot be read, or if this is not Linux. This reads `memory.max` for cgroup v2,
+/// and `memory.limit_in_bytes` for cgroup v1, so in a container it is usually the memory limit of
+/// the container.
+///
+/// This reads files, so it must not be called from inside the allocator.
+pub fn cgroup_memory_max() -> Option<usize> {
+    #[cfg(target_os = "linux")]
+    return cgroup::memory_max();
+    #[cfg(not(target_os = "linux"))]
+    None
+}
+
+/// Returns `percent`% of `total`, saturating at `usize::MAX`. Percentages above 100 are allowed.
+pub(crate) fn percent_of(total: usize, percent: usize) -> usize {
+    let bytes = total as u128 * percent as u128 / 100;
+    usize::try_from(bytes).unwrap_or(usize::MAX)
+}
+
+/// Returns `percent`% of the memory returned by `query`, or None if it returns None. The query is
+/// a parameter so the tests can replace `total_system_memory`.
+pub(crate) fn percent_of_memory(query: fn() -> Option<usize>, percent: usize) -> Option<usize> {
+    Some(percent_of(query()?, percent))
+}
+
+#[cfg(target_os = "linux")]
+mod cgroup {
+    use std::fs;
+    use std::string::String;
+
+    pub(super) fn memory_max() -> Option<usize> {
+        let cgroups = fs::read_to_string("/proc/self/cgroup").ok();
+        memory_max_in("/sys/fs/cgroup", cgroups.as_deref())
+    }
+
+    /// Returns the memory limit of the cgroup, given the mount point of the cgroup filesystem and
+    /// the contents of /proc/self/cgroup, if it could be read.
+    pub(super) fn memory_max_in(root: &str, cgroups: Option<&str>) -> Option<usize> {
+        // The path of the cgroup is relative to the mount point. If the process has its own cgroup
+        // namespace the path is "/", so the root is also tried
+        let cgroups = cgroups.unwrap_or("");
+        let v2 = cgroup_path(cgroups, |id, controllers| {
+            id == "0" && controllers.is_empty()
+        });
+        let v1 = cgroup_path(cgroups, |_, controllers| {
+            controllers.split(',').any(|c| c == "memory")
+        });
+        let candidates = [
+            v2.map(|path| String::from(root) + &path + "/memory.max"),
+            Some(String::from(root) + "/memory.max"),
+            v1.map(|path| String::from(root) + "/memory" + &path + "/memory.limit_in_bytes"),
+            Some(String::from(root) + "/memory/memory.limit_in_bytes"),
+        ];
+        // Use the first file that exists
+        candidates
+            .iter()
+            .flatten()
+            .find_map(|path| read(path))
+            .flatten()
+    }
+
+    /// Returns the path of the first cgroup in `cgroups` for which `matches` returns true. Each
+    /// line has the format "id:controllers:path".
+    fn cgroup_path(cgroups: &str, matches: impl Fn(&str, &str) -> bool) -> Option<String> {
+        cgroups.lines().find_map(|line| {
+            let mut parts = line.splitn(3, ':');
+            let (id, controllers, path) = (parts.next()?, parts.next()?, parts.next()?);
+            if matches(id, controllers) {
+                Some(String::from(path.trim_end_matches('/')))
+            } else {
+                None
+            }
+        })
+    }
+
+    /// Reads a limit file. Returns None if it cannot be read, or Some(None) if there is no limit.
+    fn read(path: &str) -> Option<Option<usize>> {
+        fs::read_to_string(path)
+            .ok()
+            .map(|contents| parse(&contents))
+    }
+
+    /// Parses the contents of `memory.max` or `memory.limit_in_bytes`, returns None if there is
+    /// no limit.
+    fn parse(contents: &str) -> Option<usize> {
+        let contents = contents.trim();
+        if contents == "max" {
+            return None;
+        }
+        let max: u64 = contents.parse().ok()?;
+        // cgroup v1 uses the largest multiple of the page size below i64::MAX for no limit
+        if max >= 1 << 62 {
+            return None;
+        }
+        usize::try_from(max).ok()
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+        use std::path::PathBuf;
+        use std::{format, process};
+
+        /// Temporary directory used as the cgroup root, removed when dropped.
+        struct Fixture(PathBuf);
+
+        impl Fixture {
+            fn new(name: &str) -> Self {
+                let dir =
+                    std::env::temp_dir().join(format!("limit-alloc-{}-{}", process::id(), name));
+                let _ = fs::remove_dir_all(&dir);
+                fs::create_dir_all(&dir).unwrap();
+                Self(dir)
+            }
+
+            fn write(&self, path: &str, contents: &str) {
+                let path = self.0.join(path);
+                fs::create_dir_all(path.parent().unwrap()).unwrap();
+                fs::write(path, contents).unwrap();
+            }
+
+            fn memory_max(&self, cgroups: Option<&str>) -> Option<usize> {
+                memory_max_in(self.0.to_str().unwrap(), cgroups)
+            }
+        }
+
+        impl Drop for Fixture {
+            fn drop(&mut self) {
+                let _ = fs::remove_dir_all(&self.0);
+            }
+        }
+
+        #[test]
+        fn v2() {
+            let root = Fixture::new("v2");
+            root.write("user.slice/app.scope/memory.max", "536870912\n");
+            root.write("memory.max", "max\n");
+            let cgroups = "0::/user.slice/app.scope\n";
+            assert_eq!(root.memory_max(Some(cgroups)), Some(512 << 20));
+            // Without /proc/self/cgroup, or in a cgroup namespace, the root is used
+            assert_eq!(root.memory_max(None), None);
+            root.write("memory.max", "1000");
+            assert_eq!(root.memory_max(None), Some(1000));
+            assert_eq!(root.memory_max(Some("0::/\n")), Some(1000));
+        }
+
+        #[test]
+        fn v2_max_means_no_limit() {
+            let root = Fixture::new("v2-max");
+            root.write("app/memory.max", "max\n");
+            // The v1 file is not used when the v2 file exists
+            root.write("memory/memory.limit_in_bytes", "1000\n");
+            assert_eq!(root.memory_max(Some("0::/app\n")), None);
+        }
+
+        #[test]
+        fn v1() {
+            let root = Fixture::new("v1");
+            root.write("memory/docker/abc/memory.limit_in_bytes", "268435456\n");
+            let cgroups = "12:pids:/docker/abc\n4:cpu,memory:/docker/abc/\n1:name=systemd:/\n";
+            assert_eq!(root.memory_max(Some(cgroups)), Some(256 << 20));
+            // The memory controller is not in the list, so only the root of the hierarchy is tried
+            assert_eq!(root.memory_max(Some("12:pids:/docker/abc\n")), None);
+            root.write("memory/memory.limit_in_bytes", "1000\n");
+            assert_eq!(root.memory_max(Some("12:pids:/docker/abc\n")), Some(1000));
+        }
+
+        #[test]
+        fn v1_sentinel_means_no_limit() {
+            let root = Fixture::new("v1-sentinel");
+            root.write("memory/memory.limit_in_bytes", "9223372036854771712\n");
+            assert_eq!(root.memory_max(Some("3:memory:/\n")), None);
+        }
+
+        #[test]
+        fn missing_files() {
+            let root = Fixture::new("missing");
+            assert_eq!(root.memory_max(Some("0::/app\n")), None);
+            assert_eq!(root.memory_max(Some("3:memory:/app\n")), None);
+            assert_eq!(root.memory_max(None), None);
+            assert_eq!(memory_max_in("/nonexistent/limit-alloc", None), None);
+        }
+
+        #[test]
+        fn malformed_contents() {
+            assert_eq!(parse("1000"), Some(1000));
+            assert_eq!(parse(" 1000\n"), Some(1000));
+            assert_eq!(parse(""), None);
+            assert_eq!(parse("-1"), None);
+            assert_eq!(parse("10M"), None);
+            assert_eq!(parse("MAX"), None);
+            assert_eq!(parse("18446744073709551616"), None);
+            assert_eq!(
+                parse(&format!("{}", (1u64 << 62) - 1)),
+                usize::try_from((1u64 << 62) - 1).ok()
+            );
+            assert_eq!(parse(&format!("{}", 1u64 << 62)), None);
+            // Lines without enough fields are skipped
+            let root = Fixture::new("malformed");
+            root.write("memory.max", "1000\n");
+            root.write("app/memory.max", "not a number\n");
+            assert_eq!(root.memory_max(Some("garbage\n0:\n")), Some(1000));
+            // A file that exists but cannot be parsed is not a limit
+            assert_eq!(root.memory_max(Some("0::/app\n")), None);
+        }
+    }
+}
+
+#[cfg(unix)]
+mod imp {
+    use core::ffi::{c_int, c_long};
+
+    extern "C" {
+        fn sysconf(name: c_int) -> c_long;
+    }
+
+    #[cfg(any(target_os = "linux", target_os = "android"))]
+    const SC_PAGESIZE: c_int = 30;
+    #[cfg(any(target_os = "linux", target_os = "android"))]
+    const SC_PHYS_PAGES: c_int = 85;
+    #[cfg(target_vendor = "apple")]
+    const SC_PAGESIZE: c_int = 29;
+    #[cfg(target_vendor = "apple")]
+    const SC_PHYS_PAGES: c_int = 200;
+    #[cfg(target_os = "freebsd")]
+    const SC_PAGESIZE: c_int = 47;
+    #[cfg(target_os = "freebsd")]
+    const SC_PHYS_PAGES: c_int = 121;
+
+    #[cfg(any(
+        target_os = "linux",
+        target_os = "android",
+        target_vendor = "apple",
+        target_os = "freebsd"
+    ))]
+    pub(super) fn total_system_memory() -> Option<usize> {
+        // Safety: sysconf has no preconditions, it returns -1 on error
+        let pages = unsafe { sysconf(SC_PHYS_PAGES) };
+        let page_size = unsafe { sysconf(SC_PAGESIZE) };
+        let pages = usize::try_from(pages).ok()?;
+        let page_size = usize::try_from(page_size).ok()?;
+        pages.checked_mul(page_size)
+    }
+
+    #[cfg(not(any(
+        target_os = "linux",
+        target_os = "android",
+        target_vendor = "apple",
+        target_os = "freebsd"
+    )))]
+    pub(super) fn total_system_memory() -> Option<usize> {
+        None
+    }
+}
+
+#[cfg(windows)]
+mod imp {
+    #[repr(C)]
+    struct MemoryStatusEx {
+        length: u32,
+        memory_load: u32,
+        total_phys: u64,
+        avail_phys: u64,
+        total_page_file: u64,
+        avail_page_file: u64,
+        total_virtual: u64,
+        avail_virtual: u64,
+        avail_extended_virtual: u64,
+    }
+
+    #[link(name = "kernel32")]
+    extern "system" {
+        fn GlobalMemoryStatusEx(buffer: *mut MemoryStatusEx) -> i32;
+    }
+
+    pub(super) fn total_system_memory() -> Option<usize> {
+        let mut status = MemoryStatusEx {
+            length: core::mem::size_of::<MemoryStatusEx>() as u32,
+            memory_load: 0,
+            total_phys: 0,
+            avail_phys: 0,
+            total_page_file: 0,
+            avail_page_file: 0,
+            total_virtual: 0,
+            avail_virtual: 0,
+            avail_extended_virtual: 0,
+        };
+        // Safety: status is a valid MEMORYSTATUSEX with its length set
+        if unsafe { GlobalMemoryStatusEx(&mut status) } == 0 {
+            return None;
+        }
+        usize::try_from(status.total_phys).ok()
+    }
+}
+
+#[cfg(not(any(unix, windows)))]
+mod imp {
+    pub(super) fn total_system_memory() -> Option<usize> {
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MIB: usize = 1 << 20;
+
+    fn eight_mib() -> Option<usize> {
+        Some(8 * MIB)
+    }
+
+    fn unknown() -> Option<usize> {
+        None
+    }
+
+    fn everything() -> Option<usize> {
+        Some(usize::MAX)
+    }
+
+    #[test]
+    fn zero_percent() {
+        assert_eq!(percent_of(8 * MIB, 0), 0);
+        assert_eq!(percent_of_memory(eight_mib, 0), Some(0));
+        assert_eq!(percent_of_memory(everything, 0), Some(0));
+    }
+
+    #[test]
+    fn percentages() {
+        assert_eq!(percent_of_memory(eight_mib, 50), Some(4 * MIB));
+        assert_eq!(percent_of_memory(eight_mib, 100), Some(8 * MIB));
+        // Fractions of a byte are truncated
+        assert_eq!(percent_of(199, 50), 99);
+        assert_eq!(percent_of(1, 99), 0);
+    }
+
+    #[test]
+    fn above_100_percent() {
+        assert_eq!(percent_of_memory(eight_mib, 150), Some(12 * MIB));
+        assert_eq!(percent_of(8 * MIB, 1_000), 80 * MIB);
+    }
+
+    #[test]
+    fn saturates_at_usize_max() {
+        assert_eq!(percent_of(usize::MAX, 100), usize::MAX);
+        assert_eq!(percent_of(usize::MAX, 50), usize::MAX / 2);
+        assert_eq!(percent_of_memory(everything, 150), Some(usize::MAX));
+        assert_eq!(percent_of(usize::MAX, usize::MAX), usize::MAX);
+    }
+
+    #[test]
+    fn unknown_memory() {
+        assert_eq!(percent_of_memory(unknown, 50), None);
+    }
+}
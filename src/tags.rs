@@ -0,0 +1,159 @@
+//! Attribute the memory of a `Limit` to tags, see `Limit::tag_scope`.
+use core::hint;
+use core::ptr;
+use core::slice;
+use core::str;
+use core::sync::atomic::Ordering::{Acquire, Relaxed, Release, SeqCst};
+use core::sync::atomic::{AtomicBool, AtomicPtr, AtomicUsize};
+use std::cell::Cell;
+
+/// Maximum number of tags that can be registered using `Limit::register_tag`.
+pub const MAX_TAGS: usize = 8;
+
+alloc_local! {
+    /// Address of the `Limit` and index of the tag of the current scope, or null.
+    static CURRENT: Cell<(*const (), usize)> = Cell::new((ptr::null(), 0));
+}
+
+pub(crate) struct Tags {
+    /// Number of registered tags. Slots are only read after this number is updated.
+    len: AtomicUsize,
+    /// Spinlock held while registering a tag.
+    registering: AtomicBool,
+    slots: [Slot; MAX_TAGS],
+}
+
+struct Slot {
+    /// Pointer and length of the `&'static str` name.
+    name: AtomicPtr<u8>,
+    name_len: AtomicUsize,
+    /// Memory attributed to this tag, in bytes.
+    bytes: AtomicUsize,
+}
+
+impl Slot {
+    const fn new() -> Self {
+        Self {
+            name: AtomicPtr::new(ptr::null_mut()),
+            name_len: AtomicUsize::new(0),
+            bytes: AtomicUsize::new(0),
+        }
+    }
+
+    fn name(&self) -> &'static str {
+        let name = self.name.load(SeqCst);
+        let len = self.name_len.load(SeqCst);
+        // Safety: registered slots store the pointer and length of a &'static str
+        unsafe { str::from_utf8_unchecked(slice::from_raw_parts(name, len)) }
+    }
+}
+
+impl Tags {
+    pub(crate) const fn new() -> Self {
+        Self {
+            len: AtomicUsize::new(0),
+            registering: AtomicBool::new(false),
+            slots: [const { Slot::new() }; MAX_TAGS],
+        }
+    }
+
+    fn registered(&self) -> &[Slot] {
+        &self.slots[..self.len.load(SeqCst)]
+    }
+
+    fn find(&self, name: &str) -> Option<usize> {
+        self.registered()
+            .iter()
+            .position(|slot| slot.name() == name)
+    }
+
+    /// Registers `name`, returns false if there is no free slot.
+    pub(crate) fn register(&self, name: &'static str) -> bool {
+        while self.registering.swap(true, Acquire) {
+            hint::spin_loop();
+        }
+        let len = self.len.load(SeqCst);
+        let registered = if self.find(name).is_some() {
+            true
+        } else if len == MAX_TAGS {
+            false
+        } else {
+            let slot = &self.slots[len];
+            slot.name.store(name.as_ptr().cast_mut(), SeqCst);
+            slot.name_len.store(name.len(), SeqCst);
+            self.len.store(len + 1, SeqCst);
+            true
+        };
+        self.registering.store(false, Release);
+        registered
+    }
+
+    /// Returns the memory attributed to `name`, or 0 if it is not registered.
+    pub(crate) fn usage(&self, name: &str) -> usize {
+        self.find(name)
+            .map_or(0, |index| self.slots[index].bytes.load(Relaxed))
+    }
+
+    pub(crate) fn iter(&self) -> impl Iterator<Item = (&'static str, usize)> + '_ {
+        self.registered()
+            .iter()
+            .map(|slot| (slot.name(), slot.bytes.load(Relaxed)))
+    }
+
+    /// Starts a scope of the tag `name` for the limit at `limit`.
+    ///
+    /// # Panics
+    ///
+    /// If `name` is not registered.
+    pub(crate) fn scope(&self, limit: *const (), name: &str) -> TagGuard {
+        let Some(index) = self.find(name) else {
+            panic!("tag {:?} is not registered", name);
+        };
+        let previous = CURRENT
+            .try_with(|current| current.replace((limit, index)))
+            .unwrap_or((ptr::null(), 0));
+        TagGuard { previous }
+    }
+
+    /// Returns the slot of the current tag, if it belongs to the limit at `limit`.
+    fn current(&self, limit: *const ()) -> Option<&Slot> {
+        // Fast path for limits without tags, which do not need the thread local
+        if self.len.load(Relaxed) == 0 {
+            return None;
+        }
+        let (current, index) = CURRENT.try_with(Cell::get).ok()?;
+        if current == limit {
+            Some(&self.slots[index])
+        } else {
+            None
+        }
+    }
+
+    /// Called after `size` bytes are charged to the limit at `limit`.
+    pub(crate) fn charged(&self, limit: *const (), size: usize) {
+        if let Some(slot) = self.current(limit) {
+            slot.bytes.fetch_add(size, Relaxed);
+        }
+    }
+
+    /// Called after `size` bytes are credited to the limit at `limit`.
+    pub(crate) fn credited(&self, limit: *const (), size: usize) {
+        if let Some(slot) = self.current(limit) {
+            // Saturate because the memory may have been allocated outside of the scope
+            let _ = slot
+                .bytes
+                .fetch_update(Relaxed, Relaxed, |old| Some(old.saturating_sub(size)));
+        }
+    }
+}
+
+/// Restores the previous tag when dropped, so scopes can be nested.
+pub(crate) struct TagGuard {
+    previous: (*const (), usize),
+}
+
+impl Drop for TagGuard {
+    fn drop(&mut self) {
+        let _ = CURRENT.try_with(|current| current.set(self.previous));
+    }
+}
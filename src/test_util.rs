@@ -0,0 +1,77 @@
+//! Inner allocators used by the tests.
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::ptr;
+use std::sync::atomic::Ordering::SeqCst;
+use std::sync::atomic::{AtomicBool, AtomicUsize};
+
+/// Allocator that uses `System`, counts the calls to each method, and returns null while `fail`
+/// is set.
+pub(crate) struct MockAlloc {
+    fail: AtomicBool,
+    pub(crate) allocs: AtomicUsize,
+    pub(crate) zeroed: AtomicUsize,
+    pub(crate) reallocs: AtomicUsize,
+    pub(crate) deallocs: AtomicUsize,
+}
+
+impl MockAlloc {
+    pub(crate) const fn new() -> Self {
+        Self {
+            fail: AtomicBool::new(false),
+            allocs: AtomicUsize::new(0),
+            zeroed: AtomicUsize::new(0),
+            reallocs: AtomicUsize::new(0),
+            deallocs: AtomicUsize::new(0),
+        }
+    }
+
+    /// Allocator that always returns null.
+    pub(crate) const fn failing() -> Self {
+        let mut a = Self::new();
+        a.fail = AtomicBool::new(true);
+        a
+    }
+
+    pub(crate) fn set_fail(&self, fail: bool) {
+        self.fail.store(fail, SeqCst);
+    }
+}
+
+unsafe impl GlobalAlloc for MockAlloc {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        self.allocs.fetch_add(1, SeqCst);
+        if self.fail.load(SeqCst) {
+            return ptr::null_mut();
+        }
+        System.alloc(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        self.deallocs.fetch_add(1, SeqCst);
+        System.dealloc(ptr, layout)
+    }
+
+    unsafe fn alloc_zeroed(&self, layout: Layout) -> *mut u8 {
+        self.zeroed.fetch_add(1, SeqCst);
+        if self.fail.load(SeqCst) {
+            return ptr::null_mut();
+        }
+        System.alloc_zeroed(layout)
+    }
+
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        self.reallocs.fetch_add(1, SeqCst);
+        if self.fail.load(SeqCst) {
+            return ptr::null_mut();
+        }
+        System.realloc(ptr, layout, new_size)
+    }
+}
+
+/// Allocates `layout` using `System`, outside of any limit, to simulate memory that a limit
+/// deallocates without having allocated it.
+pub(crate) fn foreign(layout: Layout) -> *mut u8 {
+    let ptr = unsafe { System.alloc(layout) };
+    assert!(!ptr.is_null());
+    ptr
+}
@@ -0,0 +1,225 @@
+//! Allocator with a separate memory limit for each thread.
+use std::alloc::{GlobalAlloc, Layout};
+use std::cell::Cell;
+use std::ptr;
+
+struct ThreadState {
+    /// Limit of this thread, or None to use the default limit of the allocator.
+    limit: Cell<Option<usize>>,
+    /// Memory allocated by this thread, in bytes.
+    allocated: Cell<usize>,
+}
+
+alloc_local! {
+    static STATE: ThreadState = ThreadState {
+        limit: Cell::new(None),
+        allocated: Cell::new(0),
+    };
+}
+
+/// Allocator that limits the memory allocated by each thread. Each thread starts with the default
+/// limit passed to `ThreadLimit::new`, and it can be changed using
+/// `ThreadLimit::set_current_thread_limit`.
+///
+/// Same as `ConstLimit`, the counters are not stored in the allocator but in a thread local, so
+/// all the `ThreadLimit` allocators share the same counters. This is meant to be used as the
+/// global allocator.
+///
+/// Memory is always credited to the thread that deallocates it, because a thread cannot modify
+/// the counter of another thread. So if a thread allocates memory and another thread
+/// deallocates it, the memory is still counted by the first thread, and the counter of the second
+/// thread saturates at 0. The counters never become invalid, but a thread that sends allocated
+/// memory to other threads will see its remaining memory decrease.
+pub struct ThreadLimit<A> {
+    default_limit: usize,
+    alloc: A,
+}
+
+impl<A: GlobalAlloc> ThreadLimit<A> {
+    pub const fn new(default_limit: usize, alloc: A) -> Self {
+        Self {
+            default_limit,
+            alloc,
+        }
+    }
+
+    /// Sets the limit of the current thread. Memory that is already allocated is still counted.
+    pub fn set_current_thread_limit(bytes: usize) {
+        STATE.with(|state| state.limit.set(Some(bytes)));
+    }
+
+    /// Returns the limit of the current thread.
+    pub fn limit(&self) -> usize {
+        STATE
+            .try_with(|state| state.limit.get())
+            .ok()
+            .flatten()
+            .unwrap_or(self.default_limit)
+    }
+
+    /// Returns the memory allocated by the current thread, in bytes.
+    pub fn allocated(&self) -> usize {
+        STATE.try_with(|state| state.allocated.get()).unwrap_or(0)
+    }
+
+    /// Returns the remaining memory of the current thread, in bytes.
+    pub fn remaining(&self) -> usize {
+        self.limit().saturating_sub(self.allocated())
+    }
+
+    /// Returns None if the memory limit of the current thread would be exhausted after
+    /// allocating.
+    ///
+    /// # Safety
+    ///
+    /// The same restrictions as `GlobalAlloc::alloc`.
+    pub unsafe fn try_alloc(&self, layout: Layout) -> Option<*mut u8> {
+        self.alloc_with(layout, |alloc, layout| alloc.alloc(layout))
+    }
+
+    unsafe fn alloc_with(
+        &self,
+        layout: Layout,
+        f: impl FnOnce(&A, Layout) -> *mut u8,
+    ) -> Option<*mut u8> {
+        if !self.charge(layout.size()) {
+            return None;
+        }
+        let ret = f(&self.alloc, layout);
+        if ret.is_null() {
+            // Nothing was actually allocated, so subtract the size
+            Self::credit(layout.size());
+        }
+
+        Some(ret)
+    }
+
+    /// Add `size` to the memory allocated by the current thread. Returns false if that would
+    /// exceed the limit, in that case the counter is not modified.
+    fn charge(&self, size: usize) -> bool {
+        STATE
+            .try_with(|state| {
+                let limit = state.limit.get().unwrap_or(self.default_limit);
+                match crate::add_within_limit(state.allocated.get(), size, limit) {
+                    Some(new) => {
+                        state.allocated.set(new);
+                        true
+                    }
+                    None => false,
+                }
+            })
+            // The thread local is not available, allow the allocation without counting it
+            .unwrap_or(true)
+    }
+
+    /// Subtract `size` from the memory allocated by the current thread.
+    fn credit(size: usize) {
+        let _ = STATE.try_with(|state| {
+            state
+                .allocated
+                .set(state.allocated.get().saturating_sub(size))
+        });
+    }
+}
+
+unsafe impl<A: GlobalAlloc> GlobalAlloc for ThreadLimit<A> {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        self.try_alloc(layout).unwrap_or(ptr::null_mut())
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        self.alloc.dealloc(ptr, layout);
+        Self::credit(layout.size());
+    }
+
+    unsafe fn alloc_zeroed(&self, layout: Layout) -> *mut u8 {
+        self.alloc_with(layout, |alloc, layout| alloc.alloc_zeroed(layout))
+            .unwrap_or(ptr::null_mut())
+    }
+
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        let old_size = layout.size();
+        if new_size > old_size {
+            let delta = new_size - old_size;
+            if !self.charge(delta) {
+                return ptr::null_mut();
+            }
+            let ret = self.alloc.realloc(ptr, layout, new_size);
+            if ret.is_null() {
+                // The old allocation is still valid, so only subtract the difference
+                Self::credit(delta);
+            }
+            ret
+        } else {
+            let ret = self.alloc.realloc(ptr, layout, new_size);
+            if !ret.is_null() {
+                Self::credit(old_size - new_size);
+            }
+            ret
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::alloc::System;
+    use std::sync::Barrier;
+    use std::thread;
+
+    const LAYOUT: Layout = unsafe { Layout::from_size_align_unchecked(400, 1) };
+
+    #[test]
+    fn threads_fail_independently() {
+        let limit = ThreadLimit::new(1_000, System);
+        let barrier = Barrier::new(2);
+        thread::scope(|s| {
+            // Each thread fills its own budget at the same time as the other one
+            let small = s.spawn(|| {
+                ThreadLimit::<System>::set_current_thread_limit(500);
+                let ptr = unsafe { limit.alloc(LAYOUT) };
+                barrier.wait();
+                assert!(unsafe { limit.alloc(LAYOUT) }.is_null());
+                assert_eq!(limit.allocated(), 400);
+                unsafe { limit.dealloc(ptr, LAYOUT) };
+                limit.allocated()
+            });
+            let default = s.spawn(|| {
+                let a = unsafe { limit.alloc(LAYOUT) };
+                barrier.wait();
+                let b = unsafe { limit.alloc(LAYOUT) };
+                assert!(!b.is_null());
+                assert!(unsafe { limit.alloc(LAYOUT) }.is_null());
+                assert_eq!(limit.remaining(), 200);
+                unsafe {
+                    limit.dealloc(a, LAYOUT);
+                    limit.dealloc(b, LAYOUT);
+                }
+                limit.allocated()
+            });
+            assert_eq!(small.join().unwrap(), 0);
+            assert_eq!(default.join().unwrap(), 0);
+        });
+    }
+
+    #[test]
+    fn memory_is_credited_to_the_thread_that_deallocates() {
+        let limit = ThreadLimit::new(1_000, System);
+        let ptr = thread::scope(|s| {
+            s.spawn(|| {
+                let ptr = unsafe { limit.alloc(LAYOUT) };
+                assert_eq!(limit.allocated(), 400);
+                ptr as usize
+            })
+            .join()
+            .unwrap()
+        });
+        let own = unsafe { limit.alloc(LAYOUT) };
+        unsafe { limit.dealloc(ptr as *mut u8, LAYOUT) };
+        assert_eq!(limit.allocated(), 0);
+        // The counter saturates at 0, so the limit of this thread does not grow
+        unsafe { limit.dealloc(own, LAYOUT) };
+        assert_eq!(limit.allocated(), 0);
+        assert_eq!(limit.remaining(), 1_000);
+    }
+}
@@ -0,0 +1,406 @@
+//! Record the layout of every live allocation to find deallocations with the wrong layout, see
+//! the `debug-tracking` feature.
+use core::alloc::Layout;
+#[cfg(feature = "verify-accounting")]
+use core::fmt;
+use core::sync::atomic::AtomicUsize;
+use core::sync::atomic::Ordering::Relaxed;
+use std::cell::Cell;
+use std::collections::BTreeMap;
+use std::sync::{Mutex, PoisonError};
+use std::vec::Vec;
+
+alloc_local! {
+    /// True while the current thread is modifying a map. The map allocates, and when this is the
+    /// global allocator those allocations come back here, so they are not tracked.
+    static BUSY: Cell<bool> = Cell::new(false);
+}
+
+/// Layouts of the live allocations, by address.
+///
+/// This is a `BTreeMap` instead of a `HashMap` because it can be created in a `const` context.
+pub(crate) struct Tracker {
+    live: Mutex<BTreeMap<usize, Layout>>,
+    /// Number of deallocations of pointers that were not live.
+    unknown_frees: AtomicUsize,
+    /// First deallocation error and the address of the pointer, reported by
+    /// `Limit::assert_accounting` and `Limit::verify`.
+    first_error: Mutex<Option<(usize, Mismatch)>>,
+    /// Memory counted for the allocations that are not in the map because the current thread was
+    /// busy, which includes the map itself when this is the global allocator.
+    #[cfg(feature = "verify-accounting")]
+    untracked: AtomicUsize,
+}
+
+/// A live allocation recorded by the `debug-tracking` feature, see `Limit::tracked_allocations`.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct LiveAlloc {
+    /// Address of the first byte.
+    pub address: usize,
+    /// Size in bytes, as requested in the layout.
+    pub size: usize,
+    /// Alignment in bytes.
+    pub align: usize,
+}
+
+/// Clears `BUSY` when dropped.
+struct BusyGuard;
+
+impl Drop for BusyGuard {
+    fn drop(&mut self) {
+        let _ = BUSY.try_with(|busy| busy.set(false));
+    }
+}
+
+impl Tracker {
+    pub(crate) const fn new() -> Self {
+        Self {
+            live: Mutex::new(BTreeMap::new()),
+            unknown_frees: AtomicUsize::new(0),
+            first_error: Mutex::new(None),
+            #[cfg(feature = "verify-accounting")]
+            untracked: AtomicUsize::new(0),
+        }
+    }
+
+    /// Returns true if the current thread is using a map, so allocations are not tracked.
+    #[cfg(feature = "verify-accounting")]
+    fn busy() -> bool {
+        cfg!(debug_assertions) && BUSY.try_with(Cell::get).unwrap_or(true)
+    }
+
+    /// Runs `f` with the map, unless the current thread is already using it or this is a release
+    /// build, in which case this returns None.
+    fn lock<R>(&self, f: impl FnOnce(&mut BTreeMap<usize, Layout>) -> R) -> Option<R> {
+        if !cfg!(debug_assertions) {
+            return None;
+        }
+        if !matches!(BUSY.try_with(|busy| busy.replace(true)), Ok(false)) {
+            return None;
+        }
+        let _guard = BusyGuard;
+        let mut live = self.live.lock().unwrap_or_else(PoisonError::into_inner);
+        Some(f(&mut live))
+    }
+
+    /// Runs `f` with the map, see `lock`. If `f` returns an error for `ptr`, it is recorded
+    /// after the lock is released and this returns false. This runs inside the allocator, which
+    /// must not unwind, so it does not panic, `assert_no_errors` does.
+    fn with_map(
+        &self,
+        ptr: *mut u8,
+        f: impl FnOnce(&mut BTreeMap<usize, Layout>) -> Option<Mismatch>,
+    ) -> bool {
+        let Some(error) = self.lock(f).flatten() else {
+            return true;
+        };
+        if let Mismatch::Unknown(_) = error {
+            self.unknown_frees.fetch_add(1, Relaxed);
+        }
+        let mut first = self
+            .first_error
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner);
+        first.get_or_insert((ptr as usize, error));
+        false
+    }
+
+    /// Returns the first deallocation error.
+    fn first_error(&self) -> Option<(usize, Mismatch)> {
+        *self
+            .first_error
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+    }
+
+    /// Panics if a pointer was deallocated with a different layout than it was allocated with,
+    /// or if it was not allocated by this `Limit`.
+    #[track_caller]
+    pub(crate) fn assert_no_errors(&self) {
+        match self.first_error() {
+            None => {}
+            Some((address, Mismatch::Unknown(layout))) => panic!(
+                "deallocated {:#x} with {:?}, but it was not allocated by this limit",
+                address, layout
+            ),
+            Some((address, Mismatch::Layout { recorded, layout })) => panic!(
+                "deallocated {:#x} with {:?}, but it was allocated with {:?}",
+                address, layout, recorded
+            ),
+        }
+    }
+
+    /// Called after `ptr` is allocated with `layout`, and `size` bytes are counted for it.
+    pub(crate) fn allocated(&self, ptr: *mut u8, layout: Layout, size: usize) {
+        #[cfg(feature = "verify-accounting")]
+        if Self::busy() {
+            self.untracked.fetch_add(size, Relaxed);
+        }
+        #[cfg(not(feature = "verify-accounting"))]
+        let _ = size;
+        self.with_map(ptr, |live| {
+            live.insert(ptr as usize, layout);
+            None
+        });
+    }
+
+    /// Called before `ptr` is deallocated with `layout`, and `size` bytes are credited for it.
+    /// Records an error and returns false if `ptr` is not a live allocation, or if it was
+    /// allocated with a different layout.
+    pub(crate) fn deallocated(&self, ptr: *mut u8, layout: Layout, size: usize) -> bool {
+        #[cfg(feature = "verify-accounting")]
+        if Self::busy() {
+            let _ = self
+                .untracked
+                .fetch_update(Relaxed, Relaxed, |old| Some(old.saturating_sub(size)));
+        }
+        #[cfg(not(feature = "verify-accounting"))]
+        let _ = size;
+        self.with_map(ptr, |live| {
+            // A pointer with the wrong layout is not deallocated, so it stays live
+            let error = check(live.get(&(ptr as usize)).copied(), layout);
+            if error.is_none() {
+                live.remove(&(ptr as usize));
+            }
+            error
+        })
+    }
+
+    /// Called after `ptr`, allocated with `layout`, is moved to `new` with `new_layout`. The map
+    /// does not reallocate, so this is not called while the current thread is busy. Records the
+    /// same errors as `deallocated`.
+    pub(crate) fn reallocated(
+        &self,
+        ptr: *mut u8,
+        layout: Layout,
+        new: *mut u8,
+        new_layout: Layout,
+    ) {
+        self.with_map(ptr, |live| {
+            let old = live.remove(&(ptr as usize));
+            live.insert(new as usize, new_layout);
+            check(old, layout)
+        });
+    }
+
+    /// Returns the number of deallocations of pointers that were not live.
+    pub(crate) fn unknown_frees(&self) -> usize {
+        self.unknown_frees.load(Relaxed)
+    }
+
+    /// Returns the live allocations, sorted by address.
+    pub(crate) fn snapshot(&self) -> Vec<LiveAlloc> {
+        let mut list = Vec::new();
+        // The list must not allocate while the lock is held: that allocation would not be
+        // tracked, and deallocating it later would panic. So reserve enough space first, and try
+        // again if more allocations were made in the meantime
+        loop {
+            let Some(len) = self.lock(|live| live.len()) else {
+                return list;
+            };
+            list.reserve(len + len / 8 + 4);
+            let filled = self.lock(|live| {
+                if live.len() > list.capacity() {
+                    return false;
+                }
+                list.extend(live.iter().map(|(&address, layout)| LiveAlloc {
+                    address,
+                    size: layout.size(),
+                    align: layout.align(),
+                }));
+                true
+            });
+            if filled != Some(false) {
+                return list;
+            }
+        }
+    }
+
+    /// Checks that `used()` is the sum of `size` of every live allocation plus `reserved`, see
+    /// `Limit::verify`. `size` must not allocate.
+    #[cfg(feature = "verify-accounting")]
+    pub(crate) fn verify(
+        &self,
+        used: impl FnOnce() -> usize,
+        reserved: usize,
+        size: impl Fn(usize, Layout) -> usize,
+    ) -> Result<(), AccountingError> {
+        if let Some((address, error)) = self.first_error() {
+            return Err(error.to_accounting_error(address));
+        }
+        // Read used() with the lock held, so no tracked allocation can start or end in between
+        let sums = self.lock(|live| {
+            let tracked = live.iter().fold(0usize, |sum, (&address, &layout)| {
+                sum.saturating_add(size(address, layout))
+            });
+            let untracked = self.untracked.load(Relaxed);
+            (
+                used(),
+                tracked.saturating_add(reserved).saturating_add(untracked),
+            )
+        });
+        match sums {
+            Some((used, expected)) if used != expected => {
+                Err(AccountingError::Drift { used, expected })
+            }
+            _ => Ok(()),
+        }
+    }
+}
+
+impl Drop for Tracker {
+    fn drop(&mut self) {
+        // When the global allocator is tracked too, the nodes of the map are deallocated through
+        // it, and it must not look for them because they were allocated while busy
+        if matches!(BUSY.try_with(|busy| busy.replace(true)), Ok(false)) {
+            let _guard = BusyGuard;
+            let live = self.live.get_mut().unwrap_or_else(PoisonError::into_inner);
+            drop(core::mem::take(live));
+        }
+    }
+}
+
+/// Error returned by `Limit::verify`, see the `verify-accounting` feature.
+#[cfg(feature = "verify-accounting")]
+#[non_exhaustive]
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum AccountingError {
+    /// `used` is not equal to the memory counted for the live allocations plus the unused
+    /// memory of the reservations, `expected`.
+    Drift { used: usize, expected: usize },
+    /// The pointer at `address` was allocated with `allocated`, but deallocated with
+    /// `deallocated`.
+    LayoutMismatch {
+        address: usize,
+        allocated: Layout,
+        deallocated: Layout,
+    },
+    /// The pointer at `address` was deallocated with `layout`, but it was not allocated by this
+    /// `Limit`, or it was already deallocated.
+    UnknownPointer { address: usize, layout: Layout },
+}
+
+#[cfg(feature = "verify-accounting")]
+impl fmt::Display for AccountingError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            AccountingError::Drift { used, expected } => write!(
+                f,
+                "{} bytes are counted as used, but {} bytes are allocated or reserved",
+                used, expected
+            ),
+            AccountingError::LayoutMismatch {
+                address,
+                allocated,
+                deallocated,
+            } => write!(
+                f,
+                "pointer {:#x} was allocated with {:?} but deallocated with {:?}",
+                address, allocated, deallocated
+            ),
+            AccountingError::UnknownPointer { address, layout } => write!(
+                f,
+                "pointer {:#x} was deallocated with {:?} but it was not allocated",
+                address, layout
+            ),
+        }
+    }
+}
+
+#[cfg(feature = "verify-accounting")]
+impl std::error::Error for AccountingError {}
+
+/// Error found when deallocating.
+#[derive(Copy, Clone)]
+enum Mismatch {
+    /// The pointer is not a live allocation.
+    Unknown(Layout),
+    /// The pointer was allocated with `recorded`, but deallocated with `layout`.
+    Layout { recorded: Layout, layout: Layout },
+}
+
+#[cfg(feature = "verify-accounting")]
+impl Mismatch {
+    fn to_accounting_error(self, address: usize) -> AccountingError {
+        match self {
+            Mismatch::Unknown(layout) => AccountingError::UnknownPointer { address, layout },
+            Mismatch::Layout { recorded, layout } => AccountingError::LayoutMismatch {
+                address,
+                allocated: recorded,
+                deallocated: layout,
+            },
+        }
+    }
+}
+
+/// Returns the error if a block recorded with `recorded` is deallocated with `layout`.
+fn check(recorded: Option<Layout>, layout: Layout) -> Option<Mismatch> {
+    match recorded {
+        None => Some(Mismatch::Unknown(layout)),
+        Some(recorded) if recorded != layout => Some(Mismatch::Layout { recorded, layout }),
+        Some(_) => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::test_util::foreign;
+    use crate::Limit;
+    use std::alloc::{GlobalAlloc, Layout, System};
+    use std::panic;
+    use std::string::String;
+
+    const LAYOUT: Layout = unsafe { Layout::from_size_align_unchecked(100, 8) };
+
+    fn message(limit: &Limit<System>) -> String {
+        let err = panic::catch_unwind(|| limit.assert_accounting()).unwrap_err();
+        *err.downcast::<String>().unwrap()
+    }
+
+    #[test]
+    fn wrong_layout_is_recorded_and_leaked() {
+        let limit = Limit::new(1_000, System);
+        let ptr = unsafe { limit.alloc(LAYOUT) };
+        limit.assert_accounting();
+        let wrong = Layout::from_size_align(60, 8).unwrap();
+        unsafe { limit.dealloc(ptr, wrong) };
+        assert_eq!(limit.allocated(), 100);
+        assert_eq!(limit.tracked_allocations().len(), 1);
+        assert_eq!(
+            message(&limit),
+            std::format!(
+                "deallocated {:#x} with {:?}, but it was allocated with {:?}",
+                ptr as usize,
+                wrong,
+                LAYOUT
+            )
+        );
+        unsafe { System.dealloc(ptr, LAYOUT) };
+    }
+
+    #[test]
+    fn unknown_pointer_is_recorded_and_leaked() {
+        let limit = Limit::new(1_000, System);
+        let ptr = unsafe { limit.alloc(LAYOUT) };
+        let other = foreign(LAYOUT);
+        unsafe { limit.dealloc(other, LAYOUT) };
+        assert_eq!(limit.unknown_frees(), 1);
+        assert_eq!(limit.allocated(), 100);
+        // Only the first error is reported
+        unsafe { limit.dealloc(other, LAYOUT) };
+        assert_eq!(limit.unknown_frees(), 2);
+        assert_eq!(
+            message(&limit),
+            std::format!(
+                "deallocated {:#x} with {:?}, but it was not allocated by this limit",
+                other as usize,
+                LAYOUT
+            )
+        );
+        unsafe {
+            limit.dealloc(ptr, LAYOUT);
+            System.dealloc(other, LAYOUT);
+        }
+        assert_eq!(limit.allocated(), 0);
+    }
+}
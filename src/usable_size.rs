@@ -0,0 +1,83 @@
+//! Query the real size of a block allocated by the system allocator. malloc usually rounds small
+//! allocations up to a size class, so the memory used can be bigger than the requested size.
+use core::alloc::Layout;
+use core::ffi::c_void;
+
+#[cfg(any(target_os = "linux", target_os = "android", target_os = "freebsd"))]
+extern "C" {
+    fn malloc_usable_size(ptr: *mut c_void) -> usize;
+}
+
+#[cfg(target_vendor = "apple")]
+extern "C" {
+    fn malloc_size(ptr: *const c_void) -> usize;
+}
+
+/// Returns the size of the block pointed to by `ptr`, or `layout.size()` if the platform does not
+/// provide a way to query it. On Windows the `System` allocator does not use malloc, so `_msize`
+/// cannot be used there.
+///
+/// # Safety
+///
+/// `ptr` must have been allocated by the `System` allocator and not deallocated yet.
+pub(crate) unsafe fn usable_size(ptr: *mut u8, layout: Layout) -> usize {
+    #[cfg(any(target_os = "linux", target_os = "android", target_os = "freebsd"))]
+    let size = malloc_usable_size(ptr.cast());
+    #[cfg(target_vendor = "apple")]
+    let size = malloc_size(ptr.cast());
+    #[cfg(not(any(
+        target_os = "linux",
+        target_os = "android",
+        target_os = "freebsd",
+        target_vendor = "apple"
+    )))]
+    let size = {
+        let _ = ptr;
+        layout.size()
+    };
+
+    // The block can never be smaller than the requested size
+    size.max(layout.size())
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::Limit;
+    use core::alloc::{GlobalAlloc, Layout};
+
+    #[test]
+    fn charges_at_least_the_requested_size() {
+        let limit = Limit::with_usable_size(1 << 20);
+        for size in [1, 7, 20, 100, 1_000, 4_097] {
+            let layout = Layout::from_size_align(size, 1).unwrap();
+            let ptr = unsafe { limit.alloc(layout) };
+            assert!(!ptr.is_null());
+            assert!(limit.allocated() >= size, "{} {}", size, limit.allocated());
+            assert_eq!(limit.allocated(), unsafe {
+                super::usable_size(ptr, layout)
+            });
+            unsafe { limit.dealloc(ptr, layout) };
+            assert_eq!(limit.allocated(), 0);
+        }
+        assert!(limit.stats().bytes_allocated_total >= 1 + 7 + 20 + 100 + 1_000 + 4_097);
+    }
+
+    #[test]
+    fn realloc_balances_after_free() {
+        let limit = Limit::with_usable_size(1 << 20);
+        let small = Layout::from_size_align(20, 8).unwrap();
+        let ptr = unsafe { limit.alloc(small) };
+        let ptr = unsafe { limit.realloc(ptr, small, 5_000) };
+        assert!(!ptr.is_null());
+        let big = Layout::from_size_align(5_000, 8).unwrap();
+        assert!(limit.allocated() >= 5_000);
+        assert_eq!(limit.allocated(), unsafe { super::usable_size(ptr, big) });
+        let ptr = unsafe { limit.realloc(ptr, big, 10) };
+        assert!(!ptr.is_null());
+        let tiny = Layout::from_size_align(10, 8).unwrap();
+        assert!(limit.allocated() >= 10);
+        assert_eq!(limit.allocated(), unsafe { super::usable_size(ptr, tiny) });
+        unsafe { limit.dealloc(ptr, tiny) };
+        assert_eq!(limit.allocated(), 0);
+    }
+}
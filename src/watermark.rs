@@ -0,0 +1,223 @@
+//! Callbacks called when the allocated memory crosses a threshold.
+use core::mem;
+use core::ptr;
+use core::sync::atomic::Ordering::SeqCst;
+use core::sync::atomic::{AtomicBool, AtomicPtr, AtomicUsize};
+
+/// Maximum number of watermarks that can be set using `Limit::set_watermarks`.
+pub const MAX_WATERMARKS: usize = 4;
+
+/// Function called when the allocated memory crosses a watermark. It receives the allocated memory
+/// and the limit, both in bytes.
+pub type WatermarkCallback = fn(usize, usize);
+
+pub(crate) struct Watermarks {
+    /// Number of slots in use. Slots are only read after this number is updated.
+    len: AtomicUsize,
+    slots: [Slot; MAX_WATERMARKS],
+}
+
+struct Slot {
+    threshold: AtomicUsize,
+    /// Stored as a `WatermarkCallback`.
+    callback: AtomicPtr<()>,
+    /// True if the callback should be called the next time that the threshold is crossed.
+    armed: AtomicBool,
+}
+
+impl Slot {
+    const fn new() -> Self {
+        Self {
+            threshold: AtomicUsize::new(usize::MAX),
+            callback: AtomicPtr::new(ptr::null_mut()),
+            armed: AtomicBool::new(false),
+        }
+    }
+}
+
+impl Watermarks {
+    pub(crate) const fn new() -> Self {
+        Self {
+            len: AtomicUsize::new(0),
+            slots: [const { Slot::new() }; MAX_WATERMARKS],
+        }
+    }
+
+    /// Same as `set` before any allocation, `watermarks` has `len` elements.
+    pub(crate) const fn with_thresholds(
+        watermarks: [(usize, Option<WatermarkCallback>); MAX_WATERMARKS],
+        len: usize,
+    ) -> Self {
+        let mut w = Self::new();
+        let mut i = 0;
+        while i < len {
+            if let (threshold, Some(callback)) = watermarks[i] {
+                assert!(threshold != 0, "a watermark threshold cannot be 0");
+                w.slots[i] = Slot {
+                    threshold: AtomicUsize::new(threshold),
+                    callback: AtomicPtr::new(callback as *mut ()),
+                    armed: AtomicBool::new(true),
+                };
+            }
+            i += 1;
+        }
+        w.len = AtomicUsize::new(len);
+        w
+    }
+
+    pub(crate) fn set(&self, watermarks: &[(usize, WatermarkCallback)]) {
+        assert!(
+            watermarks.len() <= MAX_WATERMARKS,
+            "at most {} watermarks are supported",
+            MAX_WATERMARKS
+        );
+        assert!(
+            watermarks.iter().all(|(threshold, _)| *threshold != 0),
+            "a watermark threshold cannot be 0"
+        );
+        // Disable all the slots while they are being modified
+        self.len.store(0, SeqCst);
+        for (slot, (threshold, callback)) in self.slots.iter().zip(watermarks) {
+            slot.threshold.store(*threshold, SeqCst);
+            slot.callback.store(*callback as *mut (), SeqCst);
+            slot.armed.store(true, SeqCst);
+        }
+        self.len.store(watermarks.len(), SeqCst);
+    }
+
+    /// Called after the allocated memory increases to `allocated`.
+    pub(crate) fn increased(&self, allocated: usize, limit: usize) {
+        for slot in &self.slots[..self.len.load(SeqCst)] {
+            // Only the thread that disarms the slot calls the callback, so it is called once per
+            // crossing
+            let threshold = slot.threshold.load(SeqCst);
+            if allocated >= threshold && slot.armed.swap(false, SeqCst) {
+                #[cfg(feature = "tracing")]
+                crate::report::watermark(allocated, threshold, limit);
+                let callback = slot.callback.load(SeqCst);
+                // Safety: the only values stored in callback are WatermarkCallback
+                let callback: WatermarkCallback = unsafe { mem::transmute(callback) };
+                callback(allocated, limit);
+            }
+        }
+    }
+
+    /// Called after the allocated memory decreases to `allocated`.
+    pub(crate) fn decreased(&self, allocated: usize) {
+        for slot in &self.slots[..self.len.load(SeqCst)] {
+            let threshold = slot.threshold.load(SeqCst);
+            // Hysteresis: only re-arm once the usage is clearly below the threshold, so that a
+            // program allocating and freeing right at the threshold does not call the callback
+            // every time
+            if allocated < threshold - threshold / 16 && !slot.armed.load(SeqCst) {
+                slot.armed.store(true, SeqCst);
+            }
+        }
+    }
+}
+
+/// Limit that does not make allocations fail, see `Limit::set_soft_limit`.
+pub(crate) struct SoftLimit {
+    threshold: AtomicUsize,
+    /// Stored as a `WatermarkCallback`, or null if there is no hook.
+    hook: AtomicPtr<()>,
+    /// True if the allocated memory is above the threshold.
+    over: AtomicBool,
+}
+
+impl SoftLimit {
+    pub(crate) const fn new() -> Self {
+        Self {
+            threshold: AtomicUsize::new(usize::MAX),
+            hook: AtomicPtr::new(ptr::null_mut()),
+            over: AtomicBool::new(false),
+        }
+    }
+
+    /// Same as `set` before any allocation.
+    pub(crate) const fn with_threshold(threshold: usize, hook: Option<WatermarkCallback>) -> Self {
+        let hook = match hook {
+            Some(f) => f as *mut (),
+            None => ptr::null_mut(),
+        };
+        Self {
+            threshold: AtomicUsize::new(threshold),
+            hook: AtomicPtr::new(hook),
+            over: AtomicBool::new(false),
+        }
+    }
+
+    pub(crate) fn set(&self, threshold: usize, hook: Option<WatermarkCallback>, allocated: usize) {
+        self.hook
+            .store(hook.map_or(ptr::null_mut(), |f| f as *mut ()), SeqCst);
+        self.threshold.store(threshold, SeqCst);
+        // Do not call the hook if the memory is already above the new threshold, only update the
+        // flag
+        self.over.store(allocated > threshold, SeqCst);
+    }
+
+    pub(crate) fn threshold(&self) -> usize {
+        self.threshold.load(SeqCst)
+    }
+
+    pub(crate) fn is_over(&self) -> bool {
+        self.over.load(SeqCst)
+    }
+
+    /// Called after the allocated memory increases to `allocated`.
+    pub(crate) fn increased(&self, allocated: usize) {
+        let threshold = self.threshold.load(SeqCst);
+        // Only the thread that sets the flag calls the hook, so it is called once per crossing
+        if allocated > threshold && !self.over.swap(true, SeqCst) {
+            let hook = self.hook.load(SeqCst);
+            if !hook.is_null() {
+                // Safety: the only non-null values stored in hook are WatermarkCallback
+                let hook: WatermarkCallback = unsafe { mem::transmute(hook) };
+                hook(allocated, threshold);
+            }
+        }
+    }
+
+    /// Called after the allocated memory decreases to `allocated`.
+    pub(crate) fn decreased(&self, allocated: usize) {
+        if allocated <= self.threshold.load(SeqCst) && self.over.load(SeqCst) {
+            self.over.store(false, SeqCst);
+        }
+    }
+}
+
+#[cfg(all(test, feature = "std"))]
+mod tests {
+    use super::*;
+
+    static CALLS: AtomicUsize = AtomicUsize::new(0);
+
+    fn count(_allocated: usize, _limit: usize) {
+        CALLS.fetch_add(1, SeqCst);
+    }
+
+    #[test]
+    fn callback_count_matches_the_upward_crossings() {
+        let w = Watermarks::new();
+        w.set(&[(1_600, count)]);
+        for crossing in 1..=5 {
+            w.increased(1_700, usize::MAX);
+            assert_eq!(CALLS.load(SeqCst), crossing);
+            // Going back and forth close to the threshold does not call it again
+            w.decreased(1_550);
+            w.increased(1_600, usize::MAX);
+            w.decreased(1_500);
+            w.increased(1_650, usize::MAX);
+            assert_eq!(CALLS.load(SeqCst), crossing);
+            // 1_600 - 1_600 / 16 = 1_500, so going below that re-arms it
+            w.decreased(1_499);
+        }
+        assert_eq!(CALLS.load(SeqCst), 5);
+    }
+
+    #[test]
+    #[should_panic(expected = "a watermark threshold cannot be 0")]
+    fn zero_threshold_is_rejected() {
+        Watermarks::new().set(&[(0, |_, _| {})]);
+    }
+}
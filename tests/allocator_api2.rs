@@ -0,0 +1,67 @@
+//! `Limit` used through the `Allocator` trait of the `allocator-api2` crate, by the collections of
+//! that crate.
+use allocator_api2::vec::Vec;
+use limit_alloc::Limit;
+use std::alloc::System;
+
+#[test]
+fn vec_fails_above_the_limit() {
+    let limit = Limit::new(1 << 10, System);
+    let mut v = Vec::<u8, _>::new_in(&limit);
+    assert!(v.try_reserve_exact(1_000).is_ok());
+    assert_eq!(limit.allocated(), 1_000);
+    // The limit is exhausted, so growing returns an error and the vector is not modified
+    assert!(v.try_reserve_exact(1_100).is_err());
+    assert_eq!(v.capacity(), 1_000);
+    assert_eq!(limit.allocated(), 1_000);
+    assert_eq!(limit.rejected_allocations(), 1);
+    drop(v);
+    assert_eq!(limit.allocated(), 0);
+}
+
+#[test]
+fn grow_and_shrink_only_charge_the_difference() {
+    let limit = Limit::new(1 << 10, System);
+    let mut v = Vec::<u8, _>::with_capacity_in(400, &limit);
+    v.extend_from_slice(&[1; 400]);
+    assert_eq!(limit.allocated(), 400);
+    // Growing to 800 bytes would need 1_200 bytes if both blocks were counted at the same time
+    v.reserve_exact(400);
+    assert_eq!(v.capacity(), 800);
+    assert_eq!(limit.allocated(), 800);
+    assert!(v.iter().all(|&x| x == 1));
+    v.truncate(100);
+    v.shrink_to_fit();
+    assert_eq!(v.capacity(), 100);
+    assert_eq!(limit.allocated(), 100);
+    drop(v);
+    assert_eq!(limit.allocated(), 0);
+}
+
+#[test]
+fn zero_sized_allocations_are_not_counted() {
+    let limit = Limit::new(0, System);
+    let mut v = Vec::<(), _>::new_in(&limit);
+    v.push(());
+    let empty = Vec::<u64, _>::with_capacity_in(0, &limit);
+    assert_eq!(v.len(), 1);
+    assert_eq!(empty.capacity(), 0);
+    assert_eq!(limit.allocated(), 0);
+    assert_eq!(limit.rejected_allocations(), 0);
+}
+
+#[test]
+fn allocate_returns_alloc_error() {
+    use allocator_api2::alloc::Allocator;
+    use std::alloc::Layout;
+
+    let limit = Limit::new(1 << 10, System);
+    let layout = Layout::from_size_align(2_000, 8).unwrap();
+    assert!(limit.allocate(layout).is_err());
+    let layout = Layout::from_size_align(1_000, 8).unwrap();
+    let ptr = limit.allocate(layout).unwrap();
+    assert_eq!(ptr.len(), 1_000);
+    assert_eq!(limit.allocated(), 1_000);
+    unsafe { limit.deallocate(ptr.cast(), layout) };
+    assert_eq!(limit.allocated(), 0);
+}
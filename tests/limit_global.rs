@@ -0,0 +1,50 @@
+//! `limit_global!` with an inner allocator that is not a unit struct. Each integration test is a
+//! separate program, so this one can declare its own global allocator.
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::sync::atomic::AtomicUsize;
+use std::sync::atomic::Ordering::SeqCst;
+
+/// Counts the calls to `alloc`, to check that the global allocator uses it.
+struct Counting {
+    allocs: AtomicUsize,
+}
+
+impl Counting {
+    const fn new() -> Self {
+        Self {
+            allocs: AtomicUsize::new(0),
+        }
+    }
+}
+
+unsafe impl GlobalAlloc for Counting {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        self.allocs.fetch_add(1, SeqCst);
+        System.alloc(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        System.dealloc(ptr, layout)
+    }
+}
+
+limit_alloc::limit_global!(64 * 1024 * 1024, Counting = Counting::new());
+
+#[test]
+fn accessor_returns_the_global_allocator() {
+    assert_eq!(global_limit().limit(), 64 * 1024 * 1024);
+    let allocs = global_limit().inner().allocs.load(SeqCst);
+    let v: Vec<u8> = Vec::with_capacity(1_000);
+    assert!(global_limit().inner().allocs.load(SeqCst) > allocs);
+    assert!(global_limit().allocated() >= 1_000);
+    drop(v);
+}
+
+#[test]
+fn global_allocations_fail_above_the_limit() {
+    let rejected = global_limit().rejected_allocations();
+    let mut v: Vec<u8> = Vec::new();
+    assert!(v.try_reserve(128 * 1024 * 1024).is_err());
+    assert!(global_limit().rejected_allocations() > rejected);
+    assert!(global_limit().remaining() <= global_limit().limit());
+}